@@ -3,10 +3,10 @@ use rand;
 use rand::seq::SliceRandom;
 use tempdir::TempDir;
 
-use fakir::storage::{Config, FsBackend, Handle};
+use fakir::storage::{Config, Handle};
 
 pub fn bench(c: &mut Criterion) {
-    let mut pairs: Vec<(Vec<u8>, Vec<u8>)> = (1..500).into_iter().map(|x| (format!("k_{}", x).as_bytes().to_vec(), format!("val_{}", x).as_bytes().to_vec())).collect();
+    let mut pairs: Vec<(Vec<u8>, Vec<u8>)> = (1..500).map(|x| (format!("k_{}", x).as_bytes().to_vec(), format!("val_{}", x).as_bytes().to_vec())).collect();
     let dir = TempDir::new("bitcask-").unwrap().into_path();
     println!("storage dir: {:?}", &dir);
     let config = Config { path: dir, ..Default::default() };
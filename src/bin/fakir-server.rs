@@ -0,0 +1,67 @@
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use clap::{Parser, ValueEnum};
+
+use fakir::server::{http, memcached, resp};
+use fakir::storage::{Config, Handle};
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Protocol {
+    /// Redis serialization protocol.
+    Resp,
+    /// Memcached text protocol.
+    Memcached,
+    /// HTTP API with admin endpoints.
+    Http,
+}
+
+/// Serves a fakir store to Redis, memcached or HTTP clients.
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// Data directory of the store.
+    #[arg(long)]
+    dir: PathBuf,
+    #[arg(long, value_enum, default_value = "resp")]
+    protocol: Protocol,
+    /// Listen address. Defaults to 127.0.0.1:6379 for RESP, 127.0.0.1:11211 for memcached and 127.0.0.1:8080 for HTTP.
+    #[arg(long)]
+    bind: Option<String>,
+    /// Syncs every write to disk before replying.
+    #[arg(long)]
+    sync_on_put: bool,
+    /// Time to live of all keys in seconds, `0` means keys only expire with their own expiry time.
+    #[arg(long, default_value_t = 0)]
+    expiry_secs: u32,
+    /// Directory where `POST /admin/backup` writes backups, the HTTP endpoint is disabled without it.
+    #[arg(long)]
+    backup_root: Option<PathBuf>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let conf = Config {
+        path: args.dir,
+        sync_on_put: args.sync_on_put,
+        expiry_secs: args.expiry_secs,
+        ..Default::default()
+    };
+
+    let handle = Mutex::new(Handle::open(&conf)?);
+    let bind = args.bind.unwrap_or_else(|| match args.protocol {
+        Protocol::Resp => "127.0.0.1:6379".to_string(),
+        Protocol::Memcached => "127.0.0.1:11211".to_string(),
+        Protocol::Http => "127.0.0.1:8080".to_string(),
+    });
+    let listener = TcpListener::bind(&bind)?;
+    eprintln!("fakir-server is listening on {}", listener.local_addr()?);
+
+    match args.protocol {
+        Protocol::Resp => resp::serve(&listener, &handle)?,
+        Protocol::Memcached => memcached::serve(&listener, &handle)?,
+        Protocol::Http => http::serve(listener, &handle, args.backup_root.as_deref())?,
+    }
+    Ok(())
+}
@@ -0,0 +1,440 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use clap::{Parser, Subcommand, ValueEnum};
+use serde_json::{json, Value};
+
+use fakir::storage::{Config, Dump, DumpEntry, Encryption, ExportOptions, Handle, Op, Problem, verify, Warning};
+
+mod shell;
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// Bytes as stored. A single value is written exactly, listed keys and values end with a newline.
+    Raw,
+    /// Lower case hex.
+    Hex,
+    /// A JSON object per line. Values which are not UTF-8 are hex encoded.
+    Json,
+}
+
+/// Reads, writes and inspects a fakir data directory.
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// Output format of keys and values.
+    #[arg(long, value_enum, global = true, default_value = "raw")]
+    format: Format,
+    /// Encryption keys of the store, one `<id>:<hex key>` per line. The first key encrypts new values,
+    /// the others only decrypt the values written before a key rotation.
+    #[arg(long, global = true)]
+    key_file: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Prints the value of a key. Exits with 1 if the key does not exist.
+    Get { dir: PathBuf, key: String },
+    /// Writes a value given as argument, read from a file or from stdin.
+    Put {
+        dir: PathBuf,
+        key: String,
+        value: Option<String>,
+        /// Reads the value from the file.
+        #[arg(long, conflicts_with = "value")]
+        file: Option<PathBuf>,
+        /// Time to live of the key in seconds.
+        #[arg(long)]
+        ttl: Option<u64>,
+    },
+    /// Deletes a key. Exits with 1 if the key does not exist.
+    Delete { dir: PathBuf, key: String },
+    /// Prints the keys in order.
+    Scan {
+        dir: PathBuf,
+        #[arg(long, default_value = "")]
+        prefix: String,
+        #[arg(long, default_value_t = usize::MAX)]
+        limit: usize,
+        /// Prints values next to the keys.
+        #[arg(long)]
+        values: bool,
+    },
+    /// Prints key and data file statistics as JSON.
+    Stats { dir: PathBuf },
+    /// Compacts all data files except the active one.
+    Merge { dir: PathBuf },
+    /// Copies the data files into an empty directory.
+    Backup { dir: PathBuf, dest: PathBuf },
+    /// Writes the live keys as JSON Lines with base64 keys and values.
+    Export {
+        dir: PathBuf,
+        /// Writes into the file instead of stdout.
+        #[arg(long)]
+        file: Option<PathBuf>,
+        /// Writes the expiry time of the keys.
+        #[arg(long)]
+        ttl: bool,
+        /// Writes the time of the last write of the keys.
+        #[arg(long)]
+        timestamps: bool,
+    },
+    /// Writes the keys of a JSON Lines export, read from the file or stdin.
+    Import { dir: PathBuf, file: Option<PathBuf> },
+    /// Writes the live keys of Riak Bitcask directories, e.g. the partition directories of a stopped node.
+    ImportBitcask {
+        dir: PathBuf,
+        #[arg(required = true)]
+        sources: Vec<PathBuf>,
+    },
+    /// Reads every record of the data files without locking the store. Exits with 1 if a file is corrupt.
+    Verify { dir: PathBuf },
+    /// Rewrites corrupt data files with their readable records and prints the keys which may be lost.
+    Repair { dir: PathBuf },
+    /// Prints every record of a data file, including the ones with a bad crc. Exits with 1 if the file is corrupt.
+    Dump {
+        file: PathBuf,
+        /// Prints only the records of the key.
+        #[arg(long)]
+        key: Option<String>,
+        /// Prints only the records which start at or after the offset.
+        #[arg(long, default_value_t = 0)]
+        from: u64,
+        /// Prints only the records which start before the offset.
+        #[arg(long, default_value_t = u64::MAX)]
+        to: u64,
+        /// Values are truncated to this many bytes, `0` prints whole values.
+        #[arg(long, default_value_t = 64)]
+        max_value: usize,
+    },
+    /// Starts an interactive shell.
+    Shell {
+        dir: PathBuf,
+        /// Opens the store without taking its lock, so it can be used next to a running server.
+        #[arg(long)]
+        read_only: bool,
+    },
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    match run(args) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("fakir: {e:#}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn run(args: Args) -> anyhow::Result<ExitCode> {
+    let format = args.format;
+    let encryption = args.key_file.as_deref().map(read_encryption).transpose()?;
+    let config = |dir: PathBuf| Config { path: dir, encryption: encryption.clone(), ..Default::default() };
+    let mut out = io::stdout().lock();
+    match args.command {
+        Command::Get { dir, key } => {
+            let conf = config(dir);
+            let Some(val) = Handle::open(&conf)?.get(key.as_bytes())? else {
+                return Ok(ExitCode::FAILURE);
+            };
+            match format {
+                Format::Json => print_json(&mut out, &json!({ "key": key, "value": bytes_value(&val) }))?,
+                Format::Raw => out.write_all(&val)?,
+                Format::Hex => print_bytes(&mut out, format, &val)?,
+            }
+        }
+        Command::Put { dir, key, value, file, ttl } => {
+            let val = match (value, file) {
+                (Some(value), _) => value.into_bytes(),
+                (None, Some(file)) => std::fs::read(&file).with_context(|| format!("{} cannot be read", file.display()))?,
+                (None, None) => {
+                    let mut val = Vec::new();
+                    io::stdin().read_to_end(&mut val)?;
+                    val
+                }
+            };
+            let conf = config(dir);
+            let mut handle = Handle::open(&conf)?;
+            match ttl {
+                Some(secs) => handle.put_with_ttl(key.as_bytes(), &val, Duration::from_secs(secs))?,
+                None => handle.put(key.as_bytes(), &val)?,
+            }
+            handle.close()?;
+        }
+        Command::Delete { dir, key } => {
+            let conf = config(dir);
+            let mut handle = Handle::open(&conf)?;
+            if !handle.contains_key(key.as_bytes())? {
+                return Ok(ExitCode::FAILURE);
+            }
+            handle.delete(key.as_bytes())?;
+            handle.close()?;
+        }
+        Command::Scan { dir, prefix, limit, values } => {
+            let conf = config(dir);
+            let mut handle = Handle::open(&conf)?;
+            let mut after: Option<Vec<u8>> = None;
+            let mut remaining = limit;
+            while remaining > 0 {
+                let keys = handle.scan(prefix.as_bytes(), after.as_deref(), remaining.min(1024))?;
+                for key in &keys {
+                    let val = if values { handle.get(key)? } else { None };
+                    if values && val.is_none() {
+                        continue;
+                    }
+                    print_entry(&mut out, format, key, val.as_deref())?;
+                }
+                remaining -= keys.len();
+                match keys.last() {
+                    Some(last) if keys.len() == 1024 => after = Some(last.clone()),
+                    _ => break,
+                }
+            }
+        }
+        Command::Stats { dir } => {
+            let conf = config(dir);
+            print_json(&mut out, &serde_json::to_value(Handle::open(&conf)?.stats()?)?)?;
+        }
+        Command::Merge { dir } => {
+            let conf = config(dir);
+            print_json(&mut out, &serde_json::to_value(Handle::open(&conf)?.merge()?)?)?;
+        }
+        Command::Backup { dir, dest } => {
+            let conf = config(dir);
+            print_json(&mut out, &serde_json::to_value(Handle::open(&conf)?.backup(&dest)?)?)?;
+        }
+        Command::Export { dir, file, ttl, timestamps } => {
+            let conf = config(dir);
+            let mut handle = Handle::open(&conf)?;
+            let options = ExportOptions { ttl, timestamps };
+            let count = match file {
+                Some(file) => handle.export(BufWriter::new(File::create(&file).with_context(|| format!("{} cannot be created", file.display()))?), &options)?,
+                None => handle.export(&mut out, &options)?,
+            };
+            eprintln!("{count} keys exported");
+        }
+        Command::Import { dir, file } => {
+            let conf = config(dir);
+            let mut handle = Handle::open(&conf)?;
+            let count = match file {
+                Some(file) => handle.import(BufReader::new(File::open(&file).with_context(|| format!("{} cannot be opened", file.display()))?))?,
+                None => handle.import(io::stdin().lock())?,
+            };
+            handle.close()?;
+            eprintln!("{count} keys imported");
+        }
+        Command::ImportBitcask { dir, sources } => {
+            let conf = config(dir);
+            let mut handle = Handle::open(&conf)?;
+            for source in sources {
+                let report = handle.import_bitcask(&source)?;
+                eprintln!("{}: {} files, {} keys imported, {} deleted keys skipped", source.display(), report.files, report.keys, report.deleted);
+                if report.corrupt_bytes > 0 {
+                    eprintln!("  {} unreadable bytes at the end of data files", report.corrupt_bytes);
+                }
+            }
+            handle.close()?;
+        }
+        Command::Verify { dir } => {
+            let report = verify(&dir)?;
+            for file in &report.files {
+                eprintln!("{}: {} records, {} bytes", file.file_id, file.records, file.bytes);
+                for problem in &file.problems {
+                    match problem {
+                        Problem::Corrupt { start, end } => eprintln!("  corrupt bytes {start}..{end}"),
+                        Problem::InvalidRecord { offset, reason } => eprintln!("  invalid record at {offset}: {reason}"),
+                        Problem::HintMismatch { offset: Some(offset), reason } => eprintln!("  hint of record at {offset}: {reason}"),
+                        Problem::HintMismatch { offset: None, reason } => eprintln!("  {reason}"),
+                    }
+                }
+                for warning in &file.warnings {
+                    match warning {
+                        Warning::OutOfOrder { offset, ts_tamp, next_file_id } => eprintln!("  warning: record at {offset} is written at {ts_tamp}, after the next file {next_file_id}"),
+                    }
+                }
+            }
+            if format == Format::Json {
+                print_json(&mut out, &serde_json::to_value(&report)?)?;
+            }
+            if !report.is_ok() {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Command::Repair { dir } => {
+            let report = Handle::repair(&dir)?;
+            for file in &report.files {
+                eprintln!("{}: {} records kept, {} bytes dropped", file.file_id, file.records, file.dropped_bytes);
+            }
+            if let Some(quarantine) = &report.quarantine {
+                eprintln!("originals are moved to {}", quarantine.display());
+            }
+            if report.unknown_bytes > 0 {
+                eprintln!("{} dropped bytes have no readable key", report.unknown_bytes);
+            }
+            for lost in &report.lost_keys {
+                match format {
+                    Format::Json => print_json(&mut out, &json!({
+                        "file_id": lost.file_id,
+                        "offset": lost.offset,
+                        "bucket": lost.bucket.as_deref().map(bytes_value),
+                        "key": bytes_value(&lost.key),
+                    }))?,
+                    _ => print_bytes(&mut out, format, &lost.key)?,
+                }
+            }
+        }
+        Command::Dump { file, key, from, to, max_value } => return dump(&mut out, format, &file, &DumpFilter { key, offsets: from..to, max_value }),
+        Command::Shell { dir, read_only } => {
+            drop(out);
+            shell::run(config(dir), read_only)?;
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+struct DumpFilter {
+    key: Option<String>,
+    offsets: Range<u64>,
+    max_value: usize,
+}
+
+fn dump(out: &mut impl Write, format: Format, file: &Path, filter: &DumpFilter) -> anyhow::Result<ExitCode> {
+    let mut code = ExitCode::SUCCESS;
+    for entry in Dump::open(file)? {
+        let (record, crc_ok) = match entry {
+            DumpEntry::Record { record, crc_ok } => (record, crc_ok),
+            DumpEntry::Corrupt { start, end } => {
+                code = ExitCode::FAILURE;
+                if filter.key.is_none() && filter.offsets.contains(&start) {
+                    match format {
+                        Format::Json => print_json(out, &json!({ "offset": start, "size": end - start, "corrupt": true }))?,
+                        _ => writeln!(out, "{start}\t{}\tcorrupt", end - start)?,
+                    }
+                }
+                continue;
+            }
+        };
+        if !crc_ok {
+            code = ExitCode::FAILURE;
+        }
+        if !filter.offsets.contains(&record.offset) || filter.key.as_ref().is_some_and(|key| key.as_bytes() != record.key) {
+            continue;
+        }
+
+        let op = match record.op {
+            Op::Put => "put",
+            Op::Delete => "delete",
+            Op::DropBucket => "drop_bucket",
+        };
+        let truncated = filter.max_value > 0 && record.value.len() > filter.max_value;
+        let value = if truncated { &record.value[..filter.max_value] } else { &record.value[..] };
+        match format {
+            Format::Json => print_json(out, &json!({
+                "offset": record.offset,
+                "size": record.size,
+                "crc_ok": crc_ok,
+                "ts": record.ts_tamp,
+                "op": op,
+                "bucket": record.bucket.as_deref().map(bytes_value),
+                "key": bytes_value(&record.key),
+                "value": bytes_value(value),
+                "value_size": record.value.len(),
+                "expires_at": record.expires_at,
+                "compressed": record.compressed,
+                "encrypted": record.encrypted,
+            }))?,
+            _ => {
+                let text = |bytes: &[u8]| if format == Format::Hex { hex(bytes) } else { bytes.escape_ascii().to_string() };
+                let mut flags = Vec::new();
+                if record.compressed { flags.push("compressed") }
+                if record.encrypted { flags.push("encrypted") }
+                write!(out, "{}\t{}\t{}\t{}\t{}\t", record.offset, record.size, if crc_ok { "ok" } else { "bad_crc" }, record.ts_tamp, op)?;
+                if let Some(bucket) = &record.bucket {
+                    write!(out, "{}/", text(bucket))?;
+                }
+                write!(out, "{}\t{}{}", text(&record.key), text(value), if truncated { "..." } else { "" })?;
+                if let Some(expires_at) = record.expires_at {
+                    write!(out, "\texpires_at={expires_at}")?;
+                }
+                if !flags.is_empty() {
+                    write!(out, "\t{}", flags.join(","))?;
+                }
+                writeln!(out)?;
+            }
+        }
+    }
+    Ok(code)
+}
+
+/// Reads the keys of `--key-file`, the first key is the current one.
+fn read_encryption(path: &Path) -> anyhow::Result<Encryption> {
+    let content = std::fs::read_to_string(path).with_context(|| format!("{} cannot be read", path.display()))?;
+    let mut keys = content.lines().map(str::trim).filter(|line| !line.is_empty()).map(parse_key);
+    let (key_id, key) = keys.next().context("key file is empty")??;
+    keys.try_fold(Encryption::new(key_id, key), |encryption, parsed| {
+        parsed.map(|(key_id, key)| encryption.with_retired_key(key_id, key))
+    })
+}
+
+fn parse_key(line: &str) -> anyhow::Result<(u8, [u8; 32])> {
+    let (key_id, key) = line.split_once(':').context("key must be written as <id>:<hex key>")?;
+    let key: Vec<u8> = (0..key.len()).step_by(2)
+        .map(|i| key.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect::<Option<_>>()
+        .context("key is not hex")?;
+    Ok((key_id.parse().context("key id must be between 0 and 255")?, key.try_into().map_err(|_| anyhow!("key must be 32 bytes"))?))
+}
+
+/// Keys and values are JSON strings if they are UTF-8, otherwise objects with the hex encoded bytes.
+fn bytes_value(bytes: &[u8]) -> Value {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => Value::String(s.to_string()),
+        Err(_) => json!({ "hex": hex(bytes) }),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn print_bytes(out: &mut impl Write, format: Format, bytes: &[u8]) -> io::Result<()> {
+    match format {
+        Format::Hex => writeln!(out, "{}", hex(bytes)),
+        _ => {
+            out.write_all(bytes)?;
+            writeln!(out)
+        }
+    }
+}
+
+fn print_entry(out: &mut impl Write, format: Format, key: &[u8], val: Option<&[u8]>) -> anyhow::Result<()> {
+    match (format, val) {
+        (Format::Json, None) => print_json(out, &json!({ "key": bytes_value(key) })),
+        (Format::Json, Some(val)) => print_json(out, &json!({ "key": bytes_value(key), "value": bytes_value(val) })),
+        (_, None) => Ok(print_bytes(out, format, key)?),
+        (_, Some(val)) => {
+            match format {
+                Format::Hex => write!(out, "{}\t", hex(key))?,
+                _ => {
+                    out.write_all(key)?;
+                    write!(out, "\t")?;
+                }
+            }
+            Ok(print_bytes(out, format, val)?)
+        }
+    }
+}
+
+fn print_json(out: &mut impl Write, value: &Value) -> anyhow::Result<()> {
+    serde_json::to_writer(&mut *out, value)?;
+    writeln!(out)?;
+    Ok(())
+}
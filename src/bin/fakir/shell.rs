@@ -0,0 +1,210 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Editor, Helper};
+
+use fakir::Error;
+use fakir::storage::{Config, Handle, ReadOnlyHandle};
+
+/// Commands and their arguments, used by `help` and tab completion.
+const COMMANDS: &[(&str, &str)] = &[
+    ("get", "<key>"),
+    ("put", "<key> <value> [ttl secs]"),
+    ("del", "<key>"),
+    ("scan", "[prefix] [limit]"),
+    ("ttl", "<key>"),
+    ("stats", ""),
+    ("refresh", "loads keys written since the read-only store is opened"),
+    ("help", ""),
+    ("exit", ""),
+];
+
+enum Store<'a> {
+    Writable(Handle<'a>),
+    ReadOnly(ReadOnlyHandle<'a>),
+}
+
+struct CommandCompleter;
+
+impl Completer for CommandCompleter {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _: &rustyline::Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let word = &line[..pos];
+        if word.contains(char::is_whitespace) {
+            return Ok((pos, Vec::new()));
+        }
+        let candidates = COMMANDS.iter()
+            .filter(|(name, _)| name.starts_with(word))
+            .map(|(name, _)| Pair { display: name.to_string(), replacement: format!("{name} ") })
+            .collect();
+        Ok((0, candidates))
+    }
+}
+
+impl Hinter for CommandCompleter {
+    type Hint = String;
+}
+
+impl Highlighter for CommandCompleter {}
+
+impl Validator for CommandCompleter {}
+
+impl Helper for CommandCompleter {}
+
+/// Runs the REPL until `exit` or end of input. A read-only store does not take the lock of the directory.
+pub fn run(conf: Config, read_only: bool) -> anyhow::Result<()> {
+    let mut store = if read_only {
+        Store::ReadOnly(ReadOnlyHandle::open(&conf)?)
+    } else {
+        Store::Writable(Handle::open(&conf).context("store cannot be opened, use --read-only next to a running store")?)
+    };
+
+    let mut editor: Editor<CommandCompleter, DefaultHistory> = Editor::new()?;
+    editor.set_helper(Some(CommandCompleter));
+    let history = std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".fakir_history"));
+    if let Some(history) = &history {
+        let _ = editor.load_history(history);
+    }
+
+    let prompt = if read_only { "fakir (read-only)> " } else { "fakir> " };
+    loop {
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let args = match split_args(&line) {
+            Ok(args) if args.is_empty() => continue,
+            Ok(args) => args,
+            Err(e) => {
+                println!("(error) {e}");
+                continue;
+            }
+        };
+        editor.add_history_entry(line.as_str())?;
+        if matches!(args[0].as_str(), "exit" | "quit") {
+            break;
+        }
+        match execute(&mut store, &args) {
+            Ok(output) => println!("{output}"),
+            Err(e) => println!("(error) {e:#}"),
+        }
+    }
+
+    if let Some(history) = &history {
+        let _ = editor.save_history(history);
+    }
+    if let Store::Writable(handle) = &mut store {
+        handle.close()?;
+    }
+    Ok(())
+}
+
+fn execute(store: &mut Store<'_>, args: &[String]) -> anyhow::Result<String> {
+    let key = || args.get(1).map(String::as_bytes).context("key is missing");
+    match (args[0].as_str(), store) {
+        ("get", Store::Writable(handle)) => Ok(format_value(handle.get(key()?)?)),
+        ("get", Store::ReadOnly(handle)) => Ok(format_value(handle.get(key()?)?)),
+        ("put" | "del", Store::ReadOnly(_)) => bail!("store is opened read-only"),
+        ("put", Store::Writable(handle)) => {
+            let val = args.get(2).context("value is missing")?;
+            match args.get(3) {
+                Some(ttl) => handle.put_with_ttl(key()?, val.as_bytes(), Duration::from_secs(ttl.parse().context("invalid ttl")?))?,
+                None => handle.put(key()?, val.as_bytes())?,
+            }
+            Ok("OK".to_string())
+        }
+        ("del", Store::Writable(handle)) => {
+            let existed = handle.contains_key(key()?)?;
+            handle.delete(key()?)?;
+            Ok(format!("(integer) {}", existed as u8))
+        }
+        ("scan", store) => {
+            let prefix = args.get(1).map(String::as_bytes).unwrap_or_default();
+            let limit = args.get(2).map(|limit| limit.parse()).transpose().context("invalid limit")?.unwrap_or(100);
+            let keys = match store {
+                Store::Writable(handle) => handle.scan(prefix, None, limit)?,
+                Store::ReadOnly(handle) => handle.scan(prefix, None, limit),
+            };
+            if keys.is_empty() {
+                return Ok("(empty)".to_string());
+            }
+            Ok(keys.iter().enumerate().map(|(i, key)| format!("{}) \"{}\"", i + 1, key.escape_ascii())).collect::<Vec<_>>().join("\n"))
+        }
+        ("ttl", store) => {
+            let (exists, ttl) = match store {
+                Store::Writable(handle) => match handle.ttl(key()?) {
+                    Err(Error::Expired) => (false, None),
+                    ttl => (handle.contains_key(key()?)?, ttl?),
+                },
+                Store::ReadOnly(handle) => (handle.contains_key(key()?), handle.ttl(key()?)),
+            };
+            Ok(match (exists, ttl) {
+                (false, _) => "(nil)".to_string(),
+                (true, None) => "(no expiry)".to_string(),
+                (true, Some(ttl)) => format!("{:.3}s", ttl.as_secs_f64()),
+            })
+        }
+        ("stats", store) => {
+            let stats = match store {
+                Store::Writable(handle) => handle.stats()?,
+                Store::ReadOnly(handle) => handle.stats(),
+            };
+            Ok(serde_json::to_string_pretty(&stats)?)
+        }
+        ("refresh", Store::ReadOnly(handle)) => {
+            handle.refresh()?;
+            Ok("OK".to_string())
+        }
+        ("refresh", Store::Writable(_)) => Ok("OK".to_string()),
+        ("help", _) => Ok(COMMANDS.iter().map(|(name, args)| format!("{name} {args}")).collect::<Vec<_>>().join("\n")),
+        (command, _) => bail!("unknown command '{command}', try help"),
+    }
+}
+
+fn format_value(val: Option<Vec<u8>>) -> String {
+    match val {
+        None => "(nil)".to_string(),
+        Some(val) => format!("\"{}\"", val.escape_ascii()),
+    }
+}
+
+/// Splits the line by whitespace. Double quoted arguments can contain whitespace and `\"`.
+fn split_args(line: &str) -> anyhow::Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        let mut arg = String::new();
+        if c == '"' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    None => bail!("unbalanced quotes"),
+                    Some('"') => break,
+                    Some('\\') => arg.extend(chars.next()),
+                    Some(c) => arg.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                arg.push(c);
+            }
+        }
+        args.push(arg);
+    }
+    Ok(args)
+}
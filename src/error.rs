@@ -0,0 +1,80 @@
+use std::io;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Errors returned by the public API.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Data directory is locked by another process or handle.
+    #[error("store is locked by another process: {0}")]
+    Locked(String),
+
+    /// Record at the given position cannot be read, e.g. crc mismatch or truncated record.
+    #[error("corrupted record in file {file_id} at offset {offset}")]
+    Corrupt { file_id: u64, offset: u64 },
+
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("key size {size} exceeds the limit of {limit} bytes")]
+    KeyTooLarge { size: usize, limit: usize },
+
+    #[error("value size {size} exceeds the limit of {limit} bytes")]
+    ValueTooLarge { size: usize, limit: usize },
+
+    /// Handle is closed with [crate::storage::Handle::close].
+    #[error("handle is closed")]
+    Closed,
+
+    /// Bucket names must be between 1 and 255 bytes.
+    #[error("invalid bucket name: {0:?}")]
+    InvalidBucketName(String),
+
+    /// Writes of a Raft group are only accepted by the leader. `leader` is the last known leader.
+    #[error("node is not the leader, leader is {leader:?}")]
+    NotLeader { leader: Option<u64> },
+
+    /// Key exists but its time to live is passed. Returned by [crate::storage::Handle::ttl], other
+    /// reads return expired keys as missing.
+    #[error("key is expired")]
+    Expired,
+
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for Error {
+    /// Storage internals use anyhow. Typed errors raised inside are restored here.
+    fn from(e: anyhow::Error) -> Self {
+        let e = match e.downcast::<Error>() {
+            Ok(e) => return e,
+            Err(e) => e,
+        };
+
+        match e.downcast::<io::Error>() {
+            Ok(e) => Error::Io(e),
+            Err(e) => Error::Other(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use anyhow::Context;
+
+    use super::Error;
+
+    #[test]
+    fn it_should_restore_typed_errors_from_anyhow() {
+        let err: anyhow::Result<()> = Err(Error::Corrupt { file_id: 1, offset: 2 }).context("rebuild failed");
+        assert!(matches!(Error::from(err.unwrap_err()), Error::Corrupt { file_id: 1, offset: 2 }));
+
+        let err = anyhow::Error::from(io::Error::from(io::ErrorKind::NotFound));
+        assert!(matches!(Error::from(err), Error::Io(e) if e.kind() == io::ErrorKind::NotFound));
+
+        let err = anyhow::anyhow!("something else");
+        assert!(matches!(Error::from(err), Error::Other(_)));
+    }
+}
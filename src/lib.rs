@@ -1,2 +1,6 @@
 #![deny(elided_lifetimes_in_paths)]
+pub use error::{Error, Result};
+
 pub mod storage;
+pub mod server;
+mod error;
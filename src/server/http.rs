@@ -0,0 +1,414 @@
+//! HTTP API, so tools without a Redis or memcached client can use the store.
+//!
+//! - `GET /kv/{key}` returns the value, with its remaining time to live in `X-Fakir-TTL` if it expires.
+//! - `PUT /kv/{key}` stores the body. `X-Fakir-TTL` sets the time to live in seconds.
+//! - `DELETE /kv/{key}` deletes the key.
+//! - `GET /kv?prefix=&start=&end=&cursor=&limit=` lists keys in order. `start` is inclusive, `end` is exclusive
+//!   and `cursor` continues from a previous page.
+//! - `GET /admin/stats`, `POST /admin/merge` and `POST /admin/backup?dir=` expose [Handle::stats],
+//!   [Handle::merge] and [Handle::backup]. Backups are disabled unless a backup root is given to [serve],
+//!   then `dir` is the name of a new directory in it. Other requests wait until merges and backups end.
+//!
+//! Keys in the path and the query are percent-encoded. Listed keys are returned in the same encoding,
+//! where only bytes outside of printable ASCII and `%` are escaped.
+
+use std::io;
+use std::net::TcpListener;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use log::debug;
+use serde_json::{json, Value};
+use tiny_http::{Method, Request, Response, Server};
+
+use crate::Error;
+use crate::storage::Handle;
+
+const TTL_HEADER: &str = "X-Fakir-TTL";
+const WORKERS: usize = 4;
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 10_000;
+
+/// Response of a request before it is converted to a [tiny_http::Response].
+#[derive(Debug, PartialEq, Eq)]
+struct Reply {
+    status: u16,
+    body: Vec<u8>,
+    headers: Vec<(&'static str, String)>,
+}
+
+impl Reply {
+    fn empty(status: u16) -> Self {
+        Self { status, body: vec![], headers: vec![] }
+    }
+
+    fn json(status: u16, value: Value) -> Self {
+        Self { status, body: value.to_string().into_bytes(), headers: vec![("Content-Type", "application/json".to_string())] }
+    }
+
+    fn error(status: u16, msg: &str) -> Self {
+        Self::json(status, json!({ "error": msg }))
+    }
+
+    fn into_response(self) -> Response<io::Cursor<Vec<u8>>> {
+        self.headers.into_iter()
+            .filter_map(|(name, value)| tiny_http::Header::from_bytes(name, value).ok())
+            .fold(Response::from_data(self.body).with_status_code(self.status), Response::with_header)
+    }
+}
+
+impl From<Error> for Reply {
+    fn from(e: Error) -> Self {
+        match e {
+            // expired after it is read
+            Error::Expired => Reply::error(404, "key not found"),
+            e => Reply::error(500, &e.to_string()),
+        }
+    }
+}
+
+type ReplyResult = Result<Reply, Reply>;
+
+/// Serves HTTP requests on the listener until it fails. Backups are written into `backup_root`.
+pub fn serve(listener: TcpListener, handle: &Mutex<Handle<'_>>, backup_root: Option<&Path>) -> io::Result<()> {
+    let server = Server::from_listener(listener, None).map_err(io::Error::other)?;
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..WORKERS).map(|_| scope.spawn(|| -> io::Result<()> {
+            loop {
+                let mut request = server.recv()?;
+                let reply = respond(handle, backup_root, &mut request);
+                if let Err(e) = request.respond(reply.into_response()) {
+                    debug!("response is not sent: {e}");
+                }
+            }
+        })).collect();
+
+        workers.into_iter().try_for_each(|worker| worker.join().unwrap())
+    })
+}
+
+fn respond(handle: &Mutex<Handle<'_>>, backup_root: Option<&Path>, request: &mut Request) -> Reply {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let query = parse_query(query);
+
+    let result = match (request.method(), path) {
+        (Method::Get, "/kv") => list(&mut handle.lock().unwrap(), &query),
+        (method, path) if path.starts_with("/kv/") => {
+            let key = percent_decode(&path["/kv/".len()..], false);
+            match method {
+                Method::Get => get(&mut handle.lock().unwrap(), &key),
+                // body is read before the handle is locked
+                Method::Put => read_put(request).and_then(|(val, ttl)| put(&mut handle.lock().unwrap(), &key, &val, ttl)),
+                Method::Delete => delete(&mut handle.lock().unwrap(), &key),
+                _ => Err(Reply::error(405, "method not allowed")),
+            }
+        }
+        (Method::Get, "/admin/stats") => stats(&handle.lock().unwrap()),
+        (Method::Post, "/admin/merge") => merge(&mut handle.lock().unwrap()),
+        (Method::Post, "/admin/backup") => backup_dir(backup_root, &query).and_then(|dir| backup(&mut handle.lock().unwrap(), &dir)),
+        _ => Err(Reply::error(404, "not found")),
+    };
+
+    result.unwrap_or_else(|e| e)
+}
+
+fn get(handle: &mut Handle<'_>, key: &[u8]) -> ReplyResult {
+    let Some(val) = handle.get(key)? else {
+        return Err(Reply::error(404, "key not found"));
+    };
+
+    let mut headers = vec![("Content-Type", "application/octet-stream".to_string())];
+    if let Some(ttl) = handle.ttl(key)? {
+        headers.push((TTL_HEADER, ttl.as_secs_f64().ceil().to_string()));
+    }
+    Ok(Reply { status: 200, body: val, headers })
+}
+
+fn read_put(request: &mut Request) -> Result<(Vec<u8>, Option<Duration>), Reply> {
+    let ttl = match request.headers().iter().find(|h| h.field.equiv(TTL_HEADER)) {
+        None => None,
+        Some(header) => match header.value.as_str().trim().parse::<u64>() {
+            Ok(secs) if secs > 0 => Some(Duration::from_secs(secs)),
+            _ => return Err(Reply::error(400, "time to live must be a positive number of seconds")),
+        },
+    };
+
+    let mut val = Vec::with_capacity(request.body_length().unwrap_or(0));
+    request.as_reader().read_to_end(&mut val).map_err(|e| Reply::error(400, &e.to_string()))?;
+    Ok((val, ttl))
+}
+
+fn put(handle: &mut Handle<'_>, key: &[u8], val: &[u8], ttl: Option<Duration>) -> ReplyResult {
+    match ttl {
+        Some(ttl) => handle.put_with_ttl(key, val, ttl)?,
+        None => handle.put(key, val)?,
+    }
+    Ok(Reply::empty(204))
+}
+
+fn delete(handle: &mut Handle<'_>, key: &[u8]) -> ReplyResult {
+    if !handle.contains_key(key)? {
+        return Err(Reply::error(404, "key not found"));
+    }
+    handle.delete(key)?;
+    Ok(Reply::empty(204))
+}
+
+/// Lists a page of keys. The cursor is the hex encoded last key of the page, it is `null` on the last page.
+fn list(handle: &mut Handle<'_>, query: &[(String, Vec<u8>)]) -> ReplyResult {
+    let param = |name: &str| query.iter().find(|(key, _)| key == name).map(|(_, val)| val.as_slice());
+    let prefix = param("prefix").unwrap_or_default();
+    let limit = match param("limit").map(|limit| std::str::from_utf8(limit).ok().and_then(|limit| limit.parse().ok())) {
+        None => DEFAULT_LIMIT,
+        Some(Some(limit)) if (1..=MAX_LIMIT).contains(&limit) => limit,
+        Some(_) => return Err(Reply::error(400, &format!("limit must be between 1 and {MAX_LIMIT}"))),
+    };
+
+    let mut keys = vec![];
+    let after = match (param("cursor"), param("start")) {
+        (Some(cursor), _) => Some(hex_decode(cursor).ok_or_else(|| Reply::error(400, "invalid cursor"))?),
+        (None, Some(start)) => {
+            // scan starts after the given key, so the start key is checked separately
+            if start.starts_with(prefix) && handle.contains_key(start)? {
+                keys.push(start.to_vec());
+            }
+            Some(start.to_vec())
+        }
+        (None, None) => None,
+    };
+    keys.extend(handle.scan(prefix, after.as_deref(), limit - keys.len())?);
+
+    let end = param("end");
+    let before_end = keys.len();
+    keys.retain(|key| end.is_none_or(|end| key.as_slice() < end));
+
+    let cursor = match keys.last() {
+        Some(last) if keys.len() == limit && before_end == limit => Value::String(hex_encode(last)),
+        _ => Value::Null,
+    };
+    let keys: Vec<Value> = keys.iter().map(|key| Value::String(percent_encode(key))).collect();
+    Ok(Reply::json(200, json!({ "keys": keys, "cursor": cursor })))
+}
+
+fn stats(handle: &Handle<'_>) -> ReplyResult {
+    let stats = handle.stats()?;
+    let files: Vec<Value> = stats.files.iter().map(|(file_id, file)| json!({
+        "file_id": file_id,
+        "total_bytes": file.total_bytes,
+        "live_bytes": file.live_bytes,
+        "dead_bytes": file.dead_bytes,
+        "live_keys": file.live_keys,
+        "tombstones": file.tombstones,
+    })).collect();
+
+    Ok(Reply::json(200, json!({
+        "live_keys": stats.live_keys,
+        "expired_keys": stats.expired_keys,
+        "key_dir_memory": stats.key_dir_memory,
+        "oldest_file_id": stats.oldest_file_id,
+        "newest_file_id": stats.newest_file_id,
+        "total_bytes": stats.total_bytes,
+        "live_bytes": stats.live_bytes,
+        "dead_bytes": stats.dead_bytes,
+        "tombstones": stats.tombstones,
+        "files": files,
+    })))
+}
+
+fn merge(handle: &mut Handle<'_>) -> ReplyResult {
+    let report = handle.merge()?;
+    Ok(Reply::json(200, json!({ "files": report.files, "file_id": report.file_id, "reclaimed_bytes": report.reclaimed_bytes })))
+}
+
+/// Returns the backup directory in the backup root. `dir` must be a single path component.
+fn backup_dir(backup_root: Option<&Path>, query: &[(String, Vec<u8>)]) -> Result<PathBuf, Reply> {
+    let Some(backup_root) = backup_root else {
+        return Err(Reply::error(403, "backups are disabled"));
+    };
+    let Some((_, dir)) = query.iter().find(|(key, _)| key == "dir") else {
+        return Err(Reply::error(400, "dir parameter is required"));
+    };
+    let dir = String::from_utf8_lossy(dir).to_string();
+    if !matches!(Path::new(&dir).components().collect::<Vec<_>>().as_slice(), [Component::Normal(_)]) {
+        return Err(Reply::error(400, "dir must be a directory name"));
+    }
+    Ok(backup_root.join(dir))
+}
+
+fn backup(handle: &mut Handle<'_>, dir: &Path) -> ReplyResult {
+    let report = handle.backup(dir)?;
+    Ok(Reply::json(200, json!({ "dir": dir, "files": report.files, "bytes": report.bytes })))
+}
+
+fn parse_query(query: &str) -> Vec<(String, Vec<u8>)> {
+    query.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, val) = pair.split_once('=').unwrap_or((pair, ""));
+            (String::from_utf8_lossy(&percent_decode(key, true)).to_string(), percent_decode(val, true))
+        })
+        .collect()
+}
+
+/// Decodes `%XX` escapes. Invalid escapes are kept as they are. `+` is a space in query strings.
+fn percent_decode(s: &str, query: bool) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+            (b'+', _) if query => decoded.push(b' '),
+            (byte, _) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    decoded
+}
+
+fn percent_encode(key: &[u8]) -> String {
+    key.iter().map(|byte| match byte {
+        b'%' => "%25".to_string(),
+        0x21..=0x7e => (*byte as char).to_string(),
+        _ => format!("%{byte:02X}"),
+    }).collect()
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn hex_decode(hex: &[u8]) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| std::str::from_utf8(pair).ok().and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use serde_json::Value;
+    use tempdir::TempDir;
+    use tiny_http::{Method, TestRequest};
+
+    use crate::storage::{Config, Handle};
+
+    use super::{percent_decode, percent_encode, respond, Reply};
+
+    fn send(handle: &Mutex<Handle<'_>>, method: Method, path: &str, body: &'static str, ttl: Option<&str>) -> Reply {
+        let mut request = TestRequest::new().with_method(method).with_path(path).with_body(body);
+        if let Some(ttl) = ttl {
+            request = request.with_header(format!("X-Fakir-TTL: {ttl}").parse().unwrap());
+        }
+        respond(handle, None, &mut request.into())
+    }
+
+    fn json(reply: &Reply) -> Value {
+        serde_json::from_slice(&reply.body).unwrap()
+    }
+
+    #[test]
+    fn it_should_serve_keys() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        let handle = Mutex::new(Handle::open(&conf).unwrap());
+
+        // when
+        assert_eq!(204, send(&handle, Method::Put, "/kv/user%3A1", "v1", None).status);
+        assert_eq!(204, send(&handle, Method::Put, "/kv/user:2", "v2", Some("60")).status);
+        assert_eq!(400, send(&handle, Method::Put, "/kv/user:3", "v3", Some("soon")).status);
+
+        // then
+        let reply = send(&handle, Method::Get, "/kv/user:1", "", None);
+        assert_eq!((200, b"v1".to_vec()), (reply.status, reply.body));
+        let reply = send(&handle, Method::Get, "/kv/user:2", "", None);
+        assert!(reply.headers.contains(&("X-Fakir-TTL", "60".to_string())));
+
+        assert_eq!(204, send(&handle, Method::Delete, "/kv/user:1", "", None).status);
+        assert_eq!(404, send(&handle, Method::Delete, "/kv/user:1", "", None).status);
+        assert_eq!(404, send(&handle, Method::Get, "/kv/user:1", "", None).status);
+        assert_eq!(404, send(&handle, Method::Get, "/missing", "", None).status);
+    }
+
+    #[test]
+    fn it_should_list_keys_by_page() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        let handle = Mutex::new(Handle::open(&conf).unwrap());
+        for key in ["a", "user:1", "user:2", "user:3", "user:4", "user:\u{1}"] {
+            handle.lock().unwrap().put(key.as_bytes(), b"v").unwrap();
+        }
+
+        // when
+        let first = json(&send(&handle, Method::Get, "/kv?prefix=user%3A&limit=2", "", None));
+        let cursor = first["cursor"].as_str().unwrap().to_string();
+        let second = json(&send(&handle, Method::Get, &format!("/kv?prefix=user:&limit=2&cursor={cursor}"), "", None));
+        let range = json(&send(&handle, Method::Get, "/kv?start=user:2&end=user:4&limit=5", "", None));
+
+        // then
+        assert_eq!(serde_json::json!(["user:%01", "user:1"]), first["keys"]);
+        assert_eq!(serde_json::json!(["user:2", "user:3"]), second["keys"]);
+        assert_eq!(serde_json::json!(["user:2", "user:3"]), range["keys"]);
+        assert_eq!(Value::Null, range["cursor"]);
+    }
+
+    #[test]
+    fn it_should_serve_admin_endpoints() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        let backup_root = TempDir::new("bitcask-backup-").unwrap().into_path();
+        let handle = Mutex::new(Handle::open(&conf).unwrap());
+        send(&handle, Method::Put, "/kv/k1", "v1", None);
+        let backup = |dir: &str| {
+            let request = TestRequest::new().with_method(Method::Post).with_path(&format!("/admin/backup?dir={dir}"));
+            respond(&handle, Some(&backup_root), &mut request.into()).status
+        };
+
+        // when
+        let stats = json(&send(&handle, Method::Get, "/admin/stats", "", None));
+        let merge = send(&handle, Method::Post, "/admin/merge", "", None);
+
+        // then
+        assert_eq!(1, stats["live_keys"]);
+        assert_eq!(200, merge.status);
+        assert_eq!(403, send(&handle, Method::Post, "/admin/backup?dir=daily", "", None).status);
+        assert_eq!(400, backup("..%2Fdaily"));
+        assert_eq!(400, backup("%2Ftmp%2Fdaily"));
+        assert_eq!(200, backup("daily"));
+        assert_eq!(500, backup("daily"));
+
+        let backup_conf = Config { path: backup_root.join("daily"), ..Default::default() };
+        assert_eq!(Some(b"v1".to_vec()), Handle::open(&backup_conf).unwrap().get(b"k1").unwrap());
+    }
+
+    #[test]
+    fn it_should_escape_keys() {
+        assert_eq!("a%25b%20c%FF", percent_encode(b"a%b c\xff"));
+        assert_eq!(b"a%b c\xff".to_vec(), percent_decode("a%25b%20c%FF", false));
+        assert_eq!(b"a b".to_vec(), percent_decode("a+b", true));
+        assert_eq!(b"a+%zz".to_vec(), percent_decode("a+%zz", false));
+    }
+}
@@ -0,0 +1,411 @@
+//! Memcached text protocol, so memcached clients can use the store.
+//!
+//! Supported commands: get, gets, set, add, replace, cas, delete, incr, decr, touch, version and quit.
+//! Items are stored as `[flags|cas|data]` in the `memcached` bucket, so they are separate from the
+//! default keyspace of the other frontends. A command runs while the handle is locked, so `add`, `cas`
+//! and `incr` are atomic.
+
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{Error, Result};
+use crate::storage::{Bucket, Handle};
+
+const MAX_KEY_SIZE: usize = 250;
+const MAX_LINE_SIZE: u64 = 8 * 1024;
+const MAX_DATA_SIZE: usize = 1024 * 1024;
+const ITEM_HEADER_SIZE: usize = 12;
+const BUCKET: &str = "memcached";
+/// Expiry times up to 30 days are relative, larger ones are unix timestamps.
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+static LAST_CAS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StoreMode {
+    Set,
+    Add,
+    Replace,
+    Cas(u64),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Get { keys: Vec<Vec<u8>>, with_cas: bool },
+    Store { mode: StoreMode, key: Vec<u8>, flags: u32, exptime: i64, data: Vec<u8> },
+    Delete { key: Vec<u8> },
+    Incr { key: Vec<u8>, delta: u64, decr: bool },
+    Touch { key: Vec<u8>, exptime: i64 },
+    Version,
+    Quit,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Request {
+    command: Command,
+    noreply: bool,
+}
+
+/// Value of a memcached key.
+struct Item {
+    flags: u32,
+    cas: u64,
+    data: Vec<u8>,
+}
+
+impl Item {
+    fn new(flags: u32, data: Vec<u8>) -> Self {
+        Self { flags, cas: next_cas(), data }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut val = Vec::with_capacity(ITEM_HEADER_SIZE + self.data.len());
+        val.extend_from_slice(&self.flags.to_be_bytes());
+        val.extend_from_slice(&self.cas.to_be_bytes());
+        val.extend_from_slice(&self.data);
+        val
+    }
+
+    fn decode(mut val: Vec<u8>) -> Option<Self> {
+        if val.len() < ITEM_HEADER_SIZE {
+            return None;
+        }
+        let flags = u32::from_be_bytes(val[..4].try_into().unwrap());
+        let cas = u64::from_be_bytes(val[4..ITEM_HEADER_SIZE].try_into().unwrap());
+        val.drain(..ITEM_HEADER_SIZE);
+        Some(Self { flags, cas, data: val })
+    }
+}
+
+enum Expiry {
+    Never,
+    After(Duration),
+    Passed,
+}
+
+/// Serves memcached clients on the listener until it fails.
+pub fn serve(listener: &TcpListener, handle: &Mutex<Handle<'_>>) -> io::Result<()> {
+    super::serve_connections(listener, handle, serve_connection)
+}
+
+fn serve_connection(stream: TcpStream, handle: &Mutex<Handle<'_>>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    serve_stream(&mut reader, &mut writer, handle)
+}
+
+fn serve_stream(reader: &mut impl BufRead, writer: &mut impl Write, handle: &Mutex<Handle<'_>>) -> io::Result<()> {
+    loop {
+        let request = match read_request(reader) {
+            Ok(None) => return writer.flush(),
+            Ok(Some(Ok(request))) => request,
+            Ok(Some(Err(e))) => {
+                writer.write_all(&e)?;
+                writer.flush()?;
+                continue;
+            }
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                write!(writer, "CLIENT_ERROR {e}\r\n")?;
+                return writer.flush();
+            }
+            Err(e) => return Err(e),
+        };
+
+        if request.command == Command::Quit {
+            return writer.flush();
+        }
+
+        let response = execute(&mut handle.lock().unwrap(), &request.command);
+        if !request.noreply {
+            writer.write_all(&response)?;
+            writer.flush()?;
+        }
+    }
+}
+
+/// Reads the next request. Malformed requests are returned as the error response, the connection stays usable.
+fn read_request(reader: &mut impl BufRead) -> io::Result<Option<std::result::Result<Request, Vec<u8>>>> {
+    let mut line = Vec::new();
+    if reader.take(MAX_LINE_SIZE).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "line is too long or not terminated"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    let mut tokens: Vec<&[u8]> = line.split(|c| *c == b' ').filter(|t| !t.is_empty()).collect();
+    let noreply = tokens.len() > 1 && tokens.last() == Some(&b"noreply".as_slice());
+    if noreply {
+        tokens.pop();
+    }
+
+    let Some((name, args)) = tokens.split_first() else {
+        return Ok(Some(Err(b"ERROR\r\n".to_vec())));
+    };
+    let command = match (*name, args) {
+        (b"get" | b"gets", keys) if !keys.is_empty() => keys.iter()
+            .map(|key| check_key(key))
+            .collect::<std::result::Result<_, _>>()
+            .map(|keys| Command::Get { keys, with_cas: *name == b"gets" }),
+        (b"set" | b"add" | b"replace", [key, flags, exptime, size]) | (b"cas", [key, flags, exptime, size, _]) => {
+            let mode = match (*name, args) {
+                (b"add", _) => Ok(StoreMode::Add),
+                (b"replace", _) => Ok(StoreMode::Replace),
+                (b"cas", [.., cas]) => parse(cas).map(StoreMode::Cas),
+                _ => Ok(StoreMode::Set),
+            };
+            let header = (check_key(key), parse(flags), parse(exptime), parse::<usize>(size), mode);
+            let (key, flags, exptime, size, mode) = match header {
+                (Ok(key), Ok(flags), Ok(exptime), Ok(size), Ok(mode)) if size <= MAX_DATA_SIZE => (key, flags, exptime, size, mode),
+                // the data block can not be skipped safely if its size is unknown
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "bad command line format")),
+            };
+
+            let mut data = vec![0u8; size + 2];
+            reader.read_exact(&mut data)?;
+            if !data.ends_with(b"\r\n") {
+                return Ok(Some(Err(b"CLIENT_ERROR bad data chunk\r\n".to_vec())));
+            }
+            data.truncate(size);
+            Ok(Command::Store { mode, key, flags, exptime, data })
+        }
+        (b"delete", [key]) => check_key(key).map(|key| Command::Delete { key }),
+        (b"incr" | b"decr", [key, delta]) => check_key(key)
+            .and_then(|key| Ok(Command::Incr { key, delta: parse(delta)?, decr: *name == b"decr" })),
+        (b"touch", [key, exptime]) => check_key(key).and_then(|key| Ok(Command::Touch { key, exptime: parse(exptime)? })),
+        (b"version", []) => Ok(Command::Version),
+        (b"quit", []) => Ok(Command::Quit),
+        _ => return Ok(Some(Err(b"ERROR\r\n".to_vec()))),
+    };
+
+    Ok(Some(command.map(|command| Request { command, noreply })))
+}
+
+fn check_key(key: &[u8]) -> std::result::Result<Vec<u8>, Vec<u8>> {
+    if key.len() > MAX_KEY_SIZE || key.iter().any(u8::is_ascii_control) {
+        return Err(b"CLIENT_ERROR bad key\r\n".to_vec());
+    }
+    Ok(key.to_vec())
+}
+
+fn parse<T: std::str::FromStr>(token: &[u8]) -> std::result::Result<T, Vec<u8>> {
+    std::str::from_utf8(token).ok()
+        .and_then(|token| token.parse().ok())
+        .ok_or_else(|| b"CLIENT_ERROR bad command line format\r\n".to_vec())
+}
+
+/// Executes the command and returns the response. Errors of the store are returned as `SERVER_ERROR`.
+fn execute(handle: &mut Handle<'_>, command: &Command) -> Vec<u8> {
+    let result = handle.bucket(BUCKET).and_then(|mut items| match command {
+        Command::Get { keys, with_cas } => get(&mut items, keys, *with_cas),
+        Command::Store { mode, key, flags, exptime, data } => store(&mut items, *mode, key, *flags, *exptime, data),
+        Command::Delete { key } => delete(&mut items, key),
+        Command::Incr { key, delta, decr } => incr(&mut items, key, *delta, *decr),
+        Command::Touch { key, exptime } => touch(&mut items, key, *exptime),
+        Command::Version => Ok(format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")).into_bytes()),
+        Command::Quit => Ok(vec![]),
+    });
+    result.unwrap_or_else(|e| format!("SERVER_ERROR {}\r\n", e.to_string().replace(['\r', '\n'], " ")).into_bytes())
+}
+
+fn get(items: &mut Bucket<'_, '_>, keys: &[Vec<u8>], with_cas: bool) -> Result<Vec<u8>> {
+    let mut response = vec![];
+    for key in keys {
+        let Some(item) = read_item(items, key)? else {
+            continue;
+        };
+
+        response.extend_from_slice(b"VALUE ");
+        response.extend_from_slice(key);
+        match with_cas {
+            true => response.extend_from_slice(format!(" {} {} {}\r\n", item.flags, item.data.len(), item.cas).as_bytes()),
+            false => response.extend_from_slice(format!(" {} {}\r\n", item.flags, item.data.len()).as_bytes()),
+        }
+        response.extend_from_slice(&item.data);
+        response.extend_from_slice(b"\r\n");
+    }
+    response.extend_from_slice(b"END\r\n");
+    Ok(response)
+}
+
+fn store(items: &mut Bucket<'_, '_>, mode: StoreMode, key: &[u8], flags: u32, exptime: i64, data: &[u8]) -> Result<Vec<u8>> {
+    let current = read_item(items, key)?;
+    let response: &[u8] = match (mode, &current) {
+        (StoreMode::Add, Some(_)) | (StoreMode::Replace, None) => b"NOT_STORED\r\n",
+        (StoreMode::Cas(_), None) => b"NOT_FOUND\r\n",
+        (StoreMode::Cas(cas), Some(item)) if item.cas != cas => b"EXISTS\r\n",
+        _ => {
+            write_item(items, key, &Item::new(flags, data.to_vec()), expiry(exptime))?;
+            b"STORED\r\n"
+        }
+    };
+    Ok(response.to_vec())
+}
+
+fn delete(items: &mut Bucket<'_, '_>, key: &[u8]) -> Result<Vec<u8>> {
+    if !items.contains_key(key)? {
+        return Ok(b"NOT_FOUND\r\n".to_vec());
+    }
+    items.delete(key)?;
+    Ok(b"DELETED\r\n".to_vec())
+}
+
+/// Increments wrap around at 2^64, decrements stop at 0. The key keeps its expiry time.
+fn incr(items: &mut Bucket<'_, '_>, key: &[u8], delta: u64, decr: bool) -> Result<Vec<u8>> {
+    let Some(item) = read_item(items, key)? else {
+        return Ok(b"NOT_FOUND\r\n".to_vec());
+    };
+    let Some(current) = std::str::from_utf8(&item.data).ok().and_then(|data| data.trim_end().parse::<u64>().ok()) else {
+        return Ok(b"CLIENT_ERROR cannot increment or decrement non-numeric value\r\n".to_vec());
+    };
+
+    let value = if decr { current.saturating_sub(delta) } else { current.wrapping_add(delta) };
+    let expiry = match items.ttl(key) {
+        Err(Error::Expired) => return Ok(b"NOT_FOUND\r\n".to_vec()),
+        ttl => ttl?.map_or(Expiry::Never, Expiry::After),
+    };
+    write_item(items, key, &Item::new(item.flags, value.to_string().into_bytes()), expiry)?;
+    Ok(format!("{value}\r\n").into_bytes())
+}
+
+fn touch(items: &mut Bucket<'_, '_>, key: &[u8], exptime: i64) -> Result<Vec<u8>> {
+    let Some(item) = read_item(items, key)? else {
+        return Ok(b"NOT_FOUND\r\n".to_vec());
+    };
+    write_item(items, key, &item, expiry(exptime))?;
+    Ok(b"TOUCHED\r\n".to_vec())
+}
+
+fn read_item(items: &mut Bucket<'_, '_>, key: &[u8]) -> Result<Option<Item>> {
+    Ok(items.get(key)?.and_then(Item::decode))
+}
+
+fn write_item(items: &mut Bucket<'_, '_>, key: &[u8], item: &Item, expiry: Expiry) -> Result<()> {
+    match expiry {
+        Expiry::Never => items.put(key, &item.encode()),
+        Expiry::After(ttl) => items.put_with_ttl(key, &item.encode(), ttl),
+        // the item is stored and expires right away, so the previous value is gone
+        Expiry::Passed => items.delete(key),
+    }
+}
+
+/// Converts the exptime of a command: `0` never expires, up to 30 days is relative and larger values
+/// are unix timestamps. Negative values expire immediately.
+fn expiry(exptime: i64) -> Expiry {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    match exptime {
+        0 => Expiry::Never,
+        t if t < 0 => Expiry::Passed,
+        t if t <= MAX_RELATIVE_EXPTIME => Expiry::After(Duration::from_secs(t as u64)),
+        t if t > now => Expiry::After(Duration::from_secs((t - now) as u64)),
+        _ => Expiry::Passed,
+    }
+}
+
+/// Returns a cas value greater than the previous one. It is based on the clock, so values stay unique after restart.
+fn next_cas() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as u64;
+    let previous = LAST_CAS.fetch_update(Ordering::AcqRel, Ordering::Acquire, |last| Some(now.max(last + 1))).unwrap();
+    now.max(previous + 1)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use std::sync::Mutex;
+
+    use tempdir::TempDir;
+
+    use crate::storage::{Config, Handle};
+
+    use super::{BUCKET, serve_stream};
+
+    fn session(handle: &Mutex<Handle<'_>>, input: &str) -> String {
+        let mut output = vec![];
+        serve_stream(&mut Cursor::new(input.as_bytes().to_vec()), &mut output, handle).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn it_should_store_items_with_flags() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        let handle = Mutex::new(Handle::open(&conf).unwrap());
+
+        // when
+        let output = session(&handle, "set k1 42 0 2\r\nv1\r\nadd k1 0 0 1\r\nx\r\nreplace k2 0 0 1\r\nx\r\n\
+            add k2 7 100 5\r\nhello\r\nset quiet 0 0 1 noreply\r\nq\r\nget k1 k2 missing\r\n");
+
+        // then
+        assert_eq!("STORED\r\nNOT_STORED\r\nNOT_STORED\r\nSTORED\r\n\
+            VALUE k1 42 2\r\nv1\r\nVALUE k2 7 5\r\nhello\r\nEND\r\n", output);
+        let mut handle = handle.lock().unwrap();
+        let mut items = handle.bucket(BUCKET).unwrap();
+        assert!(items.contains_key(b"quiet").unwrap());
+        assert!(items.ttl(b"k2").unwrap().is_some());
+    }
+
+    #[test]
+    fn it_should_not_read_values_of_other_frontends() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        let handle = Mutex::new(Handle::open(&conf).unwrap());
+        handle.lock().unwrap().put(b"k1", b"twenty bytes value!!").unwrap();
+
+        // when
+        let output = session(&handle, "get k1\r\nincr k1 1\r\nset k1 0 0 2\r\nv2\r\nget k1\r\n");
+
+        // then
+        assert_eq!("END\r\nNOT_FOUND\r\nSTORED\r\nVALUE k1 0 2\r\nv2\r\nEND\r\n", output);
+        assert_eq!(Some(b"twenty bytes value!!".to_vec()), handle.lock().unwrap().get(b"k1").unwrap());
+    }
+
+    #[test]
+    fn it_should_compare_and_swap() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        let handle = Mutex::new(Handle::open(&conf).unwrap());
+        session(&handle, "set k1 0 0 2\r\nv1\r\n");
+        let output = session(&handle, "gets k1\r\n");
+        let cas: u64 = output.lines().next().unwrap().rsplit(' ').next().unwrap().parse().unwrap();
+
+        // when
+        let output = session(&handle, &format!("cas k1 0 0 2 {cas}\r\nv2\r\ncas k1 0 0 2 {cas}\r\nv3\r\ncas k2 0 0 1 1\r\nx\r\nget k1\r\n"));
+
+        // then
+        assert_eq!("STORED\r\nEXISTS\r\nNOT_FOUND\r\nVALUE k1 0 2\r\nv2\r\nEND\r\n", output);
+    }
+
+    #[test]
+    fn it_should_update_counters() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        let handle = Mutex::new(Handle::open(&conf).unwrap());
+
+        // when
+        let output = session(&handle, "set n 5 0 2\r\n10\r\nincr n 5\r\ndecr n 100\r\nincr missing 1\r\nset s 0 0 1\r\na\r\nincr s 1\r\n\
+            touch n -1\r\nget n\r\ndelete s\r\ndelete s\r\nbogus\r\nget\r\n");
+
+        // then
+        assert_eq!("STORED\r\n15\r\n0\r\nNOT_FOUND\r\nSTORED\r\nCLIENT_ERROR cannot increment or decrement non-numeric value\r\n\
+            TOUCHED\r\nEND\r\nDELETED\r\nNOT_FOUND\r\nERROR\r\nERROR\r\n", output);
+    }
+}
@@ -0,0 +1,32 @@
+//! Network frontends which serve a [Handle] to clients of other stores.
+
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::thread;
+
+use log::debug;
+
+use crate::storage::Handle;
+
+pub mod http;
+pub mod memcached;
+pub mod resp;
+
+/// Serves every accepted connection on its own thread until the listener fails.
+/// Connections share the handle, so commands are executed one at a time.
+pub(crate) fn serve_connections(listener: &TcpListener, handle: &Mutex<Handle<'_>>,
+                                serve: fn(TcpStream, &Mutex<Handle<'_>>) -> io::Result<()>) -> io::Result<()> {
+    thread::scope(|scope| {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            scope.spawn(move || {
+                let peer = stream.peer_addr().ok();
+                if let Err(e) = serve(stream, handle) {
+                    debug!("connection of {peer:?} is closed: {e}");
+                }
+            });
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,592 @@
+//! Redis serialization protocol (RESP), so existing Redis clients can use the store.
+//!
+//! Supported commands: PING, ECHO, SELECT 0, COMMAND, QUIT, GET, SET (EX, PX, NX, XX), DEL, EXISTS,
+//! KEYS, SCAN (MATCH, COUNT), TTL, PTTL, EXPIRE, PEXPIRE, DBSIZE and INFO.
+
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::Error;
+use crate::storage::Handle;
+
+/// Same as the default `proto-max-bulk-len` of Redis.
+const MAX_BULK_SIZE: usize = 512 * 1024 * 1024;
+const MAX_ARGS: usize = 1024 * 1024;
+const MAX_LINE_SIZE: u64 = 64 * 1024;
+const DEFAULT_SCAN_COUNT: usize = 10;
+const KEYS_PAGE_SIZE: usize = 1024;
+
+/// Name, minimum and maximum argument count of the supported commands.
+const COMMANDS: &[(&str, usize, usize)] = &[
+    ("PING", 0, 1),
+    ("ECHO", 1, 1),
+    ("SELECT", 1, 1),
+    ("COMMAND", 0, usize::MAX),
+    ("QUIT", 0, 0),
+    ("GET", 1, 1),
+    ("SET", 2, usize::MAX),
+    ("DEL", 1, usize::MAX),
+    ("EXISTS", 1, usize::MAX),
+    ("KEYS", 1, 1),
+    ("SCAN", 1, usize::MAX),
+    ("TTL", 1, 1),
+    ("PTTL", 1, 1),
+    ("EXPIRE", 2, 2),
+    ("PEXPIRE", 2, 2),
+    ("DBSIZE", 0, 0),
+    ("INFO", 0, usize::MAX),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    /// `None` is the null bulk string.
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn ok() -> Self {
+        Reply::Simple("OK".to_string())
+    }
+
+    fn error(msg: &str) -> Self {
+        Reply::Error(format!("ERR {msg}"))
+    }
+
+    pub fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+        match self {
+            Reply::Simple(s) => write!(w, "+{s}\r\n"),
+            // line breaks would end the reply early
+            Reply::Error(e) => write!(w, "-{}\r\n", e.replace(['\r', '\n'], " ")),
+            Reply::Integer(n) => write!(w, ":{n}\r\n"),
+            Reply::Bulk(None) => w.write_all(b"$-1\r\n"),
+            Reply::Bulk(Some(val)) => {
+                write!(w, "${}\r\n", val.len())?;
+                w.write_all(val)?;
+                w.write_all(b"\r\n")
+            }
+            Reply::Array(items) => {
+                write!(w, "*{}\r\n", items.len())?;
+                items.iter().try_for_each(|item| item.write_to(w))
+            }
+        }
+    }
+}
+
+impl From<Error> for Reply {
+    fn from(e: Error) -> Self {
+        Reply::error(&e.to_string())
+    }
+}
+
+type CommandResult = Result<Reply, Reply>;
+
+/// Serves RESP clients on the listener until it fails.
+pub fn serve(listener: &TcpListener, handle: &Mutex<Handle<'_>>) -> io::Result<()> {
+    super::serve_connections(listener, handle, serve_connection)
+}
+
+fn serve_connection(stream: TcpStream, handle: &Mutex<Handle<'_>>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    loop {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) if args.is_empty() => continue,
+            Ok(Some(args)) => args,
+            Ok(None) => return writer.flush(),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                Reply::error(&format!("Protocol error: {e}")).write_to(&mut writer)?;
+                return writer.flush();
+            }
+            Err(e) => return Err(e),
+        };
+
+        let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+        let reply = if quit { Reply::ok() } else { execute(&mut handle.lock().unwrap(), &args) };
+        reply.write_to(&mut writer)?;
+
+        // replies of pipelined commands are sent together
+        if quit || reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+        if quit {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads a command sent as an array of bulk strings, or as an inline command separated by spaces.
+/// Returns `None` at the end of the stream.
+pub fn read_command(reader: &mut impl BufRead) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(reader)? else {
+        return Ok(None);
+    };
+
+    if line.first() != Some(&b'*') {
+        let args = line.split(u8::is_ascii_whitespace).filter(|arg| !arg.is_empty()).map(<[u8]>::to_vec).collect();
+        return Ok(Some(args));
+    }
+
+    let count = parse_size(&line[1..], MAX_ARGS)?;
+    let mut args = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+        if line.first() != Some(&b'$') {
+            return Err(invalid_data("expected '$'"));
+        }
+
+        let size = parse_size(&line[1..], MAX_BULK_SIZE)?;
+        let mut arg = vec![0u8; size + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(invalid_data("bulk string is not terminated"));
+        }
+        arg.truncate(size);
+        args.push(arg);
+    }
+
+    Ok(Some(args))
+}
+
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if reader.take(MAX_LINE_SIZE).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(invalid_data("line is too long or not terminated"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_size(digits: &[u8], limit: usize) -> io::Result<usize> {
+    std::str::from_utf8(digits).ok()
+        .and_then(|digits| digits.parse::<usize>().ok())
+        .filter(|size| *size <= limit)
+        .ok_or_else(|| invalid_data("invalid length"))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Executes a command on the handle. Errors, including errors of the store, are returned as error replies.
+pub fn execute(handle: &mut Handle<'_>, command: &[Vec<u8>]) -> Reply {
+    let Some((name, args)) = command.split_first() else {
+        return Reply::error("empty command");
+    };
+    let name = String::from_utf8_lossy(name).to_ascii_uppercase();
+
+    match COMMANDS.iter().find(|(command, _, _)| *command == name) {
+        None => return Reply::error(&format!("unknown command '{name}'")),
+        Some((_, min, max)) if args.len() < *min || args.len() > *max => {
+            return Reply::error(&format!("wrong number of arguments for '{}' command", name.to_lowercase()));
+        }
+        Some(_) => {}
+    }
+
+    let result = match name.as_str() {
+        "PING" => Ok(args.first().map_or(Reply::Simple("PONG".to_string()), |msg| Reply::Bulk(Some(msg.clone())))),
+        "ECHO" => Ok(Reply::Bulk(Some(args[0].clone()))),
+        "SELECT" if args[0] == b"0" => Ok(Reply::ok()),
+        "SELECT" => Err(Reply::error("DB index is out of range")),
+        "COMMAND" => Ok(Reply::Array(vec![])),
+        "QUIT" => Ok(Reply::ok()),
+        "GET" => handle.get(&args[0]).map(Reply::Bulk).map_err(Reply::from),
+        "SET" => set(handle, args),
+        "DEL" => delete(handle, args),
+        "EXISTS" => exists(handle, args),
+        "KEYS" => keys(handle, &args[0]),
+        "SCAN" => scan(handle, args),
+        "TTL" => ttl(handle, &args[0], 1000),
+        "PTTL" => ttl(handle, &args[0], 1),
+        "EXPIRE" => expire(handle, args, 1000),
+        "PEXPIRE" => expire(handle, args, 1),
+        "DBSIZE" => handle.keyspace_stats(None).map(|stats| Reply::Integer((stats.live_keys - stats.expired_keys) as i64)).map_err(Reply::from),
+        "INFO" => info(handle),
+        _ => unreachable!("command is listed in COMMANDS"),
+    };
+
+    result.unwrap_or_else(|e| e)
+}
+
+fn set(handle: &mut Handle<'_>, args: &[Vec<u8>]) -> CommandResult {
+    let (key, val) = (&args[0], &args[1]);
+    let (mut ttl, mut nx, mut xx) = (None, false, false);
+
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        match option.to_ascii_uppercase().as_slice() {
+            b"NX" => nx = true,
+            b"XX" => xx = true,
+            unit @ (b"EX" | b"PX") if ttl.is_none() => {
+                let time = options.next().ok_or_else(syntax_error).and_then(|arg| parse_int(arg))?;
+                if time <= 0 {
+                    return Err(Reply::error("invalid expire time in 'set' command"));
+                }
+                ttl = Some(if unit == b"EX" { Duration::from_secs(time as u64) } else { Duration::from_millis(time as u64) });
+            }
+            _ => return Err(syntax_error()),
+        }
+    }
+
+    if nx && xx {
+        return Err(syntax_error());
+    }
+    if (nx || xx) && handle.contains_key(key)? == nx {
+        return Ok(Reply::Bulk(None));
+    }
+
+    match ttl {
+        Some(ttl) => handle.put_with_ttl(key, val, ttl)?,
+        None => handle.put(key, val)?,
+    }
+    Ok(Reply::ok())
+}
+
+fn delete(handle: &mut Handle<'_>, keys: &[Vec<u8>]) -> CommandResult {
+    let mut deleted = 0;
+    for key in keys {
+        if handle.contains_key(key)? {
+            handle.delete(key)?;
+            deleted += 1;
+        }
+    }
+    Ok(Reply::Integer(deleted))
+}
+
+fn exists(handle: &mut Handle<'_>, keys: &[Vec<u8>]) -> CommandResult {
+    let mut count = 0;
+    for key in keys {
+        count += handle.contains_key(key)? as i64;
+    }
+    Ok(Reply::Integer(count))
+}
+
+fn keys(handle: &mut Handle<'_>, pattern: &[u8]) -> CommandResult {
+    let prefix = literal_prefix(pattern);
+    let mut keys = vec![];
+    let mut after = None;
+    loop {
+        let page = handle.scan(prefix, after.as_deref(), KEYS_PAGE_SIZE)?;
+        let done = page.len() < KEYS_PAGE_SIZE;
+        after = page.last().cloned();
+        keys.extend(page.into_iter().filter(|key| glob_match(pattern, key)).map(|key| Reply::Bulk(Some(key))));
+        if done {
+            return Ok(Reply::Array(keys));
+        }
+    }
+}
+
+/// Like Redis, `MATCH` filters the page after it is read, so a page can be empty before the end.
+fn scan(handle: &mut Handle<'_>, args: &[Vec<u8>]) -> CommandResult {
+    let after = decode_cursor(&args[0]).ok_or_else(|| Reply::error("invalid cursor"))?;
+    let (mut pattern, mut count) = (b"*".as_slice(), DEFAULT_SCAN_COUNT);
+
+    let mut options = args[1..].iter();
+    while let Some(option) = options.next() {
+        let arg = options.next().ok_or_else(syntax_error)?;
+        match option.to_ascii_uppercase().as_slice() {
+            b"MATCH" => pattern = arg,
+            b"COUNT" => match parse_int(arg)? {
+                n if n >= 1 => count = n as usize,
+                _ => return Err(syntax_error()),
+            },
+            _ => return Err(syntax_error()),
+        }
+    }
+
+    let page = handle.scan(literal_prefix(pattern), after.as_deref(), count)?;
+    let cursor = match page.last() {
+        Some(last) if page.len() == count => encode_cursor(last),
+        _ => b"0".to_vec(),
+    };
+    let keys = page.into_iter().filter(|key| glob_match(pattern, key)).map(|key| Reply::Bulk(Some(key))).collect();
+
+    Ok(Reply::Array(vec![Reply::Bulk(Some(cursor)), Reply::Array(keys)]))
+}
+
+/// Returns the time to live in the unit of milliseconds, `-2` if the key does not exist and `-1` if it never expires.
+fn ttl(handle: &mut Handle<'_>, key: &[u8], unit_millis: u128) -> CommandResult {
+    if !handle.contains_key(key)? {
+        return Ok(Reply::Integer(-2));
+    }
+    Ok(Reply::Integer(match handle.ttl(key) {
+        Err(Error::Expired) => -2,
+        Err(e) => return Err(e.into()),
+        Ok(None) => -1,
+        // rounded like Redis
+        Ok(Some(ttl)) => ((ttl.as_millis() + unit_millis / 2) / unit_millis) as i64,
+    }))
+}
+
+/// A time to live which is not positive deletes the key.
+fn expire(handle: &mut Handle<'_>, args: &[Vec<u8>], unit_millis: i64) -> CommandResult {
+    let key = &args[0];
+    let ttl = parse_int(&args[1])?
+        .checked_mul(unit_millis)
+        .ok_or_else(|| Reply::error("invalid expire time in 'expire' command"))?;
+
+    if ttl <= 0 {
+        return delete(handle, std::slice::from_ref(key));
+    }
+    Ok(Reply::Integer(handle.expire(key, Duration::from_millis(ttl as u64))? as i64))
+}
+
+fn info(handle: &mut Handle<'_>) -> CommandResult {
+    let stats = handle.stats()?;
+    // buckets are not visible over RESP
+    let keyspace = handle.keyspace_stats(None)?;
+    let info = [
+        "# Server".to_string(),
+        format!("fakir_version:{}", env!("CARGO_PKG_VERSION")),
+        String::new(),
+        "# Keyspace".to_string(),
+        format!("db0:keys={},expired={}", keyspace.live_keys - keyspace.expired_keys, keyspace.expired_keys),
+        String::new(),
+        "# Storage".to_string(),
+        format!("data_files:{}", stats.files.len()),
+        format!("total_bytes:{}", stats.total_bytes),
+        format!("live_bytes:{}", stats.live_bytes),
+        format!("dead_bytes:{}", stats.dead_bytes),
+        format!("tombstones:{}", stats.tombstones),
+        format!("key_dir_memory:{}", stats.key_dir_memory),
+    ];
+    Ok(Reply::Bulk(Some(format!("{}\r\n", info.join("\r\n")).into_bytes())))
+}
+
+fn parse_int(arg: &[u8]) -> Result<i64, Reply> {
+    std::str::from_utf8(arg).ok()
+        .and_then(|arg| arg.parse().ok())
+        .ok_or_else(|| Reply::error("value is not an integer or out of range"))
+}
+
+fn syntax_error() -> Reply {
+    Reply::error("syntax error")
+}
+
+/// Encodes the last key of a SCAN page as a decimal number, because clients parse cursors as integers.
+/// The key is prefixed with `1`, so leading zero bytes are kept and `0` stays free for the start and the end.
+fn encode_cursor(key: &[u8]) -> Vec<u8> {
+    // big-endian base 256 number
+    let mut num: Vec<u8> = std::iter::once(1).chain(key.iter().copied()).collect();
+    let mut digits = vec![];
+    while !num.is_empty() {
+        let mut rem = 0u32;
+        for byte in num.iter_mut() {
+            let cur = (rem << 8) | *byte as u32;
+            *byte = (cur / 10) as u8;
+            rem = cur % 10;
+        }
+        digits.push(b'0' + rem as u8);
+        let zeros = num.iter().take_while(|b| **b == 0).count();
+        num.drain(..zeros);
+    }
+    digits.reverse();
+    digits
+}
+
+/// Returns the key to continue after, `None` to start from the beginning.
+fn decode_cursor(cursor: &[u8]) -> Option<Option<Vec<u8>>> {
+    if cursor == b"0" {
+        return Some(None);
+    }
+    if cursor.is_empty() || !cursor.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+
+    // little-endian base 256 number
+    let mut num: Vec<u8> = vec![];
+    for digit in cursor {
+        let mut carry = (digit - b'0') as u32;
+        for byte in num.iter_mut() {
+            let cur = *byte as u32 * 10 + carry;
+            *byte = cur as u8;
+            carry = cur >> 8;
+        }
+        if carry > 0 {
+            num.push(carry as u8);
+        }
+    }
+    num.reverse();
+
+    match num.split_first() {
+        Some((1, key)) => Some(Some(key.to_vec())),
+        _ => None,
+    }
+}
+
+/// Part of the pattern before the first special character. Matching keys start with it.
+fn literal_prefix(pattern: &[u8]) -> &[u8] {
+    let end = pattern.iter().position(|c| b"*?[\\".contains(c)).unwrap_or(pattern.len());
+    &pattern[..end]
+}
+
+/// Matches glob-style patterns of Redis: `*`, `?`, `[abc]`, `[^a-z]` and `\` escapes.
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    match pattern {
+        [] => s.is_empty(),
+        [b'*', rest @ ..] => {
+            let rest = &rest[rest.iter().take_while(|c| **c == b'*').count()..];
+            (0..=s.len()).any(|i| glob_match(rest, &s[i..]))
+        }
+        [b'?', rest @ ..] => !s.is_empty() && glob_match(rest, &s[1..]),
+        [b'[', class @ ..] => {
+            let Some((&c, tail)) = s.split_first() else {
+                return false;
+            };
+            let (negate, mut class) = match class {
+                [b'^', class @ ..] => (true, class),
+                _ => (false, class),
+            };
+
+            let mut matched = false;
+            loop {
+                match class {
+                    [] => return false,
+                    [b']', rest @ ..] => {
+                        class = rest;
+                        break;
+                    }
+                    [b'\\', x, rest @ ..] => {
+                        matched |= *x == c;
+                        class = rest;
+                    }
+                    [lo, b'-', hi, rest @ ..] if *hi != b']' => {
+                        matched |= (*lo.min(hi)..=*lo.max(hi)).contains(&c);
+                        class = rest;
+                    }
+                    [x, rest @ ..] => {
+                        matched |= *x == c;
+                        class = rest;
+                    }
+                }
+            }
+            matched != negate && glob_match(class, tail)
+        }
+        [b'\\', x, rest @ ..] | [x, rest @ ..] => s.first() == Some(x) && glob_match(rest, &s[1..]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    use tempdir::TempDir;
+
+    use crate::storage::{Config, Handle};
+
+    use super::{decode_cursor, encode_cursor, execute, glob_match, read_command, Reply};
+
+    fn run(handle: &mut Handle<'_>, command: &str) -> Reply {
+        let args: Vec<Vec<u8>> = command.split(' ').map(|arg| arg.as_bytes().to_vec()).collect();
+        execute(handle, &args)
+    }
+
+    fn bulk(val: &str) -> Reply {
+        Reply::Bulk(Some(val.as_bytes().to_vec()))
+    }
+
+    #[test]
+    fn it_should_read_pipelined_commands() {
+        // given
+        let mut input = Cursor::new(b"*3\r\n$3\r\nSET\r\n$2\r\nk1\r\n$4\r\nv\r\n1\r\nGET  k1\r\n*1\r\n$3\r\nGET\r\n".to_vec());
+
+        // then
+        assert_eq!(Some(vec![b"SET".to_vec(), b"k1".to_vec(), b"v\r\n1".to_vec()]), read_command(&mut input).unwrap());
+        assert_eq!(Some(vec![b"GET".to_vec(), b"k1".to_vec()]), read_command(&mut input).unwrap());
+        assert_eq!(Some(vec![b"GET".to_vec()]), read_command(&mut input).unwrap());
+        assert_eq!(None, read_command(&mut input).unwrap());
+        assert!(read_command(&mut Cursor::new(b"*1\r\n$9\r\nGET\r\n".to_vec())).is_err());
+    }
+
+    #[test]
+    fn it_should_encode_replies() {
+        let mut out = vec![];
+        Reply::Array(vec![bulk("k1"), Reply::Bulk(None), Reply::Integer(-2), Reply::Error("ERR a\r\nb".to_string())])
+            .write_to(&mut out)
+            .unwrap();
+        assert_eq!(b"*4\r\n$2\r\nk1\r\n$-1\r\n:-2\r\n-ERR a  b\r\n".to_vec(), out);
+    }
+
+    #[test]
+    fn it_should_round_trip_scan_cursors() {
+        for key in [b"".as_slice(), b"k1", b"\x00\x00k", b"\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff"] {
+            let cursor = encode_cursor(key);
+            assert!(cursor.iter().all(u8::is_ascii_digit));
+            assert_eq!(Some(Some(key.to_vec())), decode_cursor(&cursor));
+        }
+        assert_eq!(b"256".to_vec(), encode_cursor(b"\x00"));
+        assert_eq!(Some(None), decode_cursor(b"0"));
+        assert_eq!(None, decode_cursor(b"12"));
+        assert_eq!(None, decode_cursor(b"k1"));
+    }
+
+    #[test]
+    fn it_should_match_globs() {
+        assert!(glob_match(b"user:*", b"user:1"));
+        assert!(glob_match(b"*:1", b"user:1"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-f]llo", b"hello"));
+        assert!(glob_match(b"a\\*", b"a*"));
+        assert!(!glob_match(b"a\\*", b"ab"));
+        assert!(!glob_match(b"user:*", b"order:1"));
+    }
+
+    #[test]
+    fn it_should_execute_commands() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        let mut handle = Handle::open(&conf).unwrap();
+
+        // when
+        for i in 0..5 {
+            assert_eq!(Reply::ok(), run(&mut handle, &format!("SET user:{i} v{i}")));
+        }
+        run(&mut handle, "SET order:1 o1 EX 100");
+        handle.bucket("b1").unwrap().put(b"user:9", b"v9").unwrap();
+        handle.put_with_ttl(b"user:8", b"v8", Duration::from_millis(1)).unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+
+        // then
+        assert_eq!(bulk("v1"), run(&mut handle, "GET user:1"));
+        assert_eq!(Reply::Bulk(None), run(&mut handle, "SET user:1 other NX"));
+        assert_eq!(Reply::Bulk(None), run(&mut handle, "SET missing other XX"));
+        assert_eq!(Reply::Integer(2), run(&mut handle, "EXISTS user:1 missing order:1"));
+        assert_eq!(Reply::Integer(100), run(&mut handle, "TTL order:1"));
+        assert_eq!(Reply::Integer(-1), run(&mut handle, "TTL user:1"));
+        assert_eq!(Reply::Integer(-2), run(&mut handle, "TTL missing"));
+        assert_eq!(Reply::Integer(1), run(&mut handle, "EXPIRE user:4 10"));
+        assert_eq!(bulk("v4"), run(&mut handle, "GET user:4"));
+        assert_eq!(Reply::Integer(1), run(&mut handle, "PEXPIRE user:4 0"));
+        assert_eq!(Reply::Integer(1), run(&mut handle, "DEL user:3 missing"));
+        assert_eq!(Reply::Array(vec![bulk("user:0"), bulk("user:1"), bulk("user:2")]), run(&mut handle, "KEYS user:*"));
+        assert_eq!(Reply::Integer(4), run(&mut handle, "DBSIZE"));
+
+        let Reply::Array(page) = run(&mut handle, "SCAN 0 MATCH user:* COUNT 2") else { panic!("unexpected reply") };
+        assert_eq!(Reply::Array(vec![bulk("user:0"), bulk("user:1")]), page[1]);
+        let Reply::Bulk(Some(cursor)) = &page[0] else { panic!("unexpected cursor") };
+        let next = run(&mut handle, &format!("SCAN {} MATCH user:* COUNT 2", String::from_utf8_lossy(cursor)));
+        assert_eq!(Reply::Array(vec![bulk("0"), Reply::Array(vec![bulk("user:2")])]), next);
+
+        assert!(matches!(run(&mut handle, "SET k v EX 0"), Reply::Error(_)));
+        assert!(matches!(run(&mut handle, "GET"), Reply::Error(e) if e.contains("wrong number")));
+        assert!(matches!(run(&mut handle, "FLUSHALL"), Reply::Error(e) if e.contains("unknown command")));
+        assert!(matches!(run(&mut handle, "INFO"), Reply::Bulk(Some(info)) if String::from_utf8_lossy(&info).contains("db0:keys=4,expired=1")));
+    }
+}
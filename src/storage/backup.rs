@@ -0,0 +1,53 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use anyhow::bail;
+use serde::Serialize;
+
+use crate::storage::rate_limit::RateLimiter;
+use crate::storage::rebuild::extract_data_file_ids;
+use crate::storage::utils::{build_data_file_name, open_file_for_read, sync_dir};
+
+const COPY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Result of [crate::storage::Handle::backup].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct BackupReport {
+    /// Ids of the copied data files.
+    pub files: Vec<u64>,
+    pub bytes: u64,
+}
+
+/// Copies the data files of `src` into `dest`, which must not contain data files.
+/// Data files must not change during the copy.
+pub(crate) fn copy_data_files(src: &Path, dest: &Path, rate_limiter: &RateLimiter) -> anyhow::Result<BackupReport> {
+    fs::create_dir_all(dest)?;
+    if extract_data_file_ids(dest)?.next().is_some() {
+        bail!("backup directory {} already contains data files", dest.display());
+    }
+
+    let mut report = BackupReport::default();
+    let mut chunk = vec![0u8; COPY_CHUNK_SIZE];
+    for file_id in extract_data_file_ids(src)? {
+        let name = build_data_file_name(file_id);
+        let mut from = open_file_for_read(src, &name)?;
+        let mut to = fs::File::create(dest.join(&name))?;
+
+        loop {
+            let size = from.read(&mut chunk)?;
+            if size == 0 {
+                break;
+            }
+            rate_limiter.acquire(size as u64);
+            to.write_all(&chunk[..size])?;
+            report.bytes += size as u64;
+        }
+
+        to.sync_all()?;
+        report.files.push(file_id);
+    }
+
+    sync_dir(dest)?;
+    Ok(report)
+}
@@ -0,0 +1,136 @@
+// Records of a bucket have FLAG_BUCKET and the bucket name prefixed to the key: [name_len|name|key]
+// A drop marker has FLAG_BUCKET_DROP, its key is [name_len|name] and its value is the position
+// of the marker when it is first written: [file_id|offset]
+
+use std::borrow::Cow;
+use std::time::Duration;
+
+use anyhow::bail;
+use bytes::BufMut;
+
+use crate::{Error, Result};
+use crate::storage::{BucketStats, Handle, Watcher};
+use crate::storage::log::FLAG_BUCKET;
+
+pub(crate) const MAX_BUCKET_NAME_SIZE: usize = u8::MAX as usize;
+
+/// A named keyspace of the store, returned by [Handle::bucket].
+///
+/// Buckets share the data files and the writer of the handle, but keys and the
+/// time to live are separate. See [crate::storage::Config::bucket_expiry_secs].
+pub struct Bucket<'h, 'a> {
+    handle: &'h mut Handle<'a>,
+    name: Vec<u8>,
+}
+
+impl<'h, 'a> Bucket<'h, 'a> {
+    pub(crate) fn new(handle: &'h mut Handle<'a>, name: Vec<u8>) -> Self {
+        Self { handle, name }
+    }
+
+    pub fn name(&self) -> String {
+        String::from_utf8_lossy(&self.name).to_string()
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.handle.get_in(Some(&self.name), key)
+    }
+
+    pub fn put(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
+        self.handle.put_in(Some(&self.name), key, val)
+    }
+
+    pub fn contains_key(&mut self, key: &[u8]) -> Result<bool> {
+        self.handle.contains_key_in(Some(&self.name), key)
+    }
+
+    /// Writes the value which expires after `ttl`, see [Handle::put_with_ttl].
+    pub fn put_with_ttl(&mut self, key: &[u8], val: &[u8], ttl: Duration) -> Result<()> {
+        self.handle.put_with_ttl_in(Some(&self.name), key, val, ttl)
+    }
+
+    /// Returns the remaining time to live of the key, see [Handle::ttl].
+    pub fn ttl(&mut self, key: &[u8]) -> Result<Option<Duration>> {
+        self.handle.ttl_in(Some(&self.name), key)
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.handle.delete_in(Some(&self.name), key)
+    }
+
+    /// Returns changes of the bucket keys which start with `prefix`, see [Handle::watch].
+    pub fn watch(&self, prefix: &[u8]) -> Result<Watcher> {
+        self.handle.watch_in(Some(&self.name), prefix)
+    }
+
+    pub fn stats(&self) -> Result<BucketStats> {
+        self.handle.keyspace_stats(Some(&self.name))
+    }
+}
+
+pub(crate) fn check_bucket_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > MAX_BUCKET_NAME_SIZE {
+        return Err(Error::InvalidBucketName(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Returns the key stored in the data file.
+pub(crate) fn record_key<'k>(bucket: Option<&[u8]>, key: &'k [u8]) -> Cow<'k, [u8]> {
+    match bucket {
+        None => Cow::Borrowed(key),
+        Some(name) => {
+            let mut record_key = Vec::with_capacity(1 + name.len() + key.len());
+            record_key.put_u8(name.len() as u8);
+            record_key.put(name);
+            record_key.put(key);
+            Cow::Owned(record_key)
+        }
+    }
+}
+
+/// Splits the key stored in the data file into the bucket name and the key.
+pub(crate) fn split_record_key(flags: u8, record_key: &[u8]) -> anyhow::Result<(Option<&[u8]>, &[u8])> {
+    if flags & FLAG_BUCKET == 0 {
+        return Ok((None, record_key));
+    }
+
+    let name_end = match record_key.first() {
+        Some(size) if (*size as usize) < record_key.len() => 1 + *size as usize,
+        _ => bail!("invalid bucket key"),
+    };
+    Ok((Some(&record_key[1..name_end]), &record_key[name_end..]))
+}
+
+/// Size of a drop marker value: `[file_id u64|offset u32]`.
+pub(crate) const DROP_MARKER_SIZE: usize = 12;
+
+pub(crate) fn encode_drop_marker(file_id: u64, offset: u32) -> Vec<u8> {
+    let mut val = Vec::with_capacity(DROP_MARKER_SIZE);
+    val.put_u64(file_id);
+    val.put_u32(offset);
+    val
+}
+
+pub(crate) fn decode_drop_marker(val: &[u8]) -> anyhow::Result<(u64, u32)> {
+    if val.len() != DROP_MARKER_SIZE {
+        bail!("invalid bucket drop marker");
+    }
+    Ok((u64::from_be_bytes(val[..8].try_into()?), u32::from_be_bytes(val[8..].try_into()?)))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storage::log::FLAG_BUCKET;
+
+    use super::{record_key, split_record_key};
+
+    #[test]
+    fn it_should_split_record_keys() {
+        let key = record_key(Some(b"users"), b"k1");
+        assert_eq!(b"\x05usersk1".as_slice(), key.as_ref());
+        assert_eq!((Some(b"users".as_slice()), b"k1".as_slice()), split_record_key(FLAG_BUCKET, &key).unwrap());
+        assert_eq!((None, key.as_ref()), split_record_key(0, &key).unwrap());
+        assert!(split_record_key(FLAG_BUCKET, b"\x05use").is_err());
+    }
+}
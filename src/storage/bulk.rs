@@ -0,0 +1,291 @@
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use anyhow::Context;
+use log::info;
+use serde::Serialize;
+
+use crate::storage::{bucket, codec, Config, Header};
+use crate::storage::hint::{build_hint_file_name, HintWriter};
+use crate::storage::log::{FLAG_BUCKET, KEY_OFFSET, with_expiry};
+use crate::storage::log_writer::{check_entry_size, create_entry, next_file_id};
+use crate::storage::utils::{build_data_file_name, sync_dir, timestamp};
+
+pub(crate) const LOAD_MANIFEST: &str = "load.manifest";
+const WRITE_BUFFER_SIZE: usize = 4 << 20;
+
+/// Result of [crate::storage::Handle::bulk_load].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct BulkLoadReport {
+    /// Ids of the added data files.
+    pub files: Vec<u64>,
+    pub keys: u64,
+    pub bytes: u64,
+}
+
+/// A record written by [write_files].
+pub(crate) struct LoadRecord<K, V> {
+    pub bucket: Option<Vec<u8>>,
+    pub key: K,
+    pub val: V,
+    /// `0` if the key never expires.
+    pub expires_at: u64,
+    /// Time of the last write, `None` for the time of the load.
+    pub ts_tamp: Option<u32>,
+}
+
+impl<K, V> LoadRecord<K, V> {
+    pub fn new(key: K, val: V) -> Self {
+        Self { bucket: None, key, val, expires_at: 0, ts_tamp: None }
+    }
+}
+
+fn build_load_file_name(file_name: &str) -> String {
+    format!("{file_name}.load")
+}
+
+/// Data and hint file which are written by the loader.
+struct LoadFile {
+    file_id: u64,
+    data: BufWriter<fs::File>,
+    hints: HintWriter,
+    position: u64,
+}
+
+impl LoadFile {
+    fn create(dir: &Path, file_id: u64) -> anyhow::Result<Self> {
+        let data = fs::File::create(dir.join(build_load_file_name(&build_data_file_name(file_id))))?;
+        let hints = HintWriter::create(&dir.join(build_load_file_name(&build_hint_file_name(file_id))))?;
+        Ok(Self { file_id, data: BufWriter::with_capacity(WRITE_BUFFER_SIZE, data), hints, position: 0 })
+    }
+
+    fn write(&mut self, key: &[u8], val: &[u8], ts_tamp: u32, flags: u8, expires_at: u64) -> anyhow::Result<u64> {
+        let (flags, stored) = with_expiry(val, flags, expires_at);
+        check_entry_size(key.len(), stored.len())?;
+        let entry = create_entry(key, &stored, ts_tamp, flags);
+        let header = Header {
+            file_id: self.file_id,
+            val_size: val.len() as u32,
+            val_offset: u32::try_from(self.position + (KEY_OFFSET + key.len() + stored.len() - val.len()) as u64).context("data file is too large")?,
+            ts_tamp,
+            flags,
+            expires_at,
+        };
+        self.data.write_all(&entry)?;
+        self.hints.write(key, &header)?;
+        self.position += entry.len() as u64;
+        Ok(entry.len() as u64)
+    }
+
+    fn finish(mut self) -> anyhow::Result<()> {
+        self.data.flush()?;
+        self.data.get_ref().sync_all()?;
+        self.hints.finish()
+    }
+}
+
+/// Writes the records into data and hint files with ids greater than `last_file_id`. Like the ids of
+/// active files, they are not older than the records. Files have the `.load` suffix until they
+/// are registered, so the store does not read them. Written files are removed if a record is an error.
+pub(crate) fn write_files<K, V>(conf: &Config, last_file_id: u64, records: impl IntoIterator<Item=anyhow::Result<LoadRecord<K, V>>>) -> anyhow::Result<BulkLoadReport>
+    where K: AsRef<[u8]>, V: AsRef<[u8]> {
+    let mut report = BulkLoadReport::default();
+    let result = write_records(conf, last_file_id, records, &mut report);
+    if result.is_err() {
+        for file_id in &report.files {
+            let _ = fs::remove_file(conf.path.join(build_load_file_name(&build_data_file_name(*file_id))));
+            let _ = fs::remove_file(conf.path.join(build_load_file_name(&build_hint_file_name(*file_id))));
+        }
+    }
+    result.map(|_| report)
+}
+
+fn write_records<K, V>(conf: &Config, last_file_id: u64, records: impl IntoIterator<Item=anyhow::Result<LoadRecord<K, V>>>, report: &mut BulkLoadReport) -> anyhow::Result<()>
+    where K: AsRef<[u8]>, V: AsRef<[u8]> {
+    let now = timestamp();
+    let mut file: Option<LoadFile> = None;
+    for record in records {
+        let LoadRecord { bucket, key, val, expires_at, ts_tamp } = record?;
+        let record_key = bucket::record_key(bucket.as_deref(), key.as_ref());
+        check_entry_size(record_key.len(), val.as_ref().len())?;
+        let (flags, val) = codec::encode_value(conf, &record_key, val.as_ref())?;
+        let flags = if bucket.is_some() { flags | FLAG_BUCKET } else { flags };
+
+        let out = match &mut file {
+            Some(out) => out,
+            None => {
+                let file_id = next_file_id(report.files.last().copied().unwrap_or(last_file_id));
+                report.files.push(file_id);
+                file.insert(LoadFile::create(&conf.path, file_id)?)
+            }
+        };
+        // records are not newer than the file
+        report.bytes += out.write(&record_key, &val, ts_tamp.map_or(now, |ts| ts.min(now)), flags, expires_at)?;
+        report.keys += 1;
+
+        if out.position > conf.max_file_size as u64 {
+            file.take().unwrap().finish()?;
+        }
+    }
+
+    if let Some(out) = file {
+        out.finish()?;
+    }
+    Ok(())
+}
+
+/// Renames the written files, so they are read by the store. Removal of the manifest is the commit
+/// point, the files are removed by [recover_bulk_load] if the process crashes before it.
+pub(crate) fn register(dir: &Path, file_ids: &[u64]) -> anyhow::Result<()> {
+    let content: String = file_ids.iter().map(|id| format!("{id}\n")).collect();
+    let mut manifest = fs::File::create(dir.join(LOAD_MANIFEST))?;
+    manifest.write_all(content.as_bytes())?;
+    manifest.sync_all()?;
+    sync_dir(dir)?;
+
+    let result = file_ids.iter().try_for_each(|id| -> anyhow::Result<()> {
+        for name in [build_hint_file_name(*id), build_data_file_name(*id)] {
+            fs::rename(dir.join(build_load_file_name(&name)), dir.join(&name)).context("loaded file rename failed")?;
+        }
+        Ok(())
+    }).and_then(|_| sync_dir(dir));
+
+    match result {
+        Ok(_) => {
+            fs::remove_file(dir.join(LOAD_MANIFEST))?;
+            sync_dir(dir)
+        }
+        Err(e) => {
+            recover_bulk_load(dir)?;
+            Err(e)
+        }
+    }
+}
+
+/// Removes the files of a bulk load which is interrupted before it is registered. Must be called
+/// before the key directory is rebuilt.
+pub(crate) fn recover_bulk_load(dir: &Path) -> anyhow::Result<()> {
+    let manifest_path = dir.join(LOAD_MANIFEST);
+    if let Ok(content) = fs::read_to_string(&manifest_path) {
+        info!("rolling back interrupted bulk load");
+        for id in content.lines().map_while(|l| l.parse::<u64>().ok()) {
+            for name in [build_data_file_name(id), build_hint_file_name(id)] {
+                match fs::remove_file(dir.join(name)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e).context("loaded file removal failed"),
+                    _ => {}
+                }
+            }
+        }
+        sync_dir(dir)?;
+        fs::remove_file(&manifest_path)?;
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "load") {
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use tempdir::TempDir;
+
+    use crate::storage::{Config, Handle};
+    use crate::storage::inspect::verify;
+    use crate::storage::utils::build_data_file_name;
+
+    use super::{build_load_file_name, LOAD_MANIFEST, LoadRecord, write_files};
+
+    #[test]
+    fn it_should_load_pairs_and_keep_them_after_reopen() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            max_file_size: 256,
+            ..Default::default()
+        };
+        let mut handle = Handle::open(&conf).unwrap();
+        handle.put(b"key-1", b"old").unwrap();
+
+        // when
+        let pairs = (0..50).rev().map(|i| (format!("key-{i}"), format!("value-{i}"))).chain([("key-7".to_string(), "last".to_string())]);
+        let report = handle.bulk_load(pairs).unwrap();
+        handle.put(b"key-2", b"new").unwrap();
+
+        // then
+        assert_eq!(51, report.keys);
+        assert!(report.files.len() > 1);
+        assert_eq!(Some(b"value-1".to_vec()), handle.get(b"key-1").unwrap());
+        assert_eq!(Some(b"last".to_vec()), handle.get(b"key-7").unwrap());
+        assert_eq!(Some(b"new".to_vec()), handle.get(b"key-2").unwrap());
+        assert_eq!(50, handle.stats().unwrap().live_keys);
+        drop(handle);
+
+        assert!(verify(&conf.path).unwrap().is_ok());
+        let mut handle = Handle::open(&conf).unwrap();
+        assert_eq!(Some(b"value-1".to_vec()), handle.get(b"key-1").unwrap());
+        assert_eq!(Some(b"last".to_vec()), handle.get(b"key-7").unwrap());
+        assert_eq!(Some(b"new".to_vec()), handle.get(b"key-2").unwrap());
+
+        handle.merge().unwrap();
+        drop(handle);
+        assert!(verify(&conf.path).unwrap().is_ok());
+        let mut handle = Handle::open(&conf).unwrap();
+        assert_eq!(Some(b"value-49".to_vec()), handle.get(b"key-49").unwrap());
+        assert_eq!(Some(b"last".to_vec()), handle.get(b"key-7").unwrap());
+    }
+
+    #[test]
+    fn it_should_name_loaded_files_after_their_records() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            max_file_size: 64,
+            ..Default::default()
+        };
+        let mut handle = Handle::open(&conf).unwrap();
+        handle.put(b"k0", b"v0").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+
+        // when
+        let report = handle.bulk_load((1..10).map(|i| (format!("k{i}"), format!("v{i}")))).unwrap();
+        handle.put(b"k10", b"v10").unwrap();
+        drop(handle);
+
+        // then
+        assert!(report.files.len() > 1);
+        let verified = verify(&conf.path).unwrap();
+        assert!(verified.is_ok());
+        assert!(verified.files.iter().all(|file| file.warnings.is_empty()), "{verified:?}");
+    }
+
+    #[test]
+    fn it_should_roll_back_interrupted_load() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        let report = write_files(&conf, 0, [Ok(LoadRecord::new(b"k1", b"v1"))]).unwrap();
+        let name = build_data_file_name(report.files[0]);
+        fs::write(conf.path.join(LOAD_MANIFEST), format!("{}\n", report.files[0])).unwrap();
+        fs::rename(conf.path.join(build_load_file_name(&name)), conf.path.join(&name)).unwrap();
+
+        // when
+        let mut handle = Handle::open(&conf).unwrap();
+
+        // then
+        assert_eq!(None, handle.get(b"k1").unwrap());
+        // the id of the removed file may be taken by the new active file
+        assert!(fs::read(conf.path.join(&name)).unwrap_or_default().is_empty());
+        assert!(!conf.path.join(LOAD_MANIFEST).exists());
+        assert_eq!(1, fs::read_dir(&conf.path).unwrap().filter(|e| e.as_ref().unwrap().path().to_string_lossy().ends_with(".data")).count());
+    }
+}
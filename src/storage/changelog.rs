@@ -0,0 +1,272 @@
+use std::io;
+use std::os::unix::fs::MetadataExt;
+
+use crate::Result;
+use crate::storage::{bucket, codec, Config};
+use crate::storage::log::{LogEntry, LogIterator};
+use crate::storage::rebuild::{extract_data_file_ids, is_torn_tail};
+use crate::storage::utils::{build_data_file_name, open_file_for_read};
+
+/// Position of a record in the data files. Records are ordered by file id, then by offset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Cursor {
+    pub file_id: u64,
+    pub offset: u64,
+    /// Inode of the data file, `0` if it is not known. A merge output replaces the file with the
+    /// same id, then the offset is stale and the file is read again from the start.
+    pub ino: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Put,
+    Delete,
+    /// All keys of the bucket written before the record are deleted.
+    DropBucket,
+}
+
+/// A record of the changelog. `value` is decoded and empty unless `op` is [Op::Put].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeRecord {
+    /// Position of the record.
+    pub cursor: Cursor,
+    /// Position of the next record, reading can be resumed from it.
+    pub next: Cursor,
+    pub op: Op,
+    pub bucket: Option<Vec<u8>>,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    pub ts_tamp: u32,
+}
+
+/// Reads records of the data files in write order, so the store can be consumed as a change stream.
+///
+/// Iteration ends when the reader catches up with the writer. Calling `next` again returns the
+/// records written in the meantime. A merge rewrites the merged files; if the file of the cursor
+/// is merged away, reading continues from the next file, and if it is replaced by the merge output,
+/// from the start of the output. Either way the moved records are read again.
+pub struct Changelog {
+    conf: Config,
+    cursor: Cursor,
+    iter: Option<LogIterator>,
+}
+
+impl Changelog {
+    /// Opens the changelog of the store at `conf.path` from `cursor`. `Cursor::default()` reads from the start.
+    /// It does not lock the store, so it can be used next to the [crate::storage::Handle] writing to it.
+    pub fn open(conf: &Config, cursor: Cursor) -> Result<Self> {
+        Ok(Self { conf: conf.clone(), cursor, iter: None })
+    }
+
+    /// Position of the next record.
+    pub fn cursor(&self) -> Cursor {
+        self.cursor
+    }
+
+    fn next_record(&mut self) -> anyhow::Result<Option<ChangeRecord>> {
+        loop {
+            let files: Vec<u64> = extract_data_file_ids(&self.conf.path)?
+                .filter(|id| *id >= self.cursor.file_id)
+                .collect();
+            let Some(file_id) = files.first().copied() else {
+                return Ok(None);
+            };
+            let is_last = files.len() == 1;
+
+            if self.iter.is_none() {
+                if file_id != self.cursor.file_id {
+                    self.cursor = Cursor { file_id, offset: 0, ino: 0 };
+                }
+
+                let file = match open_file_for_read(&self.conf.path, &build_data_file_name(file_id)) {
+                    Ok(file) => file,
+                    // removed by a merge after the listing
+                    Err(e) if e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::NotFound) => continue,
+                    Err(e) => return Err(e),
+                };
+                let ino = file.metadata()?.ino();
+                if self.cursor.ino != ino {
+                    if self.cursor.ino != 0 {
+                        self.cursor.offset = 0;
+                    }
+                    self.cursor.ino = ino;
+                }
+                self.iter = Some(LogIterator::with_offset(file_id, file, self.cursor.offset)?);
+            }
+
+            match self.iter.as_mut().unwrap().next() {
+                Some(Ok(entry)) => return self.advance(entry).map(Some),
+                None if is_last => {
+                    self.iter = None;
+                    return Ok(None);
+                }
+                Some(Err(e)) => {
+                    self.iter = None;
+                    // the writer may be in the middle of a record at the end of the active file
+                    match e.downcast_ref() {
+                        Some(&crate::Error::Corrupt { offset, .. }) if is_last => {
+                            let mut file = open_file_for_read(&self.conf.path, &build_data_file_name(file_id))?;
+                            if is_torn_tail(&mut file, offset)? {
+                                return Ok(None);
+                            }
+                            return Err(e);
+                        }
+                        _ => return Err(e),
+                    }
+                }
+                None => {
+                    self.iter = None;
+                    self.cursor = Cursor { file_id: files[1], offset: 0, ino: 0 };
+                }
+            }
+        }
+    }
+
+    /// Moves the cursor after the entry and converts it to a record.
+    fn advance(&mut self, entry: LogEntry) -> anyhow::Result<ChangeRecord> {
+        let LogEntry { key: record_key, val, header } = entry;
+        let cursor = self.cursor;
+        let next = Cursor { file_id: header.file_id, offset: header.val_offset as u64 + header.val_size as u64, ino: cursor.ino };
+        self.cursor = next;
+
+        let (bucket, key) = bucket::split_record_key(header.flags, &record_key)?;
+        let (op, value) = if header.is_bucket_drop() {
+            (Op::DropBucket, vec![])
+        } else if header.is_tombstone() {
+            (Op::Delete, vec![])
+        } else {
+            (Op::Put, codec::decode_value(&self.conf, &record_key, header.flags, val)?)
+        };
+
+        Ok(ChangeRecord {
+            cursor,
+            next,
+            op,
+            bucket: bucket.map(<[u8]>::to_vec),
+            key: key.to_vec(),
+            value,
+            ts_tamp: header.ts_tamp,
+        })
+    }
+}
+
+impl Iterator for Changelog {
+    type Item = Result<ChangeRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().map_err(Into::into).transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use tempdir::TempDir;
+
+    use crate::storage::{Config, Handle};
+
+    use super::{Changelog, Cursor, Op};
+
+    #[test]
+    fn it_should_resume_from_cursor() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            max_file_size: 64,
+            compression: crate::storage::Compression::Lz4,
+            ..Default::default()
+        };
+        let mut handle = Handle::open(&conf).unwrap();
+        for i in 0..10 {
+            handle.put(format!("k{i}").as_bytes(), format!("val{i}").as_bytes()).unwrap();
+        }
+        handle.delete(b"k1").unwrap();
+        handle.bucket("b1").unwrap().put(b"k1", b"bucket").unwrap();
+
+        // when
+        let mut changelog = Changelog::open(&conf, Cursor::default()).unwrap();
+        let first: Vec<_> = changelog.by_ref().take(5).map(Result::unwrap).collect();
+        let cursor = changelog.cursor();
+        let rest: Vec<_> = Changelog::open(&conf, cursor).unwrap().map(Result::unwrap).collect();
+
+        // then
+        assert_eq!(b"k0".to_vec(), first[0].key);
+        assert_eq!(b"val0".to_vec(), first[0].value);
+        assert_eq!(first[4].next, cursor);
+        assert_eq!(b"k5".to_vec(), rest[0].key);
+        assert_eq!(7, rest.len());
+        assert!(rest.windows(2).all(|w| w[0].cursor < w[1].cursor));
+
+        let delete = &rest[5];
+        assert_eq!((Op::Delete, b"k1".to_vec()), (delete.op, delete.key.clone()));
+        let bucket_put = &rest[6];
+        assert_eq!((Some(b"b1".to_vec()), b"bucket".to_vec()), (bucket_put.bucket.clone(), bucket_put.value.clone()));
+
+        // then: new records are returned after catching up
+        assert!(changelog.by_ref().map(Result::unwrap).count() == 7);
+        handle.put(b"k10", b"val10").unwrap();
+        assert_eq!(b"k10".to_vec(), changelog.next().unwrap().unwrap().key);
+        assert!(changelog.next().is_none());
+    }
+
+    #[test]
+    fn it_should_read_merge_output_from_the_start() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        let mut handle = Handle::open(&conf).unwrap();
+        handle.put(b"k0", &[b'x'; 100]).unwrap();
+        for i in 1..5 {
+            handle.put(format!("k{i}").as_bytes(), b"v").unwrap();
+        }
+        let cursor = Changelog::open(&conf, Cursor::default()).unwrap().next().unwrap().unwrap().next;
+        drop(handle);
+
+        // when
+        let mut handle = Handle::open(&conf).unwrap();
+        handle.put(b"k0", b"new").unwrap();
+        let report = handle.merge().unwrap();
+        let rest: Vec<_> = Changelog::open(&conf, cursor).unwrap().map(Result::unwrap).collect();
+
+        // then
+        assert_eq!(cursor.file_id, report.file_id);
+        let keys: Vec<_> = rest.iter().map(|r| String::from_utf8(r.key.clone()).unwrap()).collect();
+        assert_eq!(vec!["k1", "k2", "k3", "k4", "k0"], keys);
+    }
+
+    #[test]
+    fn it_should_return_corruption_but_wait_for_incomplete_records() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        let mut handle = Handle::open(&conf).unwrap();
+        for i in 0..3 {
+            handle.put(format!("k{i}").as_bytes(), b"v").unwrap();
+        }
+        let records: Vec<_> = Changelog::open(&conf, Cursor::default()).unwrap().map(Result::unwrap).collect();
+        let path = conf.path.join(format!("{}.bitcask.data", records[0].cursor.file_id));
+
+        // when
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[1, 2, 3]).unwrap();
+
+        // then
+        assert_eq!(3, Changelog::open(&conf, Cursor::default()).unwrap().map(Result::unwrap).count());
+
+        // when
+        let mut content = std::fs::read(&path).unwrap();
+        content[records[1].cursor.offset as usize + 5] ^= 0xff;
+        std::fs::write(&path, content).unwrap();
+
+        // then
+        let mut changelog = Changelog::open(&conf, Cursor::default()).unwrap();
+        assert_eq!(b"k0".to_vec(), changelog.next().unwrap().unwrap().key);
+        assert!(matches!(changelog.next(), Some(Err(crate::Error::Corrupt { .. }))));
+    }
+}
@@ -0,0 +1,31 @@
+use anyhow::bail;
+
+use crate::storage::{compression, Config};
+use crate::storage::log::FLAG_ENCRYPTED;
+
+/// Converts a value to the bytes stored in the data file: compress first, then encrypt.
+/// Returns the record flags together with the stored bytes.
+pub(crate) fn encode_value(conf: &Config, key: &[u8], val: &[u8]) -> anyhow::Result<(u8, Vec<u8>)> {
+    let (mut flags, mut stored) = conf.compression.compress(val)?;
+
+    if let Some(enc) = &conf.encryption {
+        stored = enc.seal(key, &stored)?;
+        flags |= FLAG_ENCRYPTED;
+    }
+
+    Ok((flags, stored))
+}
+
+/// Reverses [encode_value] using the flags of the record.
+pub(crate) fn decode_value(conf: &Config, key: &[u8], flags: u8, stored: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let compressed = if flags & FLAG_ENCRYPTED != 0 {
+        match &conf.encryption {
+            Some(enc) => enc.open(key, &stored)?,
+            None => bail!("value is encrypted but no encryption key is configured"),
+        }
+    } else {
+        stored
+    };
+
+    compression::decompress(flags, compressed)
+}
@@ -0,0 +1,69 @@
+use anyhow::{bail, Context};
+
+use crate::storage::log::{FLAG_COMPRESSION_MASK, FLAG_LZ4, FLAG_ZSTD};
+
+/// Compression applied to values before they are written to the data file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    /// zstd with the given compression level
+    Zstd(i32),
+}
+
+impl Compression {
+    /// Compresses the value and returns the record flags with the bytes to store.
+    /// Values are stored raw if compression does not make them smaller.
+    pub(crate) fn compress(&self, val: &[u8]) -> anyhow::Result<(u8, Vec<u8>)> {
+        let (flags, compressed) = match self {
+            Compression::None => return Ok((0, val.to_vec())),
+            Compression::Lz4 => (FLAG_LZ4, lz4_flex::compress_prepend_size(val)),
+            Compression::Zstd(level) => (FLAG_ZSTD, zstd::bulk::compress(val, *level).context("zstd compression failed")?),
+        };
+
+        if compressed.len() >= val.len() {
+            return Ok((0, val.to_vec()));
+        }
+
+        Ok((flags, compressed))
+    }
+}
+
+/// Restores the original value using the compression flags of the record.
+pub(crate) fn decompress(flags: u8, buf: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    match flags & FLAG_COMPRESSION_MASK {
+        0 => Ok(buf),
+        FLAG_LZ4 => lz4_flex::decompress_size_prepended(&buf).context("lz4 decompression failed"),
+        FLAG_ZSTD => zstd::stream::decode_all(buf.as_slice()).context("zstd decompression failed"),
+        other => bail!("unknown compression flag: {other}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Compression, decompress};
+
+    #[test]
+    fn it_should_roundtrip_compressed_values() {
+        let val = br#"{"name":"fakir","tags":["kv","kv","kv","kv","kv","kv","kv","kv"]}"#.repeat(10);
+
+        for compression in [Compression::None, Compression::Lz4, Compression::Zstd(3)] {
+            let (flags, stored) = compression.compress(&val).unwrap();
+            if compression != Compression::None {
+                assert_ne!(0, flags);
+                assert!(stored.len() < val.len());
+            }
+
+            assert_eq!(val, decompress(flags, stored).unwrap());
+        }
+    }
+
+    #[test]
+    fn it_should_store_incompressible_values_raw() {
+        let (flags, stored) = Compression::Lz4.compress(b"abc").unwrap();
+
+        assert_eq!(0, flags);
+        assert_eq!(b"abc".to_vec(), stored);
+    }
+}
@@ -1,11 +1,41 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
 
+use crate::storage::{Compression, Encryption, MergePolicy};
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub path: PathBuf,
     pub expiry_secs: u32,
+    /// Time to live of the keys in the named buckets. Buckets which are not listed use `expiry_secs`.
+    pub bucket_expiry_secs: HashMap<String, u32>,
     pub sync_on_put: bool,
     pub max_file_size: u32,
+    /// Size of the userspace write buffer in bytes. `0` disables buffering and every
+    /// record is written to the active file immediately.
+    pub write_buffer_size: usize,
+    /// Compression used for new values. Existing records keep the compression they are written with.
+    pub compression: Compression,
+    /// Encrypts values at rest when set. Only values are encrypted: keys and bucket names stay in
+    /// plaintext in data files, hint files, exports and dumps, because the key directory is
+    /// rebuilt and scanned in key order from them. Keys are authenticated with their value, but
+    /// must not contain secrets.
+    pub encryption: Option<Encryption>,
+    /// Starts a background merger which compacts files selected by the policy.
+    pub merge_policy: Option<MergePolicy>,
+    /// Bytes per second limit of maintenance I/O like merge reads and writes. `0` means unlimited.
+    /// It can be changed at runtime with [crate::storage::Handle::set_maintenance_rate_limit].
+    pub maintenance_rate_limit: u64,
+}
+
+impl Config {
+    pub(crate) fn expiry_secs_of(&self, bucket: Option<&[u8]>) -> u32 {
+        bucket
+            .and_then(|name| std::str::from_utf8(name).ok())
+            .and_then(|name| self.bucket_expiry_secs.get(name))
+            .copied()
+            .unwrap_or(self.expiry_secs)
+    }
 }
 
 impl Default for Config {
@@ -13,8 +43,14 @@ impl Default for Config {
         Self {
             path: PathBuf::new(),
             expiry_secs: 0,
+            bucket_expiry_secs: HashMap::new(),
             sync_on_put: false,
             max_file_size: 1 << 20, // 1MB
+            write_buffer_size: 0,
+            compression: Compression::None,
+            encryption: None,
+            merge_policy: None,
+            maintenance_rate_limit: 0,
         }
     }
 }
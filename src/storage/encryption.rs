@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+
+use anyhow::{anyhow, bail};
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use chacha20poly1305::aead::{Aead, AeadCore, OsRng, Payload};
+
+pub const ENCRYPTION_KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+
+// sealed value: [key_id|nonce|ciphertext+tag]
+const KEY_ID_SIZE: usize = 1;
+const SEALED_HEADER_SIZE: usize = KEY_ID_SIZE + NONCE_SIZE;
+
+/// Keys used for authenticated encryption(ChaCha20-Poly1305) of values.
+///
+/// New values are sealed with the current key. Retired keys are only used to open values
+/// written before a key rotation, merge rewrites them with the current key.
+///
+/// Keys of the store are not encrypted, see [crate::storage::Config::encryption].
+#[derive(Clone)]
+pub struct Encryption {
+    key_id: u8,
+    key: [u8; ENCRYPTION_KEY_SIZE],
+    retired_keys: HashMap<u8, [u8; ENCRYPTION_KEY_SIZE]>,
+}
+
+impl Encryption {
+    pub fn new(key_id: u8, key: [u8; ENCRYPTION_KEY_SIZE]) -> Self {
+        Self { key_id, key, retired_keys: HashMap::new() }
+    }
+
+    pub fn with_retired_key(mut self, key_id: u8, key: [u8; ENCRYPTION_KEY_SIZE]) -> Self {
+        self.retired_keys.insert(key_id, key);
+        self
+    }
+
+    pub fn key_id(&self) -> u8 {
+        self.key_id
+    }
+
+    /// Encrypts the value. Record key is authenticated with the value, so a sealed value
+    /// cannot be moved to another key.
+    pub(crate) fn seal(&self, key: &[u8], val: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&self.key));
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher.encrypt(&nonce, Payload { msg: val, aad: key })
+            .map_err(|_| anyhow!("value encryption failed"))?;
+
+        let mut sealed = Vec::with_capacity(SEALED_HEADER_SIZE + ciphertext.len());
+        sealed.push(self.key_id);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+
+        Ok(sealed)
+    }
+
+    pub(crate) fn open(&self, key: &[u8], sealed: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if sealed.len() < SEALED_HEADER_SIZE {
+            bail!("sealed value is too short");
+        }
+
+        let key_id = sealed[0];
+        let secret = if key_id == self.key_id {
+            &self.key
+        } else {
+            self.retired_keys.get(&key_id).ok_or_else(|| anyhow!("no encryption key with id {key_id}"))?
+        };
+
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(secret));
+        let nonce = Nonce::from_slice(&sealed[KEY_ID_SIZE..SEALED_HEADER_SIZE]);
+        cipher.decrypt(nonce, Payload { msg: &sealed[SEALED_HEADER_SIZE..], aad: key })
+            .map_err(|_| anyhow!("value decryption failed, wrong key or corrupted value"))
+    }
+
+    /// Returns true if the value is sealed with a retired key.
+    pub(crate) fn needs_rotation(&self, sealed: &[u8]) -> bool {
+        sealed.first().is_some_and(|key_id| *key_id != self.key_id)
+    }
+}
+
+impl Debug for Encryption {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut retired: Vec<&u8> = self.retired_keys.keys().collect();
+        retired.sort();
+        write!(f, "Encryption<key_id={}, retired={:?}>", self.key_id, retired)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Encryption;
+
+    #[test]
+    fn it_should_seal_and_open() {
+        let enc = Encryption::new(1, [7; 32]);
+
+        let sealed = enc.seal(b"key", b"secret").unwrap();
+
+        assert!(!sealed.windows(6).any(|w| w == b"secret"));
+        assert_eq!(b"secret".to_vec(), enc.open(b"key", &sealed).unwrap());
+        assert!(enc.open(b"other-key", &sealed).is_err());
+    }
+
+    #[test]
+    fn it_should_open_with_retired_key() {
+        let old = Encryption::new(1, [1; 32]);
+        let sealed = old.seal(b"key", b"secret").unwrap();
+
+        let rotated = Encryption::new(2, [2; 32]).with_retired_key(1, [1; 32]);
+
+        assert!(rotated.needs_rotation(&sealed));
+        assert_eq!(b"secret".to_vec(), rotated.open(b"key", &sealed).unwrap());
+        assert!(Encryption::new(2, [2; 32]).open(b"key", &sealed).is_err());
+    }
+}
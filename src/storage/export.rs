@@ -0,0 +1,65 @@
+// A JSON object per line: {"bucket": "name", "key": base64, "value": base64, "expires_at": ms, "ts": secs}
+// `bucket` is omitted for the default keyspace, `expires_at` and `ts` are optional.
+
+use std::io::Write;
+
+use anyhow::Context;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::{Deserialize, Serialize};
+
+/// Fields written by [crate::storage::Handle::export] in addition to keys and values.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExportOptions {
+    /// Writes the expiry time of the keys which have their own time to live.
+    pub ttl: bool,
+    /// Writes the time of the last write in seconds since epoch, which is restored by import.
+    pub timestamps: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Line {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bucket: Option<String>,
+    key: String,
+    value: String,
+    /// Milliseconds since epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ts: Option<u32>,
+}
+
+/// A key read by [parse_line].
+pub(crate) struct Entry {
+    pub bucket: Option<String>,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    /// `0` if the key never expires.
+    pub expires_at: u64,
+    pub ts_tamp: Option<u32>,
+}
+
+pub(crate) fn write_line(out: &mut impl Write, bucket: Option<&[u8]>, key: &[u8], value: &[u8], expires_at: u64, ts_tamp: u32, options: &ExportOptions) -> anyhow::Result<()> {
+    let line = Line {
+        bucket: bucket.map(|name| String::from_utf8_lossy(name).to_string()),
+        key: STANDARD.encode(key),
+        value: STANDARD.encode(value),
+        expires_at: Some(expires_at).filter(|at| options.ttl && *at > 0),
+        ts: Some(ts_tamp).filter(|_| options.timestamps),
+    };
+    serde_json::to_writer(&mut *out, &line)?;
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
+pub(crate) fn parse_line(line: &str) -> anyhow::Result<Entry> {
+    let line: Line = serde_json::from_str(line)?;
+    Ok(Entry {
+        bucket: line.bucket,
+        key: STANDARD.decode(line.key).context("key is not base64")?,
+        value: STANDARD.decode(line.value).context("value is not base64")?,
+        expires_at: line.expires_at.unwrap_or_default(),
+        ts_tamp: line.ts,
+    })
+}
@@ -1,20 +1,37 @@
 use std::{fs, process};
 use std::fs::OpenOptions;
 use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::{Path};
+use std::path::{Path, PathBuf};
 
-use anyhow::{bail, Context};
+use anyhow::Context;
 use fs2::FileExt;
 use log::debug;
 
-pub(crate) fn try_lock_db<P>(dir: P) -> anyhow::Result<()> where P: AsRef<Path> {
+use crate::Error;
+
+/// Keeps the data directory locked until dropped.
+pub(crate) struct FileLock {
+    file: fs::File,
+    path: PathBuf,
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+        let _ = self.file.unlock();
+    }
+}
+
+pub(crate) fn try_lock_db<P>(dir: P) -> anyhow::Result<FileLock> where P: AsRef<Path> {
     let pid_file_path = dir.as_ref().join("bitcask.write.lock");
 
     if let Ok(mut f) = OpenOptions::new()
         .create_new(true)// return err if file already exists
         .write(true)
         .open(&pid_file_path) {
-        return write_pid(&mut f);
+        f.try_lock_exclusive().map_err(|_| Error::Locked("lock file is locked".to_string()))?;
+        write_pid(&mut f)?;
+        return Ok(FileLock { file: f, path: pid_file_path });
     }
 
     debug!("lock file exist.");
@@ -24,26 +41,28 @@ pub(crate) fn try_lock_db<P>(dir: P) -> anyhow::Result<()> where P: AsRef<Path>
         .write(true)
         .open(&pid_file_path)?;
 
-    pid_file.try_lock_exclusive().context("process already running")?;
+    pid_file.try_lock_exclusive().map_err(|_| Error::Locked("lock file is locked".to_string()))?;
 
     let mut pid = String::new();
     pid_file.read_to_string(&mut pid)?;
 
     if pid.is_empty() {
-        bail!(format!("cannot read PID from lock file({}). You can remove lock file after ensure server is not running.", pid_file_path.clone().display()));
+        return Err(Error::Locked(format!("cannot read PID from lock file({}). You can remove lock file after ensure server is not running.", pid_file_path.clone().display())).into());
     }
 
     unsafe {
         if libc::kill(pid.parse()?, 0) == 0 {
-            bail!("process already running");
+            return Err(Error::Locked(format!("process {pid} is running")).into());
         }
     }
 
-    write_pid(&mut pid_file)
+    write_pid(&mut pid_file)?;
+    Ok(FileLock { file: pid_file, path: pid_file_path })
 }
 
 
 fn write_pid(file: &mut fs::File) -> anyhow::Result<()> {
+    file.set_len(0)?;
     file.seek(SeekFrom::Start(0)).context("lock file seek failed")?;
     let pid = process::id().to_string();
     file.write_all(pid.as_bytes())?;
@@ -1,72 +1,204 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::fs;
-use std::sync::{Arc, RwLock};
+use std::io::{BufRead, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, mpsc, RwLock};
+use std::thread;
+use std::time::Duration;
 
-use anyhow::Context;
+use anyhow::{anyhow, Context};
 
-use crate::storage::{file_lock, KeyDir, utils};
+use crate::{Error, Result};
+use crate::storage::{backup, BackupReport, BitcaskImportReport, Bucket, bucket, BucketStats, bulk, BulkLoadReport, codec, export, ExportOptions, file_lock, Header, KeyDir, MergeReport, repair, RepairReport, riak, Stats, utils, Watcher};
+use crate::storage::bulk::{LoadRecord, recover_bulk_load};
 use crate::storage::config::Config;
-use crate::storage::log_reader::LogReader;
-use crate::storage::log_writer::LogWriter;
-use crate::storage::rebuild::rebuild_storage;
+use crate::storage::file_lock::FileLock;
+use crate::storage::hint::read_hints;
+use crate::storage::log_reader::{LogReader, ValueReader};
+use crate::storage::log_writer::{check_entry_size, LogWriter};
+use crate::storage::merge::{Merger, recover_merge, run_background_merger};
+use crate::storage::rate_limit::RateLimiter;
+use crate::storage::rebuild::{apply_record, extract_data_file_ids, rebuild_storage, truncate_torn_tail};
+use crate::storage::stats::FileStatsMap;
 
 pub struct Handle<'a> {
     conf: &'a Config,
     writer: LogWriter<'a>,
     key_dir: Arc<RwLock<KeyDir>>,
+    file_stats: Arc<RwLock<FileStatsMap>>,
     /**
     We use RefCell because we update readers if read ops come for a key that stay in a different file after startup
      */
     readers: RefCell<HashMap<u64, LogReader>>,
+    /// Merge epoch of the cached readers.
+    readers_epoch: Cell<u64>,
+    merger: Arc<Merger>,
+    background_merger: Option<(mpsc::Sender<()>, thread::JoinHandle<()>)>,
+    rate_limiter: Arc<RateLimiter>,
+    closed: bool,
+    // declared last, so the directory is unlocked after the writer is dropped
+    _lock: FileLock,
 }
 
 impl<'a> Handle<'a> {
-    pub fn open(conf: &'a Config) -> anyhow::Result<Handle<'a>> {
-        // TODO: rebuild storage
-        let key_dir = Arc::new(RwLock::new(rebuild_storage(&conf.path)?));
-        // let key_dir = Arc::new(RwLock::new(Default::default()));
-
+    pub fn open(conf: &'a Config) -> Result<Handle<'a>> {
         fs::create_dir_all(&conf.path).context("data directory creation failed")?;
-        file_lock::try_lock_db(&conf.path)?;
+        let lock = file_lock::try_lock_db(&conf.path)?;
+        recover_merge(&conf.path)?;
+        recover_bulk_load(&conf.path)?;
+        truncate_torn_tail(&conf.path)?;
 
-        let writer = LogWriter::new(conf, key_dir.clone()).unwrap();
+        let (key_dir, file_stats) = rebuild_storage(&conf.path)?;
+        let key_dir = Arc::new(RwLock::new(key_dir));
+        let file_stats = Arc::new(RwLock::new(file_stats));
+        let writer = LogWriter::new(conf, key_dir.clone(), file_stats.clone())?;
 
         let mut readers = HashMap::new();
         readers.insert(writer.file_id(), LogReader::new(&conf.path, writer.file_id())?);
 
+        let rate_limiter = Arc::new(RateLimiter::new(conf.maintenance_rate_limit));
+        let merger = Arc::new(Merger::new(conf.clone(), key_dir.clone(), file_stats.clone(), writer.active_file_id(), rate_limiter.clone(), writer.watchers()));
+        let background_merger = conf.merge_policy.clone().map(|policy| {
+            let (stop, stopped) = mpsc::channel();
+            let merger = merger.clone();
+            (stop, thread::spawn(move || run_background_merger(merger, policy, stopped)))
+        });
+
         Ok(Handle {
             key_dir,
+            file_stats,
             writer,
             conf,
             readers: RefCell::new(readers),
+            readers_epoch: Cell::new(merger.epoch()),
+            merger,
+            background_merger,
+            rate_limiter,
+            closed: false,
+            _lock: lock,
         })
     }
 
-    pub fn get(&mut self, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
-        let expired = {
-            let bind = self.key_dir.read().unwrap();
-            let header = bind.get(key);
-            match header {
-                None => { return Ok(None); }
-                Some(header) => {
-                    if header.ts_tamp > utils::expiry_time(self.conf.expiry_secs) {
-                        return Ok(Some(self.read(header.file_id, header.val_offset, header.val_size)?));
-                    }
-                    true
-                }
+    /// Rewrites the data files which have corrupt records with their readable records, and moves the
+    /// originals to a quarantine directory. The store must not be open.
+    pub fn repair(path: &Path) -> Result<RepairReport> {
+        let _lock = file_lock::try_lock_db(path)?;
+        recover_merge(path)?;
+        recover_bulk_load(path)?;
+        Ok(repair::repair_data_files(path)?)
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_in(None, key)
+    }
+
+    pub(crate) fn get_in(&mut self, bucket: Option<&[u8]>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.ensure_open()?;
+        let val = self.with_live_header(bucket, key, |handle, header| {
+            handle.read(header.file_id, header.val_offset, header.val_size).map(|val| (header.flags, val))
+        })?;
+
+        match val {
+            None => Ok(None),
+            Some((flags, val)) => Ok(Some(codec::decode_value(self.conf, &bucket::record_key(bucket, key), flags, val)?)),
+        }
+    }
+
+    /// Returns a reader over the value, so large values can be consumed without loading them into memory.
+    pub fn get_reader(&mut self, key: &[u8]) -> Result<Option<ValueReader>> {
+        self.ensure_open()?;
+        Ok(self.with_live_header(None, key, |handle, header| {
+            if header.is_encoded() {
+                let val = handle.read(header.file_id, header.val_offset, header.val_size)?;
+                return Ok(ValueReader::from_memory(codec::decode_value(handle.conf, key, header.flags, val)?));
             }
-        };
 
-        if expired {
-            self.writer.delete(key)?;
-            return Ok(None);
+            if let Some(val) = handle.writer.read_buffered(header.file_id, header.val_offset, header.val_size) {
+                return Ok(ValueReader::from_memory(val));
+            }
+
+            // reader keeps its own file handle, so it stays valid if the file is merged later
+            ValueReader::from_file(&handle.conf.path, header.file_id, header.val_offset, header.val_size)
+        })?)
+    }
+
+    /// Reads `len` bytes of the value starting from `offset`. Returned slice is shorter
+    /// if the value ends before `offset + len`.
+    pub fn get_range(&mut self, key: &[u8], offset: u32, len: u32) -> Result<Option<Vec<u8>>> {
+        self.ensure_open()?;
+        Ok(self.with_live_header(None, key, |handle, header| {
+            if header.is_encoded() {
+                // encoded values can only be read as a whole
+                let val = handle.read(header.file_id, header.val_offset, header.val_size)?;
+                let val = codec::decode_value(handle.conf, key, header.flags, val)?;
+                let start = (offset as usize).min(val.len());
+                let end = (offset as usize).saturating_add(len as usize).min(val.len());
+                return Ok(val[start..end].to_vec());
+            }
+
+            let start = offset.min(header.val_size);
+            let end = offset.saturating_add(len).min(header.val_size);
+            handle.read(header.file_id, header.val_offset + start, end - start)
+        })?)
+    }
+
+    pub fn contains_key(&mut self, key: &[u8]) -> Result<bool> {
+        self.contains_key_in(None, key)
+    }
+
+    pub(crate) fn contains_key_in(&mut self, bucket: Option<&[u8]>, key: &[u8]) -> Result<bool> {
+        self.ensure_open()?;
+        Ok(self.with_live_header(bucket, key, |_, _| Ok(()))?.is_some())
+    }
+
+    /// Returns up to `limit` live keys which start with `prefix`, in key order. The last key of a page is
+    /// passed as `after` to read the next page, a page shorter than `limit` is the last one.
+    pub fn scan(&self, prefix: &[u8], after: Option<&[u8]>, limit: usize) -> Result<Vec<Vec<u8>>> {
+        self.ensure_open()?;
+        let key_dir = self.key_dir.read().unwrap();
+        let expiry_time = utils::expiry_time(self.conf.expiry_secs_of(None));
+        let now = utils::now_millis();
+        Ok(key_dir.scan(None, prefix, after)
+            .filter(|(_, header)| !header.is_expired(expiry_time, now))
+            .take(limit)
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    /// Calls `f` with the header of the key while the key directory is locked, so a merge
+    /// cannot move the record before it is read. Expired keys are deleted.
+    fn with_live_header<T>(&mut self, bucket: Option<&[u8]>, key: &[u8], f: impl FnOnce(&Self, &Header) -> anyhow::Result<T>) -> anyhow::Result<Option<T>> {
+        {
+            let key_dir = self.key_dir.read().unwrap();
+            match key_dir.get(bucket, key) {
+                None => return Ok(None),
+                Some(header) if !header.is_expired(utils::expiry_time(self.conf.expiry_secs_of(bucket)), utils::now_millis()) => {
+                    self.refresh_readers();
+                    return f(self, header).map(Some);
+                }
+                Some(_) => {}
+            }
         }
 
-        unreachable!("handle.get should always return")
+        self.writer.expire(bucket, key)?;
+        Ok(None)
+    }
+
+    /// Drops cached file handles if files are replaced by a merge.
+    fn refresh_readers(&self) {
+        let epoch = self.merger.epoch();
+        if self.readers_epoch.get() != epoch {
+            self.readers.borrow_mut().clear();
+            self.readers_epoch.set(epoch);
+        }
     }
 
     fn read(&self, file_id: u64, offset: u32, size: u32) -> anyhow::Result<Vec<u8>> {
+        if let Some(buf) = self.writer.read_buffered(file_id, offset, size) {
+            return Ok(buf);
+        }
+
         let mut readers = self.readers.borrow_mut();
         if readers.get(&file_id).is_none() {
             readers.insert(file_id, LogReader::new(&self.conf.path, file_id)?);
@@ -76,12 +208,927 @@ impl<'a> Handle<'a> {
         reader.read(offset, size)
     }
 
-    pub fn put(&mut self, key: &[u8], val: &[u8]) -> anyhow::Result<()> {
-        self.writer.put(key, val)
+    pub fn put(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
+        self.put_in(None, key, val)
+    }
+
+    pub(crate) fn put_in(&mut self, bucket: Option<&[u8]>, key: &[u8], val: &[u8]) -> Result<()> {
+        self.ensure_open()?;
+        Ok(self.writer.put(bucket, key, val)?)
+    }
+
+    /// Writes the value which expires after `ttl`, independently of [Config::expiry_secs].
+    pub fn put_with_ttl(&mut self, key: &[u8], val: &[u8], ttl: Duration) -> Result<()> {
+        self.put_with_ttl_in(None, key, val, ttl)
+    }
+
+    pub(crate) fn put_with_ttl_in(&mut self, bucket: Option<&[u8]>, key: &[u8], val: &[u8], ttl: Duration) -> Result<()> {
+        self.ensure_open()?;
+        Ok(self.writer.put_with_expiry(bucket, key, val, expires_at(ttl))?)
+    }
+
+    /// Returns the remaining time to live of the key. `None` if the key does not exist or never expires.
+    /// Returns [Error::Expired] if the time to live of the key is passed, the key is deleted then.
+    pub fn ttl(&mut self, key: &[u8]) -> Result<Option<Duration>> {
+        self.ttl_in(None, key)
+    }
+
+    pub(crate) fn ttl_in(&mut self, bucket: Option<&[u8]>, key: &[u8]) -> Result<Option<Duration>> {
+        self.ensure_open()?;
+        let exists = self.key_dir.read().unwrap().get(bucket, key).is_some();
+        let expiry_secs = self.conf.expiry_secs_of(bucket);
+        let ttl = self.with_live_header(bucket, key, |_, header| Ok(header.ttl(expiry_secs, utils::now_millis())))?;
+        match ttl {
+            Some(ttl) => Ok(ttl),
+            None if exists => Err(Error::Expired),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets the time to live of an existing key by writing its value again. Returns false if the key does not exist.
+    pub fn expire(&mut self, key: &[u8], ttl: Duration) -> Result<bool> {
+        match self.get(key)? {
+            None => Ok(false),
+            Some(val) => self.put_with_ttl(key, &val, ttl).map(|_| true),
+        }
+    }
+
+    /// Stores a value of `len` bytes read from the reader, without loading it into memory.
+    /// Streamed values are not compressed, and cannot be encrypted: it fails if [Config::encryption] is set.
+    pub fn put_from_reader(&mut self, key: &[u8], mut reader: impl Read, len: u32) -> Result<()> {
+        self.ensure_open()?;
+        Ok(self.writer.put_from_reader(None, key, &mut reader, len)?)
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.delete_in(None, key)
+    }
+
+    pub(crate) fn delete_in(&mut self, bucket: Option<&[u8]>, key: &[u8]) -> Result<()> {
+        self.ensure_open()?;
+        Ok(self.writer.delete(bucket, key)?)
+    }
+
+    /// Returns changes of the keys which start with `prefix`. Changes are delivered once the record is
+    /// synced to disk: after each write with `sync_on_put`, otherwise on [Handle::flush] or file rotation.
+    pub fn watch(&self, prefix: &[u8]) -> Result<Watcher> {
+        self.watch_in(None, prefix)
+    }
+
+    pub(crate) fn watch_in(&self, bucket: Option<&[u8]>, prefix: &[u8]) -> Result<Watcher> {
+        self.ensure_open()?;
+        Ok(self.writer.watchers().subscribe(bucket, prefix))
+    }
+
+    /// Returns the named bucket. It is created if it does not exist.
+    pub fn bucket(&mut self, name: &str) -> Result<Bucket<'_, 'a>> {
+        self.ensure_open()?;
+        bucket::check_bucket_name(name)?;
+        self.key_dir.write().unwrap().create_bucket(name.as_bytes());
+        Ok(Bucket::new(self, name.as_bytes().to_vec()))
+    }
+
+    /// Returns the names of the buckets. Buckets without keys are not kept after the store is reopened.
+    pub fn buckets(&self) -> Result<Vec<String>> {
+        self.ensure_open()?;
+        let mut names: Vec<String> = self.key_dir.read().unwrap().bucket_names()
+            .map(|name| String::from_utf8_lossy(name).to_string())
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    /// Removes all keys of the bucket by writing a single marker record. Space is reclaimed by merge.
+    pub fn drop_bucket(&mut self, name: &str) -> Result<()> {
+        self.ensure_open()?;
+        bucket::check_bucket_name(name)?;
+        Ok(self.writer.drop_bucket(name.as_bytes())?)
     }
 
-    pub fn delete(&mut self, key: &[u8]) -> anyhow::Result<()> {
-        self.writer.delete(key)
+    /// Returns the counters of the bucket, or of the default keyspace if `bucket` is `None`.
+    pub(crate) fn keyspace_stats(&self, bucket: Option<&[u8]>) -> Result<BucketStats> {
+        self.ensure_open()?;
+        let key_dir = self.key_dir.read().unwrap();
+        let expiry_time = utils::expiry_time(self.conf.expiry_secs_of(bucket));
+        Ok(key_dir.keys(bucket).map(|keys| BucketStats::new(bucket, keys, expiry_time)).unwrap_or_default())
+    }
+
+    /// Returns key and data file statistics of the store.
+    pub fn stats(&self) -> Result<Stats> {
+        self.ensure_open()?;
+        let key_dir = self.key_dir.read().unwrap();
+        let file_stats = self.file_stats.read().unwrap();
+        Ok(Stats::new(&key_dir, &file_stats, self.conf))
+    }
+
+    /// Compacts all data files except the active one on the caller thread.
+    pub fn merge(&mut self) -> Result<MergeReport> {
+        self.ensure_open()?;
+        Ok(self.merger.merge_all()?)
+    }
+
+    /// Copies the data files into `dir`, so it can be opened as a store. Writes of the handle and merges
+    /// wait until the copy is done. Copy is limited by the maintenance rate limit.
+    pub fn backup(&mut self, dir: &Path) -> Result<BackupReport> {
+        self.ensure_open()?;
+        let _paused = self.merger.pause();
+        self.writer.flush()?;
+        Ok(backup::copy_data_files(&self.conf.path, dir, &self.rate_limiter)?)
+    }
+
+    /// Calls `f` with the ids of the data files while merges are paused, so the files are not
+    /// replaced or removed. Buffered writes are flushed first.
+    pub(crate) fn with_data_files<T>(&mut self, f: impl FnOnce(&Path, Vec<u64>) -> anyhow::Result<T>) -> Result<T> {
+        self.ensure_open()?;
+        let _paused = self.merger.pause();
+        self.writer.flush()?;
+        let file_ids = extract_data_file_ids(&self.conf.path)?.collect();
+        Ok(f(&self.conf.path, file_ids)?)
+    }
+
+    /// Writes the pairs into new data and hint files with large sequential writes, then adds the files
+    /// to the store at once: either all pairs are visible or none. Later pairs of the same key win.
+    /// Watchers are not notified about the loaded keys.
+    pub fn bulk_load<K, V>(&mut self, pairs: impl IntoIterator<Item=(K, V)>) -> Result<BulkLoadReport>
+        where K: AsRef<[u8]>, V: AsRef<[u8]> {
+        self.load(pairs.into_iter().map(|(key, val)| Ok(LoadRecord::new(key, val))))
+    }
+
+    fn load<K, V>(&mut self, records: impl IntoIterator<Item=anyhow::Result<LoadRecord<K, V>>>) -> Result<BulkLoadReport>
+        where K: AsRef<[u8]>, V: AsRef<[u8]> {
+        self.ensure_open()?;
+        let _paused = self.merger.pause();
+        self.writer.flush()?;
+        let report = bulk::write_files(self.conf, self.writer.file_id(), records)?;
+        let Some(last_file_id) = report.files.last().copied() else {
+            return Ok(report);
+        };
+
+        // next writes must be newer than the loaded records
+        self.writer.rotate_after(last_file_id)?;
+        bulk::register(&self.conf.path, &report.files)?;
+
+        let mut key_dir = self.key_dir.write().unwrap();
+        let mut file_stats = self.file_stats.write().unwrap();
+        for file_id in &report.files {
+            let hints = read_hints(&self.conf.path, *file_id)?.context("loaded hint file cannot be read")?;
+            for (key, header) in hints {
+                apply_record(&mut key_dir, &mut file_stats, &key, &[], header)?;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Writes the live keys of all keyspaces as JSON Lines, keys and values are base64 encoded.
+    /// Returns the number of written keys.
+    pub fn export(&mut self, mut out: impl Write, options: &ExportOptions) -> Result<u64> {
+        self.ensure_open()?;
+        let keys: Vec<(Option<Vec<u8>>, Vec<u8>)> = self.key_dir.read().unwrap().keyspaces()
+            .flat_map(|(bucket, keys)| keys.keys().map(move |key| (bucket.map(<[u8]>::to_vec), key.clone())))
+            .collect();
+
+        let mut count = 0;
+        for (bucket, key) in keys {
+            let record_key = bucket::record_key(bucket.as_deref(), &key);
+            let entry = self.with_live_header(bucket.as_deref(), &key, |handle, header| {
+                let val = handle.read(header.file_id, header.val_offset, header.val_size)?;
+                Ok((codec::decode_value(handle.conf, &record_key, header.flags, val)?, header.expires_at, header.ts_tamp))
+            })?;
+            // deleted or expired after the keys are listed
+            let Some((val, expires_at, ts_tamp)) = entry else {
+                continue;
+            };
+            export::write_line(&mut out, bucket.as_deref(), &key, &val, expires_at, ts_tamp, options)?;
+            count += 1;
+        }
+        out.flush()?;
+        Ok(count)
+    }
+
+    /// Writes the keys of JSON Lines written by [Handle::export] like [Handle::bulk_load], so either
+    /// all keys are imported or none if a line is invalid. Keys keep their expiry time and the time
+    /// of the last write, the ones which are already expired are skipped. Returns the number of
+    /// written keys.
+    pub fn import(&mut self, input: impl BufRead) -> Result<u64> {
+        let now = utils::now_millis();
+        let records = input.lines().enumerate()
+            .map(|(i, line)| -> anyhow::Result<Option<LoadRecord<Vec<u8>, Vec<u8>>>> {
+                let line = line?;
+                if line.trim().is_empty() {
+                    return Ok(None);
+                }
+                let entry = export::parse_line(&line)
+                    .and_then(|entry| {
+                        if let Some(name) = &entry.bucket {
+                            bucket::check_bucket_name(name)?;
+                        }
+                        check_entry_size(bucket::record_key(entry.bucket.as_deref().map(str::as_bytes), &entry.key).len(), entry.value.len())?;
+                        Ok(entry)
+                    })
+                    .with_context(|| format!("invalid line {}", i + 1))?;
+                if entry.expires_at != 0 && entry.expires_at <= now {
+                    return Ok(None);
+                }
+                Ok(Some(LoadRecord {
+                    bucket: entry.bucket.map(String::into_bytes),
+                    key: entry.key,
+                    val: entry.value,
+                    expires_at: entry.expires_at,
+                    ts_tamp: entry.ts_tamp,
+                }))
+            })
+            .filter_map(|record| record.transpose());
+        Ok(self.load(records)?.keys)
+    }
+
+    /// Writes the live keys of a Riak Bitcask directory, which must not be written during the import.
+    /// Keys and values are copied as they are, the time to live of Bitcask is not applied.
+    pub fn import_bitcask(&mut self, dir: impl AsRef<Path>) -> Result<BitcaskImportReport> {
+        self.ensure_open()?;
+        let dir = dir.as_ref();
+        if fs::canonicalize(dir)? == fs::canonicalize(&self.conf.path)? {
+            return Err(Error::Other(anyhow!("store cannot be imported into itself")));
+        }
+
+        let mut report = BitcaskImportReport::default();
+        let key_dir = riak::read_key_dir(dir, &mut report)?;
+        let mut files = riak::DataFiles::new(dir);
+        for (key, location) in key_dir {
+            match files.read_value(&key, &location)? {
+                Some(val) => {
+                    self.writer.put_with_expiry(None, &key, &val, 0)?;
+                    report.keys += 1;
+                }
+                None => report.deleted += 1,
+            }
+        }
+        self.writer.flush()?;
+        Ok(report)
+    }
+
+    /// Changes the bytes per second limit of maintenance I/O, including a running merge. `0` means unlimited.
+    pub fn set_maintenance_rate_limit(&self, bytes_per_sec: u64) {
+        self.rate_limiter.set_rate(bytes_per_sec);
+    }
+
+    /// Writes records waiting in the write buffer to disk.
+    pub fn flush(&mut self) -> Result<()> {
+        self.ensure_open()?;
+        Ok(self.writer.flush()?)
+    }
+
+    /// Flushes pending writes. All operations return [Error::Closed] after the handle is closed.
+    pub fn close(&mut self) -> Result<()> {
+        self.ensure_open()?;
+        self.stop_background_merger();
+        self.writer.flush()?;
+        self.readers.borrow_mut().clear();
+        self.closed = true;
+        Ok(())
+    }
+
+    fn stop_background_merger(&mut self) {
+        if let Some((stop, merger)) = self.background_merger.take() {
+            let _ = stop.send(());
+            let _ = merger.join();
+        }
+    }
+
+    fn ensure_open(&self) -> Result<()> {
+        if self.closed {
+            return Err(Error::Closed);
+        }
+        Ok(())
+    }
+}
+
+/// Converts the time to live to an expiry time in milliseconds. It is never `0`, which means no expiry.
+fn expires_at(ttl: Duration) -> u64 {
+    utils::now_millis().saturating_add(ttl.as_millis() as u64).max(1)
+}
+
+impl Drop for Handle<'_> {
+    fn drop(&mut self) {
+        self.stop_background_merger();
     }
 }
 
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+    use std::io::{Cursor, Read, Write};
+    use std::time::{Duration, Instant};
+
+    use tempdir::TempDir;
+
+    use crate::Error;
+    use crate::storage::{ChangeKind, Config, Encryption, ExportOptions, Handle, MergePolicy, Stats};
+
+    #[test]
+    fn it_should_stream_values() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            write_buffer_size: 1024,
+            ..Default::default()
+        };
+        let mut handle = Handle::open(&conf).unwrap();
+        let val: Vec<u8> = (0..200_000u32).map(|x| (x % 251) as u8).collect();
+
+        // when
+        handle.put(b"small", b"buffered").unwrap();
+        handle.put_from_reader(b"blob", Cursor::new(val.clone()), val.len() as u32).unwrap();
+
+        // then
+        let mut actual = Vec::new();
+        handle.get_reader(b"blob").unwrap().unwrap().read_to_end(&mut actual).unwrap();
+        assert_eq!(val, actual);
+
+        assert_eq!(Some(val[1000..1010].to_vec()), handle.get_range(b"blob", 1000, 10).unwrap());
+        assert_eq!(Some(val[199_990..].to_vec()), handle.get_range(b"blob", 199_990, 100).unwrap());
+        assert_eq!(Some(vec![]), handle.get_range(b"blob", 300_000, 10).unwrap());
+        assert_eq!(Some(b"buffered".to_vec()), handle.get(b"small").unwrap());
+
+        let file = std::fs::read(conf.path.join(format!("{}.bitcask.data", handle.writer.file_id()))).unwrap();
+        let record = &file[file.len() - (16 + 4 + val.len())..];
+        assert_eq!(crc32fast::hash(&record[4..]).to_be_bytes(), record[..4]);
+    }
+
+    #[test]
+    fn it_should_drop_partially_streamed_value() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        let mut handle = Handle::open(&conf).unwrap();
+
+        // when
+        let result = handle.put_from_reader(b"blob", Cursor::new(vec![1u8; 10]), 100);
+
+        // then
+        assert!(result.is_err());
+        assert_eq!(None, handle.get(b"blob").unwrap());
+
+        handle.put(b"k1", b"v1").unwrap();
+        assert_eq!(Some(b"v1".to_vec()), handle.get(b"k1").unwrap());
+
+        let file = std::fs::read(conf.path.join(format!("{}.bitcask.data", handle.writer.file_id()))).unwrap();
+        assert_eq!(16 + 2 + 2, file.len());
+    }
+
+    #[test]
+    fn it_should_start_new_file_for_streamed_value_which_does_not_fit() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            max_file_size: 1024,
+            ..Default::default()
+        };
+        let mut handle = Handle::open(&conf).unwrap();
+        handle.put(b"k1", b"v1").unwrap();
+        let first_file_id = handle.writer.file_id();
+
+        // when
+        handle.put_from_reader(b"blob", Cursor::new(vec![1u8; 1000]), 1000).unwrap();
+
+        // then
+        let size = |file_id: u64| std::fs::metadata(conf.path.join(format!("{file_id}.bitcask.data"))).unwrap().len();
+        assert_eq!(16 + 2 + 2, size(first_file_id));
+        assert_eq!(Some(vec![1u8; 1000]), handle.get(b"blob").unwrap());
+        assert_eq!(Some(b"v1".to_vec()), handle.get(b"k1").unwrap());
+    }
+
+    #[test]
+    fn it_should_drop_streamed_value_whose_crc_is_not_written() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        let file_id = {
+            let mut handle = Handle::open(&conf).unwrap();
+            handle.put(b"k1", b"v1").unwrap();
+            handle.put_from_reader(b"blob", Cursor::new(vec![1u8; 100]), 100).unwrap();
+            handle.writer.file_id()
+        };
+
+        // when
+        let path = conf.path.join(format!("{}.bitcask.data", file_id));
+        let mut content = std::fs::read(&path).unwrap();
+        content[16 + 2 + 2..16 + 2 + 2 + 4].fill(0);
+        std::fs::write(&path, content).unwrap();
+        let mut handle = Handle::open(&conf).unwrap();
+
+        // then
+        assert_eq!(None, handle.get(b"blob").unwrap());
+        assert_eq!(Some(b"v1".to_vec()), handle.get(b"k1").unwrap());
+    }
+
+    #[test]
+    fn it_should_return_typed_errors() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        let mut handle = Handle::open(&conf).unwrap();
+
+        // then
+        assert!(matches!(Handle::open(&conf), Err(Error::Locked(_))));
+        assert!(matches!(handle.put(&vec![0u8; 1 << 24], b"v"), Err(Error::KeyTooLarge { .. })));
+
+        handle.close().unwrap();
+        assert!(matches!(handle.get(b"k1"), Err(Error::Closed)));
+        assert!(matches!(handle.put(b"k1", b"v1"), Err(Error::Closed)));
+    }
+
+    #[test]
+    fn it_should_report_corrupted_records() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        let file_id = {
+            let mut handle = Handle::open(&conf).unwrap();
+            handle.put(b"k1", b"v1").unwrap();
+            handle.put(b"k2", b"v2").unwrap();
+            handle.writer.file_id()
+        };
+
+        // when
+        let path = conf.path.join(format!("{}.bitcask.data", file_id));
+        let mut content = std::fs::read(&path).unwrap();
+        content[16 + 2 + 2 - 1] ^= 0xff;
+        std::fs::write(&path, content).unwrap();
+
+        // then
+        let result = Handle::open(&conf);
+        match result {
+            Err(Error::Corrupt { file_id: id, offset }) => {
+                assert_eq!(file_id, id);
+                assert_eq!(0, offset);
+            }
+            Err(e) => panic!("unexpected error: {e}"),
+            Ok(_) => panic!("corrupted store is opened"),
+        };
+    }
+
+    #[test]
+    fn it_should_truncate_incomplete_record_at_the_end_of_the_newest_file() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        let file_id = {
+            let mut handle = Handle::open(&conf).unwrap();
+            handle.put(b"k1", b"v1").unwrap();
+            handle.put(b"k2", b"v2").unwrap();
+            handle.writer.file_id()
+        };
+        let path = conf.path.join(format!("{}.bitcask.data", file_id));
+        let size = std::fs::metadata(&path).unwrap().len();
+
+        // when
+        std::fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(&[1, 2, 3]).unwrap();
+        let mut handle = Handle::open(&conf).unwrap();
+
+        // then
+        assert_eq!(size, std::fs::metadata(&path).unwrap().len());
+        assert_eq!(Some(b"v1".to_vec()), handle.get(b"k1").unwrap());
+        assert_eq!(Some(b"v2".to_vec()), handle.get(b"k2").unwrap());
+    }
+
+    #[test]
+    fn it_should_keep_single_byte_values_which_look_like_tombstones() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        {
+            let mut handle = Handle::open(&conf).unwrap();
+            handle.put(b"k", &[8]).unwrap();
+        }
+
+        // when
+        let mut handle = Handle::open(&conf).unwrap();
+
+        // then
+        assert_eq!(Some(vec![8]), handle.get(b"k").unwrap());
+    }
+
+    #[test]
+    fn it_should_report_stats() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        let record = |key: &[u8], val: &[u8]| (16 + key.len() + val.len()) as u64;
+
+        // when
+        let (file_id, stats) = {
+            let mut handle = Handle::open(&conf).unwrap();
+            handle.put(b"k1", b"v1").unwrap();
+            handle.put(b"k1", b"val1").unwrap();
+            handle.put(b"k2", b"v2").unwrap();
+            handle.delete(b"k2").unwrap();
+            handle.delete(b"k3").unwrap();
+            (handle.writer.file_id(), handle.stats().unwrap())
+        };
+
+        // then
+        let file = stats.files.get(&file_id).unwrap();
+        assert_eq!(1, stats.live_keys);
+        assert_eq!(2, stats.tombstones);
+        assert_eq!(record(b"k1", b"val1"), file.live_bytes);
+        assert_eq!(record(b"k1", b"v1") + record(b"k2", b"v2") + 2 * record(b"k2", &[8]), file.dead_bytes);
+        assert_eq!(file.live_bytes + file.dead_bytes, file.total_bytes);
+        assert_eq!(Some(file_id), stats.newest_file_id);
+        assert!(stats.key_dir_memory > 0);
+
+        let mut handle = Handle::open(&conf).unwrap();
+        let reopened = handle.stats().unwrap();
+        assert_eq!(file, reopened.files.get(&file_id).unwrap());
+        assert_eq!(Some(file_id), reopened.oldest_file_id);
+        assert_eq!(Some(handle.writer.file_id()), reopened.newest_file_id);
+        assert_eq!(None, handle.get(b"k2").unwrap());
+        assert_eq!(Some(b"val1".to_vec()), handle.get(b"k1").unwrap());
+    }
+
+    fn data_file_count(conf: &Config) -> usize {
+        std::fs::read_dir(&conf.path).unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().ends_with(".bitcask.data"))
+            .count()
+    }
+
+    #[test]
+    fn it_should_merge_files() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            max_file_size: 128,
+            ..Default::default()
+        };
+        let mut handle = Handle::open(&conf).unwrap();
+        for i in 0..50 {
+            handle.put(format!("k{}", i % 10).as_bytes(), format!("val{i}").as_bytes()).unwrap();
+        }
+        handle.delete(b"k3").unwrap();
+        handle.delete(b"k4").unwrap();
+        let files_before = data_file_count(&conf);
+        let stats_before = handle.stats().unwrap();
+
+        // when
+        let report = handle.merge().unwrap();
+
+        // then
+        assert_eq!(files_before - 1, report.files.len());
+        assert_eq!(2, data_file_count(&conf));
+        let stats = handle.stats().unwrap();
+        assert!(stats.dead_bytes < stats_before.dead_bytes);
+        assert_eq!(stats_before.live_bytes, stats.live_bytes);
+        assert_eq!(stats_before.total_bytes - report.reclaimed_bytes, stats.total_bytes);
+
+        let expect = |handle: &mut Handle<'_>| {
+            for i in 40..50 {
+                let key = format!("k{}", i % 10);
+                let expected = if i % 10 == 3 || i % 10 == 4 { None } else { Some(format!("val{i}").into_bytes()) };
+                assert_eq!(expected, handle.get(key.as_bytes()).unwrap(), "{key}");
+            }
+        };
+        expect(&mut handle);
+
+        drop(handle);
+        let mut handle = Handle::open(&conf).unwrap();
+        expect(&mut handle);
+    }
+
+    #[test]
+    fn it_should_rotate_encryption_key_on_merge() {
+        // given
+        let path = TempDir::new("bitcask-").unwrap().into_path();
+        let old_conf = Config { path: path.clone(), encryption: Some(Encryption::new(1, [1; 32])), ..Default::default() };
+        let rotated_conf = Config { path: path.clone(), encryption: Some(Encryption::new(2, [2; 32]).with_retired_key(1, [1; 32])), ..Default::default() };
+        let new_conf = Config { path: path.clone(), encryption: Some(Encryption::new(2, [2; 32])), ..Default::default() };
+
+        {
+            let mut handle = Handle::open(&old_conf).unwrap();
+            handle.put(b"k1", b"secret").unwrap();
+        }
+
+        // when
+        {
+            let mut handle = Handle::open(&rotated_conf).unwrap();
+            assert_eq!(Some(b"secret".to_vec()), handle.get(b"k1").unwrap());
+            handle.merge().unwrap();
+        }
+
+        // then
+        let mut handle = Handle::open(&new_conf).unwrap();
+        assert_eq!(Some(b"secret".to_vec()), handle.get(b"k1").unwrap());
+    }
+
+    #[test]
+    fn it_should_merge_in_background() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            max_file_size: 128,
+            merge_policy: Some(MergePolicy {
+                dead_ratio: 0.5,
+                min_files: 2,
+                check_interval: Duration::from_millis(10),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut handle = Handle::open(&conf).unwrap();
+
+        // when
+        for i in 0..100 {
+            handle.put(b"key", format!("val{i}").as_bytes()).unwrap();
+        }
+
+        // then
+        let mut merged = false;
+        for _ in 0..200 {
+            if handle.stats().unwrap().files.len() <= 3 {
+                merged = true;
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(merged);
+        assert_eq!(Some(b"val99".to_vec()), handle.get(b"key").unwrap());
+    }
+
+    #[test]
+    fn it_should_limit_merge_rate() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            max_file_size: 512,
+            maintenance_rate_limit: 8 * 1024,
+            ..Default::default()
+        };
+        let mut handle = Handle::open(&conf).unwrap();
+        for i in 0..200 {
+            handle.put(format!("k{}", i % 10).as_bytes(), format!("val{i}").as_bytes()).unwrap();
+        }
+        let total_bytes = handle.stats().unwrap().total_bytes;
+        assert!(total_bytes > 4 * 1024);
+
+        // when
+        let started = Instant::now();
+        handle.merge().unwrap();
+
+        // then
+        // only the lower bound is checked, a loaded machine can make the merge slower but never faster
+        assert!(started.elapsed() >= Duration::from_millis(400));
+
+        handle.set_maintenance_rate_limit(0);
+        assert_eq!(0, handle.rate_limiter.rate());
+        for i in 0..200 {
+            handle.put(format!("k{}", i % 10).as_bytes(), format!("val{i}").as_bytes()).unwrap();
+        }
+        handle.merge().unwrap();
+        assert_eq!(Some(b"val199".to_vec()), handle.get(b"k9").unwrap());
+    }
+
+    #[test]
+    fn it_should_separate_buckets() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            bucket_expiry_secs: [("sessions".to_string(), 1)].into_iter().collect(),
+            ..Default::default()
+        };
+        let mut handle = Handle::open(&conf).unwrap();
+
+        // when
+        handle.put(b"k1", b"default").unwrap();
+        handle.bucket("users").unwrap().put(b"k1", b"user").unwrap();
+        handle.bucket("orders").unwrap().put(b"k1", b"order").unwrap();
+        handle.bucket("sessions").unwrap().put(b"k1", b"session").unwrap();
+        handle.bucket("orders").unwrap().delete(b"k1").unwrap();
+
+        // then
+        assert_eq!(Some(b"default".to_vec()), handle.get(b"k1").unwrap());
+        assert_eq!(Some(b"user".to_vec()), handle.bucket("users").unwrap().get(b"k1").unwrap());
+        assert_eq!(None, handle.bucket("orders").unwrap().get(b"k1").unwrap());
+        assert_eq!(vec!["orders", "sessions", "users"], handle.buckets().unwrap());
+        assert_eq!(1, handle.bucket("users").unwrap().stats().unwrap().live_keys);
+        assert_eq!(3, handle.stats().unwrap().live_keys);
+        assert!(matches!(handle.bucket(""), Err(Error::InvalidBucketName(_))));
+
+        std::thread::sleep(Duration::from_millis(1100));
+        assert_eq!(1, handle.bucket("sessions").unwrap().stats().unwrap().expired_keys);
+        assert_eq!(None, handle.bucket("sessions").unwrap().get(b"k1").unwrap());
+        assert_eq!(Some(b"user".to_vec()), handle.bucket("users").unwrap().get(b"k1").unwrap());
+    }
+
+    #[test]
+    fn it_should_drop_buckets() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            max_file_size: 256,
+            ..Default::default()
+        };
+        let non_empty = |stats: Stats| stats.files.into_iter().filter(|(_, file)| file.total_bytes > 0).collect::<Vec<_>>();
+        let files = {
+            let mut handle = Handle::open(&conf).unwrap();
+            for i in 0..50 {
+                handle.bucket("users").unwrap().put(format!("k{i}").as_bytes(), b"old").unwrap();
+                handle.put(format!("k{i}").as_bytes(), b"default").unwrap();
+            }
+
+            // when
+            handle.drop_bucket("users").unwrap();
+            handle.bucket("users").unwrap().put(b"k1", b"new").unwrap();
+
+            assert_eq!(None, handle.bucket("users").unwrap().get(b"k2").unwrap());
+            assert_eq!(Some(b"new".to_vec()), handle.bucket("users").unwrap().get(b"k1").unwrap());
+            non_empty(handle.stats().unwrap())
+        };
+
+        // then
+        let assert_dropped = |handle: &mut Handle<'_>| {
+            let mut users = handle.bucket("users").unwrap();
+            assert_eq!(None, users.get(b"k2").unwrap());
+            assert_eq!(Some(b"new".to_vec()), users.get(b"k1").unwrap());
+            assert_eq!(1, users.stats().unwrap().live_keys);
+            assert_eq!(Some(b"default".to_vec()), handle.get(b"k2").unwrap());
+        };
+
+        let mut handle = Handle::open(&conf).unwrap();
+        assert_dropped(&mut handle);
+        // counters of the dropped keys are the same as the ones counted on rebuild
+        assert_eq!(files, non_empty(handle.stats().unwrap()));
+        let dead_bytes = handle.stats().unwrap().dead_bytes;
+
+        handle.merge().unwrap();
+        assert!(handle.stats().unwrap().dead_bytes < dead_bytes);
+        assert_dropped(&mut handle);
+        drop(handle);
+
+        let mut handle = Handle::open(&conf).unwrap();
+        assert_dropped(&mut handle);
+    }
+
+    #[test]
+    fn it_should_deliver_changes_after_sync() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            expiry_secs: 1,
+            ..Default::default()
+        };
+        let mut handle = Handle::open(&conf).unwrap();
+        let watcher = handle.watch(b"user:").unwrap();
+        let bucket_watcher = handle.bucket("b1").unwrap().watch(b"").unwrap();
+
+        // when
+        handle.put(b"user:1", b"v1").unwrap();
+        handle.put(b"order:1", b"v1").unwrap();
+        handle.delete(b"user:1").unwrap();
+        handle.bucket("b1").unwrap().put(b"user:2", b"v2").unwrap();
+        handle.put(b"user:3", b"v3").unwrap();
+
+        // then
+        assert!(watcher.try_recv().is_none());
+        handle.flush().unwrap();
+
+        let events: Vec<(ChangeKind, Vec<u8>)> = std::iter::from_fn(|| watcher.try_recv()).map(|e| (e.kind, e.key)).collect();
+        assert_eq!(vec![
+            (ChangeKind::Put, b"user:1".to_vec()),
+            (ChangeKind::Delete, b"user:1".to_vec()),
+            (ChangeKind::Put, b"user:3".to_vec()),
+        ], events);
+        assert_eq!(b"user:2".to_vec(), bucket_watcher.try_recv().unwrap().key);
+        assert!(bucket_watcher.try_recv().is_none());
+
+        std::thread::sleep(Duration::from_millis(1100));
+        assert_eq!(None, handle.get(b"user:3").unwrap());
+        handle.flush().unwrap();
+        assert_eq!(ChangeKind::Expire, watcher.recv_timeout(Duration::from_secs(1)).unwrap().kind);
+    }
+
+    #[test]
+    fn it_should_expire_keys_with_own_ttl() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            max_file_size: 64,
+            ..Default::default()
+        };
+
+        // when
+        {
+            let mut handle = Handle::open(&conf).unwrap();
+            handle.put_with_ttl(b"short", b"v1", Duration::from_millis(50)).unwrap();
+            handle.put_with_ttl(b"long", b"v2", Duration::from_secs(3600)).unwrap();
+            handle.put(b"persistent", b"v3").unwrap();
+            assert!(handle.expire(b"persistent", Duration::from_secs(60)).unwrap());
+            handle.put(b"persistent", b"v3").unwrap();
+            assert!(!handle.expire(b"missing", Duration::from_secs(60)).unwrap());
+
+            std::thread::sleep(Duration::from_millis(60));
+            assert!(matches!(handle.ttl(b"short"), Err(Error::Expired)));
+            assert_eq!(None, handle.ttl(b"short").unwrap());
+            assert_eq!(None, handle.get(b"short").unwrap());
+            handle.merge().unwrap();
+        }
+
+        // then
+        let mut handle = Handle::open(&conf).unwrap();
+        assert_eq!(None, handle.get(b"short").unwrap());
+        assert_eq!(Some(b"v2".to_vec()), handle.get(b"long").unwrap());
+        let stats = handle.stats().unwrap();
+        assert_eq!(stats.total_bytes, stats.files.keys().map(|id| std::fs::metadata(conf.path.join(format!("{id}.bitcask.data"))).unwrap().len()).sum::<u64>());
+        let ttl = handle.ttl(b"long").unwrap().unwrap();
+        assert!(ttl > Duration::from_secs(3590) && ttl <= Duration::from_secs(3600));
+        assert_eq!(None, handle.ttl(b"persistent").unwrap());
+        assert!(handle.contains_key(b"persistent").unwrap());
+    }
+
+    #[test]
+    fn it_should_import_exported_keys() {
+        // given
+        let src = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        let dest = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        let mut handle = Handle::open(&src).unwrap();
+        handle.put(b"k1", &[0, 159, 146, 150]).unwrap();
+        handle.put_with_ttl(b"k2", b"v2", Duration::from_secs(3600)).unwrap();
+        handle.put_with_ttl(b"short", b"v3", Duration::from_millis(200)).unwrap();
+        handle.bucket("b1").unwrap().put(b"k1", b"v4").unwrap();
+
+        // when
+        let mut exported = Vec::new();
+        assert_eq!(4, handle.export(&mut exported, &ExportOptions { ttl: true, timestamps: true }).unwrap());
+        std::thread::sleep(Duration::from_millis(250));
+        let mut imported = Handle::open(&dest).unwrap();
+        let count = imported.import(Cursor::new(&exported)).unwrap();
+
+        // then
+        assert_eq!(3, count);
+        assert_eq!(Some(vec![0, 159, 146, 150]), imported.get(b"k1").unwrap());
+        assert_eq!(None, imported.get(b"short").unwrap());
+        let ttl = imported.ttl(b"k2").unwrap().unwrap();
+        assert!(ttl > Duration::from_secs(3590) && ttl <= Duration::from_secs(3600));
+        assert_eq!(Some(b"v4".to_vec()), imported.bucket("b1").unwrap().get(b"k1").unwrap());
+        let mut reexported = Vec::new();
+        imported.export(&mut reexported, &ExportOptions { ttl: true, timestamps: true }).unwrap();
+        let lines = |export: &[u8]| -> BTreeSet<String> { String::from_utf8(export.to_vec()).unwrap().lines().map(str::to_string).collect() };
+        assert!(lines(&reexported).is_subset(&lines(&exported)));
+        assert_eq!(1, imported.import(Cursor::new(r#"{"key": "b2xk", "value": "djE=", "ts": 1000}"#)).unwrap());
+        let mut reexported = Vec::new();
+        imported.export(&mut reexported, &ExportOptions { ttl: false, timestamps: true }).unwrap();
+        assert!(lines(&reexported).contains(r#"{"key":"b2xk","value":"djE=","ts":1000}"#));
+
+        let mut without_ttl = Vec::new();
+        handle.export(&mut without_ttl, &ExportOptions::default()).unwrap();
+        assert!(String::from_utf8(without_ttl).unwrap().lines().all(|line| !line.contains("expires_at") && !line.contains("\"ts\"")));
+        assert!(imported.import(Cursor::new(b"{\"key\": \"!\"}")).is_err());
+        let partly_valid = [r#"{"key": "bmV3", "value": "djE="}"#, r#"{"key": "!"}"#].join("\n");
+        assert!(imported.import(Cursor::new(partly_valid)).is_err());
+        assert_eq!(None, imported.get(b"new").unwrap());
+        assert!(std::fs::read_dir(&dest.path).unwrap().all(|e| e.unwrap().path().extension().is_none_or(|ext| ext != "load")));
+    }
+
+    #[test]
+    fn it_should_scan_keys_in_order() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        let mut handle = Handle::open(&conf).unwrap();
+        for key in ["user:3", "order:1", "user:1", "user:2", "user:4", "users"] {
+            handle.put(key.as_bytes(), b"v").unwrap();
+        }
+        handle.delete(b"user:2").unwrap();
+        handle.put_with_ttl(b"user:4", b"v", Duration::from_millis(1)).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+
+        // when
+        let first = handle.scan(b"user:", None, 1).unwrap();
+        let rest = handle.scan(b"user:", first.last().map(Vec::as_slice), 10).unwrap();
+
+        // then
+        assert_eq!(vec![b"user:1".to_vec()], first);
+        assert_eq!(vec![b"user:3".to_vec()], rest);
+        assert_eq!(4, handle.scan(b"", Some(b"a"), 10).unwrap().len());
+    }
+}
@@ -0,0 +1,115 @@
+// [crc|ts_tamp|ksz|vsz|val_offset|expires_at|key]
+// A hint file has an entry per record of the data file with the same id, so the key directory can be
+// rebuilt without reading values. ksz keeps the record flags like in data files, vsz and val_offset
+// point after the stored expiry time like in Header.
+
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use bytes::BufMut;
+use log::warn;
+
+use crate::storage::Header;
+use crate::storage::log::{FLAG_BUCKET_DROP, FLAGS_SHIFT, MAX_KEY_SIZE};
+use crate::storage::utils::build_data_file_name;
+
+const HINT_KEY_OFFSET: usize = 28;
+
+/// Record key and header of a record.
+pub(crate) type Hint = (Vec<u8>, Header);
+
+pub(crate) fn build_hint_file_name(file_id: u64) -> String {
+    format!("{file_id}.bitcask.hint")
+}
+
+pub(crate) struct HintWriter {
+    writer: BufWriter<fs::File>,
+}
+
+impl HintWriter {
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        Ok(Self { writer: BufWriter::with_capacity(1 << 20, fs::File::create(path)?) })
+    }
+
+    pub fn write(&mut self, record_key: &[u8], header: &Header) -> anyhow::Result<()> {
+        let mut entry = Vec::with_capacity(HINT_KEY_OFFSET + record_key.len());
+        entry.put_u32(0); // empty space for crc
+        entry.put_u32(header.ts_tamp);
+        entry.put_u32(((header.flags as u32) << FLAGS_SHIFT) | record_key.len() as u32);
+        entry.put_u32(header.val_size);
+        entry.put_u32(header.val_offset);
+        entry.put_u64(header.expires_at);
+        entry.put(record_key);
+
+        let crc = crc32fast::hash(&entry[4..]);
+        entry[..4].copy_from_slice(&crc.to_be_bytes());
+        self.writer.write_all(&entry)?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        Ok(())
+    }
+}
+
+/// Reads the hint file of the data file. Returns `None` if there is no hint file, or it does not
+/// match the data file, then the data file must be read instead.
+pub(crate) fn read_hints(dir: &Path, file_id: u64) -> anyhow::Result<Option<Vec<Hint>>> {
+    let content = match fs::read(dir.join(build_hint_file_name(file_id))) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let data_size = fs::metadata(dir.join(build_data_file_name(file_id)))?.len();
+
+    let hints = parse_hints(file_id, &content);
+    let end = hints.as_ref().map(|hints| hints.last().map(|(_, h)| h.val_offset as u64 + h.val_size as u64).unwrap_or_default());
+    if end != Some(data_size) {
+        warn!("hint file of {} does not match the data file, reading the data file", file_id);
+        return Ok(None);
+    }
+    Ok(hints)
+}
+
+fn parse_hints(file_id: u64, mut content: &[u8]) -> Option<Vec<Hint>> {
+    let mut hints = Vec::new();
+    while !content.is_empty() {
+        if content.len() < HINT_KEY_OFFSET {
+            return None;
+        }
+        let field = |at: usize| u32::from_be_bytes(content[at..at + 4].try_into().unwrap());
+        let key_size = (field(8) & MAX_KEY_SIZE as u32) as usize;
+        let end = HINT_KEY_OFFSET + key_size;
+        if content.len() < end || crc32fast::hash(&content[4..end]) != field(0) {
+            return None;
+        }
+
+        let flags = (field(8) >> FLAGS_SHIFT) as u8;
+        // drop markers need their value to be applied
+        if flags & FLAG_BUCKET_DROP != 0 {
+            return None;
+        }
+        let header = Header {
+            file_id,
+            ts_tamp: field(4),
+            val_size: field(12),
+            val_offset: field(16),
+            flags,
+            expires_at: u64::from_be_bytes(content[20..28].try_into().unwrap()),
+        };
+        hints.push((content[HINT_KEY_OFFSET..end].to_vec(), header));
+        content = &content[end..];
+    }
+    Some(hints)
+}
+
+/// Removes the hint file of a data file which is rewritten or removed.
+pub(crate) fn remove_hint(dir: &Path, file_id: u64) -> anyhow::Result<()> {
+    match fs::remove_file(dir.join(build_hint_file_name(file_id))) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
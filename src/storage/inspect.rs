@@ -0,0 +1,408 @@
+//! Read-only access to data files for tools like `fakir verify` and `fakir dump`.
+//! Nothing here takes the directory lock, so it can run next to a writer.
+
+use std::path::Path;
+
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::Result;
+use crate::storage::{bucket, Op};
+use crate::storage::hint::{build_hint_file_name, Hint, read_hints};
+use crate::storage::log::{decode_record, find_next_record, FLAG_COMPRESSION_MASK, FLAG_ENCRYPTED, KEY_OFFSET, LogEntry, LogIterator, TOMBSTONE_MARKER_CHAR, valid_record_size};
+use crate::storage::rebuild::extract_data_file_ids;
+use crate::storage::utils::{build_data_file_name, open_file_for_read};
+
+/// A record of a data file. `value` is stored as written, so it can be compressed or encrypted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub offset: u64,
+    /// Size of the record in the file.
+    pub size: u64,
+    pub ts_tamp: u32,
+    pub op: Op,
+    pub bucket: Option<Vec<u8>>,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    /// Expiry time of the key in milliseconds since epoch.
+    pub expires_at: Option<u64>,
+    pub compressed: bool,
+    pub encrypted: bool,
+}
+
+/// Iterates the records of a data file. Iteration ends after the first corrupt record.
+pub struct Records {
+    iter: LogIterator,
+}
+
+impl Records {
+    /// Opens a data file. Its id is read from the file name.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file_id = file_id_of(path)?;
+        let file = std::fs::File::open(path).with_context(|| format!("{} cannot be opened", path.display()))?;
+        Ok(Self { iter: LogIterator::new(file_id, file) })
+    }
+}
+
+fn file_id_of(path: &Path) -> anyhow::Result<u64> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.split('.').next())
+        .and_then(|id| id.parse().ok())
+        .with_context(|| format!("{} is not a data file", path.display()))
+}
+
+impl Iterator for Records {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|entry| to_record(entry?).map_err(Into::into))
+    }
+}
+
+/// An entry of [Dump].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DumpEntry {
+    /// `crc_ok` is false if the record fits in the file but its content does not match the crc.
+    Record { record: Record, crc_ok: bool },
+    /// Bytes which cannot be read as a record, up to the next record with a valid crc.
+    Corrupt { start: u64, end: u64 },
+}
+
+/// Iterates every record of a data file, including the ones with a bad crc. The file is loaded into memory.
+pub struct Dump {
+    file_id: u64,
+    data: Vec<u8>,
+    offset: usize,
+}
+
+impl Dump {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file_id = file_id_of(path)?;
+        let data = std::fs::read(path).with_context(|| format!("{} cannot be read", path.display()))?;
+        Ok(Self { file_id, data, offset: 0 })
+    }
+}
+
+impl Iterator for Dump {
+    type Item = DumpEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.data.len() {
+            return None;
+        }
+
+        let start = self.offset;
+        let crc_ok = valid_record_size(&self.data[start..]).is_some();
+        let next = find_next_record(&self.data, start + 1).unwrap_or(self.data.len());
+        let record = decode_record(self.file_id, &self.data, start).and_then(to_record).ok()
+            // a record with a bad crc is only trusted if the next record starts right after it
+            .filter(|record| crc_ok || start + record.size as usize == next);
+
+        match record {
+            Some(record) => {
+                self.offset += record.size as usize;
+                Some(DumpEntry::Record { record, crc_ok })
+            }
+            None => {
+                self.offset = next;
+                Some(DumpEntry::Corrupt { start: start as u64, end: next as u64 })
+            }
+        }
+    }
+}
+
+fn to_record(entry: LogEntry) -> anyhow::Result<Record> {
+    let LogEntry { key: record_key, val, header } = entry;
+    let (bucket, key) = bucket::split_record_key(header.flags, &record_key)?;
+    let op = if header.is_bucket_drop() {
+        Op::DropBucket
+    } else if header.is_tombstone() {
+        Op::Delete
+    } else {
+        Op::Put
+    };
+
+    let size = (KEY_OFFSET + record_key.len()) as u64 + header.stored_size() as u64;
+    Ok(Record {
+        offset: header.val_offset as u64 + header.val_size as u64 - size,
+        size,
+        ts_tamp: header.ts_tamp,
+        op,
+        bucket: bucket.map(<[u8]>::to_vec),
+        key: key.to_vec(),
+        value: val,
+        expires_at: Some(header.expires_at).filter(|at| *at > 0),
+        compressed: header.flags & FLAG_COMPRESSION_MASK != 0,
+        encrypted: header.flags & FLAG_ENCRYPTED != 0,
+    })
+}
+
+/// An inconsistency found by [verify]. Offsets are positions in the data file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Problem {
+    /// Bytes which cannot be read as records, e.g. crc mismatch or a truncated record. Reading continues
+    /// from the next valid record at `end`.
+    Corrupt { start: u64, end: u64 },
+    /// Record is readable but cannot be written by the store, e.g. a tombstone with a value.
+    InvalidRecord { offset: u64, reason: String },
+    /// Hint file does not describe the records of the data file. `offset` is the first record which differs.
+    HintMismatch { offset: Option<u64>, reason: String },
+}
+
+/// A finding of [verify] which does not make the store inconsistent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Warning {
+    /// Record is newer than the next data file. The store does not rely on the order of timestamps
+    /// across files, but it may point to files renamed or copied from another store.
+    OutOfOrder { offset: u64, ts_tamp: u32, next_file_id: u64 },
+}
+
+/// Result of [verify] for a data file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct FileReport {
+    pub file_id: u64,
+    pub records: u64,
+    /// Size of the readable records.
+    pub bytes: u64,
+    pub problems: Vec<Problem>,
+    pub warnings: Vec<Warning>,
+}
+
+/// Result of [verify].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct VerifyReport {
+    pub files: Vec<FileReport>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.files.iter().all(|file| file.problems.is_empty())
+    }
+}
+
+/// Reads every record of the data files in `dir`, and reports corrupt byte ranges and records
+/// which are inconsistent with the rest of the store. Warnings do not fail [VerifyReport::is_ok].
+pub fn verify(dir: &Path) -> Result<VerifyReport> {
+    let file_ids: Vec<u64> = extract_data_file_ids(dir)?.collect();
+    let mut report = VerifyReport::default();
+    for (i, file_id) in file_ids.iter().copied().enumerate() {
+        report.files.push(verify_file(dir, file_id, file_ids.get(i + 1).copied())?);
+    }
+    Ok(report)
+}
+
+fn verify_file(dir: &Path, file_id: u64, next_file_id: Option<u64>) -> anyhow::Result<FileReport> {
+    let file_name = build_data_file_name(file_id);
+    let mut report = FileReport { file_id, ..Default::default() };
+    // the file is only loaded into memory to skip corrupt regions
+    let mut content: Option<Vec<u8>> = None;
+    let has_hints = dir.join(build_hint_file_name(file_id)).exists();
+    let mut records: Vec<Hint> = Vec::new();
+    let mut iter = LogIterator::new(file_id, open_file_for_read(dir, &file_name)?);
+    while let Some(entry) = iter.next() {
+        let offset = match entry {
+            Ok(entry) => {
+                report.records += 1;
+                if has_hints {
+                    records.push((entry.key.clone(), entry.header));
+                }
+                check_record(&mut report, entry, next_file_id);
+                continue;
+            }
+            Err(e) => match e.downcast_ref::<crate::Error>() {
+                Some(crate::Error::Corrupt { offset, .. }) => *offset,
+                _ => return Err(e),
+            },
+        };
+
+        let content = match &mut content {
+            Some(content) => content,
+            None => content.insert(std::fs::read(dir.join(&file_name))?),
+        };
+        match find_next_record(content, offset as usize + 1) {
+            Some(next) => {
+                report.problems.push(Problem::Corrupt { start: offset, end: next as u64 });
+                iter = LogIterator::with_offset(file_id, open_file_for_read(dir, &file_name)?, next as u64)?;
+            }
+            None => report.problems.push(Problem::Corrupt { start: offset, end: content.len() as u64 }),
+        }
+    }
+
+    if has_hints {
+        if let Some(problem) = check_hints(dir, file_id, &records)? {
+            report.problems.push(problem);
+        }
+    }
+    Ok(report)
+}
+
+fn check_hints(dir: &Path, file_id: u64, records: &[Hint]) -> anyhow::Result<Option<Problem>> {
+    let Some(hints) = read_hints(dir, file_id)? else {
+        return Ok(Some(Problem::HintMismatch { offset: None, reason: "hint file is corrupt or has a different size".to_string() }));
+    };
+
+    let record_offset = |(key, header): &Hint| header.val_offset as u64 + header.val_size as u64 - (KEY_OFFSET + key.len()) as u64 - header.stored_size() as u64;
+    if let Some(i) = (0..records.len().max(hints.len())).find(|i| records.get(*i) != hints.get(*i)) {
+        let offset = records.get(i).or(hints.get(i)).map(record_offset);
+        return Ok(Some(Problem::HintMismatch { offset, reason: "hint does not match the record".to_string() }));
+    }
+    Ok(None)
+}
+
+fn check_record(report: &mut FileReport, entry: LogEntry, next_file_id: Option<u64>) {
+    let size = (KEY_OFFSET + entry.key.len()) as u64 + entry.header.stored_size() as u64;
+    let offset = entry.header.val_offset as u64 + entry.header.val_size as u64 - size;
+    report.bytes += size;
+
+    let file_id = report.file_id;
+    let mut invalid = |reason: &str| report.problems.push(Problem::InvalidRecord { offset, reason: reason.to_string() });
+    let record = match to_record(entry) {
+        Ok(record) => record,
+        Err(_) => return invalid("invalid bucket key"),
+    };
+    match record.op {
+        Op::Delete if record.value != [TOMBSTONE_MARKER_CHAR] => invalid("tombstone has a value"),
+        Op::Delete | Op::DropBucket if record.compressed || record.encrypted || record.expires_at.is_some() => invalid("tombstone has value flags"),
+        Op::DropBucket => match bucket::decode_drop_marker(&record.value) {
+            Err(_) => invalid("invalid bucket drop marker"),
+            Ok((marker_file_id, _)) if marker_file_id > file_id => invalid("bucket drop marker points to a newer file"),
+            Ok(_) => {}
+        },
+        _ => {}
+    }
+
+    if let Some(next_file_id) = next_file_id.filter(|id| record.ts_tamp as u64 > *id) {
+        report.warnings.push(Warning::OutOfOrder { offset, ts_tamp: record.ts_tamp, next_file_id });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+
+    use crate::storage::{Config, Handle, Op};
+
+    use super::{Dump, DumpEntry, Problem, Records, Warning, verify};
+
+    #[test]
+    fn it_should_read_records_and_find_corruption() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        {
+            let mut handle = Handle::open(&conf).unwrap();
+            handle.put(b"k1", b"v1").unwrap();
+            handle.bucket("b1").unwrap().put(b"k2", b"v2").unwrap();
+            handle.delete(b"k1").unwrap();
+        }
+        let path = std::fs::read_dir(&conf.path).unwrap()
+            .map(|e| e.unwrap().path())
+            .find(|p| p.to_string_lossy().ends_with(".data"))
+            .unwrap();
+
+        // when
+        let records: Vec<_> = Records::open(&path).unwrap().map(Result::unwrap).collect();
+
+        // then
+        assert_eq!(vec![Op::Put, Op::Put, Op::Delete], records.iter().map(|r| r.op).collect::<Vec<_>>());
+        assert_eq!(Some(b"b1".to_vec()), records[1].bucket);
+        assert_eq!(records[0].offset + records[0].size, records[1].offset);
+        assert!(verify(&conf.path).unwrap().is_ok());
+
+        let mut content = std::fs::read(&path).unwrap();
+        let last = content.len() - 1;
+        content[last] ^= 0xff;
+        std::fs::write(&path, content).unwrap();
+
+        let report = verify(&conf.path).unwrap();
+        assert_eq!(vec![Problem::Corrupt { start: records[2].offset, end: records[2].offset + records[2].size }], report.files[0].problems);
+        assert_eq!(2, report.files[0].records);
+
+        let dumped: Vec<_> = Dump::open(&path).unwrap().collect();
+        assert_eq!(3, dumped.len());
+        assert!(matches!(&dumped[1], DumpEntry::Record { record, crc_ok: true } if *record == records[1]));
+        assert!(matches!(&dumped[2], DumpEntry::Record { record, crc_ok: false } if record.key == b"k1"));
+    }
+
+    #[test]
+    fn it_should_continue_verify_after_corrupt_region() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        {
+            let mut handle = Handle::open(&conf).unwrap();
+            handle.put(b"k1", b"v1").unwrap();
+            handle.put(b"k2", b"v2").unwrap();
+            handle.put(b"k3", b"v3").unwrap();
+        }
+        let path = std::fs::read_dir(&conf.path).unwrap()
+            .map(|e| e.unwrap().path())
+            .find(|p| p.to_string_lossy().ends_with(".data"))
+            .unwrap();
+        let records: Vec<_> = Records::open(&path).unwrap().map(Result::unwrap).collect();
+        let mut content = std::fs::read(&path).unwrap();
+        content[records[1].offset as usize + 5] ^= 0xff;
+        std::fs::write(&path, content).unwrap();
+
+        // when
+        let report = verify(&conf.path).unwrap();
+
+        // then
+        assert!(!report.is_ok());
+        assert_eq!(vec![Problem::Corrupt { start: records[1].offset, end: records[2].offset }], report.files[0].problems);
+        assert_eq!(2, report.files[0].records);
+    }
+
+    #[test]
+    fn it_should_find_hints_which_do_not_match_records() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        let loaded = Handle::open(&conf).unwrap().bulk_load([(b"k1", b"v1"), (b"k2", b"v2")]).unwrap();
+        let hint_path = conf.path.join(format!("{}.bitcask.hint", loaded.files[0]));
+        assert!(verify(&conf.path).unwrap().is_ok());
+
+        // when
+        let mut content = std::fs::read(&hint_path).unwrap();
+        content.truncate(content.len() / 2);
+        std::fs::write(&hint_path, content).unwrap();
+
+        // then
+        let report = verify(&conf.path).unwrap();
+        let file = report.files.iter().find(|f| f.file_id == loaded.files[0]).unwrap();
+        assert!(matches!(file.problems.as_slice(), [Problem::HintMismatch { offset: None, .. }]));
+        assert_eq!(Some(b"v2".to_vec()), Handle::open(&conf).unwrap().get(b"k2").unwrap());
+    }
+
+    #[test]
+    fn it_should_warn_about_records_newer_than_the_next_file() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        let first = Handle::open(&conf).unwrap().bulk_load([(b"k1", b"v1")]).unwrap().files[0];
+        let second = Handle::open(&conf).unwrap().bulk_load([(b"k2", b"v2")]).unwrap().files[0];
+        for (from, to) in [(first, 1), (second, 2)] {
+            std::fs::rename(conf.path.join(format!("{from}.bitcask.data")), conf.path.join(format!("{to}.bitcask.data"))).unwrap();
+            std::fs::remove_file(conf.path.join(format!("{from}.bitcask.hint"))).unwrap();
+        }
+
+        // when
+        let report = verify(&conf.path).unwrap();
+
+        // then
+        assert!(report.is_ok());
+        let file = report.files.iter().find(|f| f.file_id == 1).unwrap();
+        assert!(matches!(file.warnings.as_slice(), [Warning::OutOfOrder { offset: 0, next_file_id: 2, .. }]));
+    }
+}
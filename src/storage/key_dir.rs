@@ -0,0 +1,182 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+
+use crate::storage::Header;
+use crate::storage::stats::record_size;
+
+/// Keys are ordered, so a keyspace can be scanned by prefix from a cursor.
+pub(crate) type KeyMap = BTreeMap<Vec<u8>, Header>;
+
+/// Key directory of the default keyspace and of the buckets. Each bucket has its own map,
+/// so a bucket is dropped without touching its keys.
+#[derive(Default)]
+pub(crate) struct KeyDir {
+    keys: KeyMap,
+    buckets: HashMap<Vec<u8>, BucketKeys>,
+}
+
+/// Keys of a bucket and the size of their records per data file, so the file counters are
+/// updated without visiting the keys when the bucket is dropped.
+#[derive(Default)]
+pub(crate) struct BucketKeys {
+    keys: KeyMap,
+    pub live: HashMap<u64, LiveRecords>,
+}
+
+/// Records of a bucket in a data file which are pointed by its keys.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct LiveRecords {
+    pub bytes: u64,
+    pub keys: u64,
+}
+
+impl BucketKeys {
+    fn insert(&mut self, name: &[u8], key: Vec<u8>, header: Header) -> Option<Header> {
+        // stored keys are prefixed with the bucket name
+        let record_key_size = 1 + name.len() + key.len();
+        let live = self.live.entry(header.file_id).or_default();
+        live.bytes += record_size(record_key_size, header.stored_size());
+        live.keys += 1;
+        let replaced = self.keys.insert(key, header);
+        if let Some(replaced) = &replaced {
+            self.remove_live(record_key_size, replaced);
+        }
+        replaced
+    }
+
+    fn remove(&mut self, name: &[u8], key: &[u8]) -> Option<Header> {
+        let removed = self.keys.remove(key)?;
+        self.remove_live(1 + name.len() + key.len(), &removed);
+        Some(removed)
+    }
+
+    fn remove_live(&mut self, record_key_size: usize, header: &Header) {
+        if let Some(live) = self.live.get_mut(&header.file_id) {
+            live.bytes = live.bytes.saturating_sub(record_size(record_key_size, header.stored_size()));
+            live.keys = live.keys.saturating_sub(1);
+            if live.keys == 0 {
+                self.live.remove(&header.file_id);
+            }
+        }
+    }
+}
+
+impl KeyDir {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the keys of the bucket, or the default keyspace if `bucket` is `None`.
+    pub fn keys(&self, bucket: Option<&[u8]>) -> Option<&KeyMap> {
+        match bucket {
+            None => Some(&self.keys),
+            Some(name) => self.buckets.get(name).map(|bucket| &bucket.keys),
+        }
+    }
+
+    pub fn get(&self, bucket: Option<&[u8]>, key: &[u8]) -> Option<&Header> {
+        self.keys(bucket).and_then(|keys| keys.get(key))
+    }
+
+    /// Points the key to `new` if it still points to the record of `old`. Used by merge
+    /// when the record is copied. Returns false if the key is written or deleted since.
+    pub fn relocate(&mut self, bucket: Option<&[u8]>, key: &[u8], old: &Header, new: Header) -> bool {
+        let is_current = |current: &Header| current.file_id == old.file_id && current.val_offset == old.val_offset;
+        match bucket {
+            None => match self.keys.get_mut(key) {
+                Some(current) if is_current(current) => {
+                    *current = new;
+                    true
+                }
+                _ => false,
+            },
+            Some(name) => match self.buckets.get_mut(name) {
+                Some(keys) if keys.keys.get(key).is_some_and(is_current) => {
+                    keys.insert(name, key.to_vec(), new);
+                    true
+                }
+                _ => false,
+            },
+        }
+    }
+
+    /// Iterates the keys which start with `prefix` and are greater than `after`, in key order.
+    pub fn scan<'k>(&'k self, bucket: Option<&[u8]>, prefix: &'k [u8], after: Option<&[u8]>) -> impl Iterator<Item=(&'k Vec<u8>, &'k Header)> {
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after.to_vec()),
+            _ => Bound::Included(prefix.to_vec()),
+        };
+        self.keys(bucket).into_iter()
+            .flat_map(move |keys| keys.range((start.clone(), Bound::Unbounded)))
+            .take_while(move |(key, _)| key.starts_with(prefix))
+    }
+
+    pub fn contains_key(&self, bucket: Option<&[u8]>, key: &[u8]) -> bool {
+        self.get(bucket, key).is_some()
+    }
+
+    /// Inserts the key and creates the bucket if it does not exist.
+    pub fn insert(&mut self, bucket: Option<&[u8]>, key: Vec<u8>, header: Header) -> Option<Header> {
+        match bucket {
+            None => self.keys.insert(key, header),
+            Some(name) => self.create_bucket(name).insert(name, key, header),
+        }
+    }
+
+    pub fn remove(&mut self, bucket: Option<&[u8]>, key: &[u8]) -> Option<Header> {
+        match bucket {
+            None => self.keys.remove(key),
+            Some(name) => self.buckets.get_mut(name).and_then(|keys| keys.remove(name, key)),
+        }
+    }
+
+    pub fn create_bucket(&mut self, name: &[u8]) -> &mut BucketKeys {
+        if !self.buckets.contains_key(name) {
+            self.buckets.insert(name.to_vec(), BucketKeys::default());
+        }
+        self.buckets.get_mut(name).unwrap()
+    }
+
+    /// Removes the bucket and returns its keys.
+    pub fn drop_bucket(&mut self, name: &[u8]) -> Option<BucketKeys> {
+        self.buckets.remove(name)
+    }
+
+    /// Removes the keys of the bucket which are written before `(file_id, val_offset)`.
+    /// Used on rebuild, where records of a dropped bucket can be loaded from a file after the drop marker.
+    pub fn drop_bucket_before(&mut self, name: &[u8], file_id: u64, val_offset: u32) -> Vec<(Vec<u8>, Header)> {
+        let Some(keys) = self.buckets.get_mut(name) else {
+            return vec![];
+        };
+
+        let dropped: Vec<Vec<u8>> = keys.keys.iter()
+            .filter(|(_, h)| (h.file_id, h.val_offset) < (file_id, val_offset))
+            .map(|(k, _)| k.clone())
+            .collect();
+
+        let dropped = dropped.into_iter().map(|k| {
+            let header = keys.remove(name, &k).unwrap();
+            (k, header)
+        }).collect();
+
+        if keys.keys.is_empty() {
+            self.buckets.remove(name);
+        }
+        dropped
+    }
+
+    pub fn bucket_names(&self) -> impl Iterator<Item=&Vec<u8>> {
+        self.buckets.keys()
+    }
+
+    /// Number of keys in the default keyspace and all buckets.
+    pub fn len(&self) -> usize {
+        self.keys.len() + self.buckets.values().map(|bucket| bucket.keys.len()).sum::<usize>()
+    }
+
+    /// Iterates the default keyspace and the buckets with their names.
+    pub fn keyspaces(&self) -> impl Iterator<Item=(Option<&[u8]>, &KeyMap)> {
+        std::iter::once((None, &self.keys))
+            .chain(self.buckets.iter().map(|(name, bucket)| (Some(name.as_slice()), &bucket.keys)))
+    }
+}
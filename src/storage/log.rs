@@ -1,8 +1,13 @@
 // [crc|ts_tamp|ksz|vsz|key|val]
+// The highest byte of ksz keeps the record flags, so key size is limited to 24 bits.
+// If FLAG_EXPIRES is set, val starts with the expiry time of the key: [expires_at|stored value]
+// Deletes are only recognized by FLAG_TOMBSTONE. Files written before the flag existed mark deletes
+// with the value TOMBSTONE_MARKER_CHAR alone, which is read as a value like those versions did.
 
-use std::{fs, io, str};
+use std::{fs, io};
+use std::borrow::Cow;
 use std::fmt::{Debug, Formatter};
-use std::io::{ErrorKind, Read, Seek};
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom};
 use std::mem::size_of;
 
 use anyhow::Error;
@@ -19,121 +24,214 @@ pub const KEY_SIZE_OFFSET: usize = CRC_SIZE + TS_SIZE;
 pub const VAL_SIZE_OFFSET: usize = KEY_SIZE_OFFSET + KEY_SIZE;
 pub const KEY_OFFSET: usize = VAL_SIZE_OFFSET + VAL_SIZE;
 
+pub const FLAGS_SHIFT: u32 = 24;
+pub const MAX_KEY_SIZE: usize = (1 << FLAGS_SHIFT) - 1;
+
+pub const FLAG_LZ4: u8 = 0b01;
+pub const FLAG_ZSTD: u8 = 0b10;
+pub const FLAG_COMPRESSION_MASK: u8 = 0b11;
+pub const FLAG_ENCRYPTED: u8 = 0b100;
+pub const FLAG_TOMBSTONE: u8 = 0b1000;
+pub const FLAG_BUCKET: u8 = 0b1_0000;
+pub const FLAG_BUCKET_DROP: u8 = 0b10_0000;
+pub const FLAG_EXPIRES: u8 = 0b100_0000;
+
+pub const EXPIRES_AT_SIZE: usize = size_of::<u64>();
+
 // use backspace char as tombstone marker
 pub const TOMBSTONE_MARKER_CHAR: u8 = 8;
 
+/// A record read from a data file. `val` is the stored value, it can be compressed or encrypted.
 pub struct LogEntry {
-    // pub crc: [u8; CRC_SIZE],
-    pub crc: Vec<u8>,
-    // TODO: [u8;4] gibi kullanabiliriz.
-    pub ts_tamp: u32,
-    pub val_size: u32,
-    pub val_offset: u32,
     pub key: Vec<u8>,
     pub val: Vec<u8>,
+    pub header: Header,
 }
 
 impl Debug for LogEntry {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "LogEntry<key={}, val={}>", str::from_utf8(&self.key).unwrap(), str::from_utf8(&self.val).unwrap())
+        write!(f, "LogEntry<key={}, val={}>", String::from_utf8_lossy(&self.key), String::from_utf8_lossy(&self.val))
     }
 }
 
 pub struct LogIterator {
-    file: fs::File,
+    reader: BufReader<fs::File>,
     file_id: u64,
+    file_size: u64,
+    position: u64,
+    done: bool,
 }
 
 impl LogIterator {
     pub fn new(file_id: u64, file: fs::File) -> Self {
-        Self { file_id, file }
+        let file_size = file.metadata().map(|m| m.len()).unwrap_or(u64::MAX);
+        Self { reader: BufReader::new(file), file_id, file_size, position: 0, done: false }
+    }
+
+    /// Starts from the record at `offset`.
+    pub fn with_offset(file_id: u64, mut file: fs::File, offset: u64) -> io::Result<Self> {
+        file.seek(SeekFrom::Start(offset))?;
+        let mut iter = Self::new(file_id, file);
+        iter.position = offset;
+        Ok(iter)
     }
 
+    /// Fills the buffer and returns consumed byte count, which is less than the buffer size only at EOF.
     fn read_to(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
-        match self.file.read(buf) {
-            Ok(size) => Ok(size),
-            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(0),
-            Err(e) => Err(e),
+        let mut consumed = 0;
+        while consumed < buf.len() {
+            match self.reader.read(&mut buf[consumed..]) {
+                Ok(0) => break,
+                Ok(size) => consumed += size,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
         }
+        Ok(consumed)
+    }
+
+    fn corrupt(&mut self, offset: u64) -> Option<anyhow::Result<LogEntry>> {
+        self.done = true;
+        Some(Err(crate::Error::Corrupt { file_id: self.file_id, offset }.into()))
+    }
+
+    fn failed(&mut self, e: io::Error) -> Option<anyhow::Result<LogEntry>> {
+        self.done = true;
+        Some(Err(Error::from(e)))
     }
 }
 
 impl Iterator for LogIterator {
-    type Item = anyhow::Result<(Vec<u8>, Header)>;
+    type Item = anyhow::Result<LogEntry>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        // TODO: crc check?
-        let mut crc = [0u8; CRC_SIZE];
-        let result = self.read_to(&mut crc);
-        if let Err(e) = result {
-            return Some(Err(Error::from(e)));
-        }
-
-        // CRC is first byte of the entry, so If 0 byte consumed, it means EOF
-        if result.unwrap() == 0 {
+        if self.done {
             return None;
         }
 
-        let mut timestamp = [0u8; TS_SIZE];
-        let result = self.read_to(&mut timestamp);
-        if let Err(e) = result {
-            return Some(Err(Error::from(e)));
+        let entry_start = self.position;
+        let mut entry_header = [0u8; KEY_OFFSET];
+        match self.read_to(&mut entry_header) {
+            Err(e) => return self.failed(e),
+            // CRC is first byte of the entry, so If 0 byte consumed, it means EOF
+            Ok(0) => return None,
+            Ok(size) if size < KEY_OFFSET => return self.corrupt(entry_start),
+            Ok(_) => {}
         }
 
-        let mut key_size_bytes = [0u8; KEY_SIZE];
-        let result = self.read_to(&mut key_size_bytes);
-        if let Err(e) = result {
-            return Some(Err(Error::from(e)));
-        }
+        let crc = u32::from_be_bytes(entry_header[CRC_OFFSET..CRC_OFFSET + CRC_SIZE].try_into().unwrap());
+        let timestamp = u32::from_be_bytes(entry_header[CRC_SIZE..KEY_SIZE_OFFSET].try_into().unwrap());
+        let key_size_field = u32::from_be_bytes(entry_header[KEY_SIZE_OFFSET..VAL_SIZE_OFFSET].try_into().unwrap());
+        let flags = (key_size_field >> FLAGS_SHIFT) as u8;
+        let key_size = (key_size_field & MAX_KEY_SIZE as u32) as usize;
+        let val_size = u32::from_be_bytes(entry_header[VAL_SIZE_OFFSET..KEY_OFFSET].try_into().unwrap());
 
-        let key_size = u32::from_be_bytes(key_size_bytes);
+        let entry_end = entry_start + (KEY_OFFSET + key_size) as u64 + val_size as u64;
+        if entry_end > self.file_size {
+            return self.corrupt(entry_start);
+        }
 
-        let mut val_size_bytes = [0u8; VAL_SIZE];
-        let result = self.read_to(&mut val_size_bytes);
-        if let Err(e) = result {
-            return Some(Err(Error::from(e)));
+        let mut body = vec![0u8; key_size + val_size as usize];
+        match self.read_to(&mut body) {
+            Err(e) => return self.failed(e),
+            Ok(size) if size < body.len() => return self.corrupt(entry_start),
+            Ok(_) => {}
         }
 
-        let val_size = u32::from_be_bytes(val_size_bytes);
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&entry_header[CRC_OFFSET + CRC_SIZE..]);
+        hasher.update(&body);
+        if hasher.finalize() != crc {
+            return self.corrupt(entry_start);
+        }
 
-        let mut key = vec![0u8; key_size as usize];
+        self.position = entry_end;
 
-        let result = self.read_to(&mut key);
-        if let Err(e) = result {
-            return Some(Err(Error::from(e)));
+        let entry = decode_entry(self.file_id, entry_start, timestamp, flags, key_size, body);
+        if entry.is_err() {
+            self.done = true;
         }
+        Some(entry)
+    }
+}
 
-        let stream_pos = match self.file.stream_position() {
-            Ok(pos) => { pos }
-            Err(e) => return Some(Err(Error::from(e))),
-        };
+/// Builds the entry from the record body `[key|val]`.
+fn decode_entry(file_id: u64, entry_start: u64, ts_tamp: u32, flags: u8, key_size: usize, mut body: Vec<u8>) -> anyhow::Result<LogEntry> {
+    let val_size = (body.len() - key_size) as u32;
+
+    let val_offset = u32::try_from(entry_start + (KEY_OFFSET + key_size) as u64)?;
+    let mut val = body.split_off(key_size);
+    let mut header = Header {
+        file_id,
+        ts_tamp,
+        val_size,
+        val_offset,
+        flags,
+        expires_at: 0,
+    };
+
+    if flags & FLAG_EXPIRES != 0 {
+        if val.len() < EXPIRES_AT_SIZE {
+            return Err(crate::Error::Corrupt { file_id, offset: entry_start }.into());
+        }
+        header.expires_at = u64::from_be_bytes(val[..EXPIRES_AT_SIZE].try_into().unwrap());
+        header.val_offset += EXPIRES_AT_SIZE as u32;
+        header.val_size -= EXPIRES_AT_SIZE as u32;
+        val.drain(..EXPIRES_AT_SIZE);
+    }
 
-        let val_offset = match u32::try_from(stream_pos) {
-            Ok(x) => { x }
-            Err(e) => {
-                return Some(Err(Error::from(e)));
-            }
-        };
+    Ok(LogEntry { key: body, val, header })
+}
 
-        let mut val = vec![0u8; val_size as usize];
-        let result = self.read_to(&mut val);
-        if let Err(e) = result {
-            return Some(Err(Error::from(e)));
-        }
+/// Decodes the record at `offset` of the file content without checking its crc.
+pub(crate) fn decode_record(file_id: u64, data: &[u8], offset: usize) -> anyhow::Result<LogEntry> {
+    let corrupt = || Error::from(crate::Error::Corrupt { file_id, offset: offset as u64 });
+    let record = &data[offset..];
+    let (flags, key_size, val_size) = read_sizes(record).ok_or_else(corrupt)?;
+    let end = (KEY_OFFSET + key_size).checked_add(val_size).filter(|end| *end <= record.len()).ok_or_else(corrupt)?;
+    let ts_tamp = u32::from_be_bytes(record[CRC_SIZE..KEY_SIZE_OFFSET].try_into().unwrap());
+    decode_entry(file_id, offset as u64, ts_tamp, flags, key_size, record[KEY_OFFSET..end].to_vec())
+}
 
-        Some(Ok((key, Header {
-            file_id: self.file_id,
-            ts_tamp: u32::from_be_bytes(timestamp),
-            val_size,
-            val_offset,
-        })))
+/// Returns the flags and the value written to the data file. If `expires_at` is set,
+/// the value is prefixed with it, so the key keeps its time to live after rebuild and merge.
+pub(crate) fn with_expiry(val: &[u8], flags: u8, expires_at: u64) -> (u8, Cow<'_, [u8]>) {
+    if expires_at == 0 {
+        return (flags, Cow::Borrowed(val));
     }
+
+    let mut prefixed = Vec::with_capacity(EXPIRES_AT_SIZE + val.len());
+    prefixed.extend_from_slice(&expires_at.to_be_bytes());
+    prefixed.extend_from_slice(val);
+    (flags | FLAG_EXPIRES, Cow::Owned(prefixed))
 }
 
-fn byte_to_header(buf: &[u8]) -> anyhow::Result<Header> {
-    todo!()
+/// Returns the offset of the first record at or after `from` which fits in `data` and has a valid crc.
+/// Used to find where readable records start again after a corrupt region.
+pub(crate) fn find_next_record(data: &[u8], from: usize) -> Option<usize> {
+    (from..data.len()).find(|offset| valid_record_size(&data[*offset..]).is_some())
 }
 
-fn byte_to_entry(buf: &[u8]) -> anyhow::Result<Header> {
-    todo!()
+/// Returns the size of the record at the start of `data` if it can be read by [LogIterator].
+pub(crate) fn valid_record_size(data: &[u8]) -> Option<usize> {
+    let (flags, key_size, val_size) = read_sizes(data)?;
+    // the highest flag bit is not used
+    if flags >> 7 != 0 || (flags & FLAG_EXPIRES != 0 && val_size < EXPIRES_AT_SIZE) {
+        return None;
+    }
+    let end = (KEY_OFFSET + key_size).checked_add(val_size).filter(|end| *end <= data.len())?;
+
+    let crc = u32::from_be_bytes(data[CRC_OFFSET..CRC_OFFSET + CRC_SIZE].try_into().unwrap());
+    (crc32fast::hash(&data[CRC_OFFSET + CRC_SIZE..end]) == crc).then_some(end)
+}
+
+/// Returns flags, key size and value size from the record header at the start of `data`, without checking the record.
+pub(crate) fn read_sizes(data: &[u8]) -> Option<(u8, usize, usize)> {
+    if data.len() < KEY_OFFSET {
+        return None;
+    }
+
+    let key_size_field = u32::from_be_bytes(data[KEY_SIZE_OFFSET..VAL_SIZE_OFFSET].try_into().unwrap());
+    let val_size = u32::from_be_bytes(data[VAL_SIZE_OFFSET..KEY_OFFSET].try_into().unwrap());
+    Some(((key_size_field >> FLAGS_SHIFT) as u8, (key_size_field & MAX_KEY_SIZE as u32) as usize, val_size as usize))
 }
@@ -1,6 +1,6 @@
 use std::cell::RefCell;
 use std::fs;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{Cursor, Read, Seek, SeekFrom, Take};
 use std::path::Path;
 
 use crate::storage::utils::{build_data_file_name, open_file_for_read};
@@ -26,5 +26,34 @@ impl LogReader {
     }
 }
 
+/// Reads a single value without loading it into memory.
+/// Compressed, encrypted or not flushed values are served from memory.
+pub struct ValueReader {
+    source: ValueSource,
+}
 
+enum ValueSource {
+    File(Take<fs::File>),
+    Memory(Cursor<Vec<u8>>),
+}
 
+impl ValueReader {
+    pub(crate) fn from_file<P>(dir: P, file_id: u64, offset: u32, size: u32) -> anyhow::Result<Self> where P: AsRef<Path> {
+        let mut file = open_file_for_read(dir, &build_data_file_name(file_id))?;
+        file.seek(SeekFrom::Start(offset as u64))?;
+        Ok(ValueReader { source: ValueSource::File(file.take(size as u64)) })
+    }
+
+    pub(crate) fn from_memory(val: Vec<u8>) -> Self {
+        ValueReader { source: ValueSource::Memory(Cursor::new(val)) }
+    }
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match &mut self.source {
+            ValueSource::File(f) => f.read(buf),
+            ValueSource::Memory(c) => c.read(buf),
+        }
+    }
+}
@@ -1,39 +1,154 @@
 use std::fs;
-use std::io::{stderr, Write};
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, stderr, Write};
 use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use anyhow::Context;
+use anyhow::{bail, Context};
 use bytes::BufMut;
 
-use crate::storage::{Config, Header, KeyDir, utils};
-use crate::storage::log::{CRC_OFFSET, CRC_SIZE, KEY_OFFSET, KEY_SIZE_OFFSET, TOMBSTONE_MARKER_CHAR, VAL_SIZE_OFFSET};
+use crate::Error;
+use crate::storage::{bucket, codec, Config, Header, KeyDir, utils};
+use crate::storage::bucket::DROP_MARKER_SIZE;
+use crate::storage::log::{CRC_OFFSET, CRC_SIZE, FLAG_BUCKET, FLAG_BUCKET_DROP, FLAG_TOMBSTONE, FLAGS_SHIFT, KEY_OFFSET, MAX_KEY_SIZE, TOMBSTONE_MARKER_CHAR, with_expiry};
+use crate::storage::rebuild::extract_data_file_ids;
+use crate::storage::stats::{FileStatsMap, record_delete, record_put, records_dead};
 use crate::storage::utils::{build_data_file_name, open_file_for_write};
+use crate::storage::watch::{Change, ChangeEvent, ChangeKind, Watchers};
+
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
 
 pub struct LogWriter<'a> {
     file_id: u64,
+    /// Shared copy of `file_id` for the merger, which must not touch the active file.
+    active_file_id: Arc<AtomicU64>,
     file: fs::File,
     position: u32,
+    /// Records which are not written to `file` yet. Only used when `write_buffer_size` is set.
+    buffer: Vec<u8>,
+    /// Position of the active file on disk. Bytes between `flushed` and `position` stay in `buffer`.
+    flushed: u32,
     conf: &'a Config,
     key_dir: Arc<RwLock<KeyDir>>,
-    // ctx: &'a WriteContext,
+    file_stats: Arc<RwLock<FileStatsMap>>,
+    watchers: Arc<Watchers>,
+    /// Changes which are published to the watchers once the active file is synced.
+    pending: Vec<Change>,
 }
 
 impl<'a> LogWriter<'a> {
-    pub fn new(conf: &'a Config, key_dir: Arc<RwLock<KeyDir>>) -> anyhow::Result<Self> {
-        let file_id = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    pub fn new(conf: &'a Config, key_dir: Arc<RwLock<KeyDir>>, file_stats: Arc<RwLock<FileStatsMap>>) -> anyhow::Result<Self> {
+        // never append to an existing file, it may be written by a previous writer in the same second
+        let last_file_id = extract_data_file_ids(&conf.path)?.last().unwrap_or_default();
+        let file_id = next_file_id(last_file_id);
         let file = open_file_for_write(&conf.path, &build_data_file_name(file_id))?;
-
-        Ok(LogWriter { file_id, file, conf, key_dir, position: 0 })
+        file_stats.write().unwrap().entry(file_id).or_default();
+
+        Ok(LogWriter {
+            file_id,
+            active_file_id: Arc::new(AtomicU64::new(file_id)),
+            file,
+            conf,
+            key_dir,
+            file_stats,
+            watchers: Default::default(),
+            pending: Vec::new(),
+            position: 0,
+            buffer: Vec::with_capacity(conf.write_buffer_size),
+            flushed: 0,
+        })
     }
 
     pub fn file_id(&self) -> u64 {
         self.file_id
     }
 
-    pub fn put(&mut self, key: &[u8], val: &[u8]) -> anyhow::Result<()> {
-        let header = self.write_content(key, val)?;
-        self.key_dir.write().unwrap().insert(key.to_vec(), header);
+    pub fn active_file_id(&self) -> Arc<AtomicU64> {
+        self.active_file_id.clone()
+    }
+
+    pub fn watchers(&self) -> Arc<Watchers> {
+        self.watchers.clone()
+    }
+
+    /// Writes buffered records to the active file and syncs it.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.flush_buffer()?;
+        self.file.sync_data()?;
+        self.publish();
+        Ok(())
+    }
+
+    /// Returns the value if it is still waiting in the write buffer.
+    pub fn read_buffered(&self, file_id: u64, offset: u32, size: u32) -> Option<Vec<u8>> {
+        if file_id != self.file_id || offset < self.flushed {
+            return None;
+        }
+
+        let start = (offset - self.flushed) as usize;
+        self.buffer.get(start..start + size as usize).map(<[u8]>::to_vec)
+    }
+
+    /// Writes the value of the key in the bucket, or in the default keyspace if `bucket` is `None`.
+    pub fn put(&mut self, bucket: Option<&[u8]>, key: &[u8], val: &[u8]) -> anyhow::Result<()> {
+        self.put_with_expiry(bucket, key, val, 0)
+    }
+
+    /// Writes the value with the expiry time of the key in milliseconds since epoch, `0` means no expiry.
+    pub fn put_with_expiry(&mut self, bucket: Option<&[u8]>, key: &[u8], val: &[u8], expires_at: u64) -> anyhow::Result<()> {
+        let record_key = bucket::record_key(bucket, key);
+        let (flags, val) = codec::encode_value(self.conf, &record_key, val)?;
+        let header = self.write_content(&record_key, &val, flags | bucket_flag(bucket), expires_at)?;
+        self.index(bucket, key, record_key.len(), header);
+        self.track(bucket, key, ChangeKind::Put, header.ts_tamp);
+
+        if self.position > self.conf.max_file_size {
+            self.new_active_file()?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes a value of `len` bytes from the reader without buffering it in memory.
+    /// Streamed values are stored without compression, and cannot be encrypted.
+    pub fn put_from_reader(&mut self, bucket: Option<&[u8]>, key: &[u8], reader: &mut impl Read, len: u32) -> anyhow::Result<()> {
+        let record_key = bucket::record_key(bucket, key);
+        let flags = bucket_flag(bucket);
+        check_entry_size(record_key.len(), len as usize)?;
+        if self.conf.encryption.is_some() {
+            bail!("streaming values are not supported when encryption is enabled");
+        }
+
+        let ts_tamp = utils::timestamp();
+        let entry_header = create_entry_header(&record_key, len, ts_tamp, flags);
+        self.rotate_if_full(entry_header.len() as u64 + len as u64, self.conf.max_file_size)?;
+
+        // streamed record is written to the file directly, so buffered records must be written first
+        self.flush_buffer()?;
+        let entry_start_pos = self.position;
+
+        let result = self.write_streamed(&entry_header, reader, len);
+        if let Err(e) = result {
+            // drop the partially written record, so the file stays readable
+            self.file.set_len(entry_start_pos as u64).context("partial record truncation failed")?;
+            return Err(e);
+        }
+
+        self.position += entry_header.len() as u32 + len;
+        self.flushed = self.position;
+        self.sync()?;
+
+        let header = Header {
+            file_id: self.file_id,
+            val_size: len,
+            val_offset: entry_start_pos + entry_header.len() as u32,
+            ts_tamp,
+            flags,
+            expires_at: 0,
+        };
+        self.index(bucket, key, record_key.len(), header);
+        self.track(bucket, key, ChangeKind::Put, ts_tamp);
 
         if self.position > self.conf.max_file_size {
             self.new_active_file()?;
@@ -42,13 +157,112 @@ impl<'a> LogWriter<'a> {
         Ok(())
     }
 
-    pub fn delete(&mut self, key: &[u8]) -> anyhow::Result<()> {
-        self.write_content(key, &[TOMBSTONE_MARKER_CHAR; 1]).context("key deletion failed")?;
-        self.key_dir.write().unwrap().remove(key);
+    fn write_streamed(&mut self, entry_header: &[u8], reader: &mut impl Read, len: u32) -> anyhow::Result<()> {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&entry_header[CRC_OFFSET + CRC_SIZE..]);
+        self.file.write_all(entry_header).context("file write failed")?;
+
+        let mut chunk = vec![0u8; STREAM_CHUNK_SIZE];
+        let mut remaining = len as usize;
+        while remaining > 0 {
+            let size = remaining.min(STREAM_CHUNK_SIZE);
+            reader.read_exact(&mut chunk[..size]).context("value reader returned less bytes than expected")?;
+            hasher.update(&chunk[..size]);
+            self.file.write_all(&chunk[..size]).context("file write failed")?;
+            remaining -= size;
+        }
+
+        // the record is invalid until its crc is patched, so if the process stops before, it is an
+        // incomplete record at the end of the newest file which is truncated on open
+        // the active file is opened in append mode, so crc is patched with another handle
+        let mut file = OpenOptions::new().write(true).open(self.conf.path.join(build_data_file_name(self.file_id)))?;
+        file.seek(SeekFrom::Start(self.position as u64 + CRC_OFFSET as u64))?;
+        file.write_all(&hasher.finalize().to_be_bytes())?;
+
+        Ok(())
+    }
+
+    pub fn delete(&mut self, bucket: Option<&[u8]>, key: &[u8]) -> anyhow::Result<()> {
+        self.delete_with(bucket, key, ChangeKind::Delete)
+    }
+
+    /// Deletes a key whose time to live is passed.
+    pub fn expire(&mut self, bucket: Option<&[u8]>, key: &[u8]) -> anyhow::Result<()> {
+        self.delete_with(bucket, key, ChangeKind::Expire)
+    }
+
+    fn delete_with(&mut self, bucket: Option<&[u8]>, key: &[u8], kind: ChangeKind) -> anyhow::Result<()> {
+        let record_key = bucket::record_key(bucket, key);
+        let flags = FLAG_TOMBSTONE | bucket_flag(bucket);
+        let tombstone = self.write_content(&record_key, &[TOMBSTONE_MARKER_CHAR; 1], flags, 0).context("key deletion failed")?;
+
+        let mut key_dir = self.key_dir.write().unwrap();
+        let removed = key_dir.remove(bucket, key);
+        record_delete(&mut self.file_stats.write().unwrap(), record_key.len(), &tombstone, removed.as_ref());
+        drop(key_dir);
+
+        self.track(bucket, key, kind, tombstone.ts_tamp);
         Ok(())
     }
 
-    fn write_content(&mut self, key: &[u8], val: &[u8]) -> anyhow::Result<Header> {
+    /// Writes a single drop marker instead of a tombstone per key, and removes the keys of the bucket
+    /// from the key directory. Records of the bucket are reclaimed by merge.
+    pub fn drop_bucket(&mut self, name: &[u8]) -> anyhow::Result<()> {
+        let record_key = bucket::record_key(Some(name), &[]);
+        // the marker points to its own position, so the file must not be rotated after it is encoded
+        self.rotate_if_full((KEY_OFFSET + record_key.len() + DROP_MARKER_SIZE) as u64, u32::MAX)?;
+        let marker = bucket::encode_drop_marker(self.file_id, self.position);
+        let header = self.write_content(&record_key, &marker, FLAG_BUCKET | FLAG_BUCKET_DROP, 0).context("bucket drop failed")?;
+
+        let mut key_dir = self.key_dir.write().unwrap();
+        let mut file_stats = self.file_stats.write().unwrap();
+        record_delete(&mut file_stats, record_key.len(), &header, None);
+        let dropped = key_dir.drop_bucket(name);
+        for (file_id, live) in dropped.iter().flat_map(|bucket| &bucket.live) {
+            records_dead(&mut file_stats, *file_id, live.bytes, live.keys);
+        }
+        drop(file_stats);
+        drop(key_dir);
+
+        // keys are freed after the locks are released
+        drop(dropped);
+        Ok(())
+    }
+
+    /// Queues the change for the watchers. Records are durable right after the write if `sync_on_put` is set,
+    /// otherwise changes wait until the active file is synced.
+    fn track(&mut self, bucket: Option<&[u8]>, key: &[u8], kind: ChangeKind, ts_tamp: u32) {
+        if self.watchers.is_empty() {
+            return;
+        }
+
+        self.pending.push(Change {
+            bucket: bucket.map(<[u8]>::to_vec),
+            event: ChangeEvent { kind, key: key.to_vec(), ts_tamp },
+        });
+        if self.conf.sync_on_put {
+            self.publish();
+        }
+    }
+
+    fn publish(&mut self) {
+        if !self.pending.is_empty() {
+            self.watchers.publish(self.pending.drain(..));
+        }
+    }
+
+    /// Points the key to the new record and updates the file counters.
+    fn index(&self, bucket: Option<&[u8]>, key: &[u8], record_key_size: usize, header: Header) {
+        let mut key_dir = self.key_dir.write().unwrap();
+        let replaced = key_dir.insert(bucket, key.to_vec(), header);
+        record_put(&mut self.file_stats.write().unwrap(), record_key_size, &header, replaced.as_ref());
+    }
+
+    fn write_content(&mut self, key: &[u8], val: &[u8], flags: u8, expires_at: u64) -> anyhow::Result<Header> {
+        let val_size = val.len() as u32;
+        let (flags, val) = with_expiry(val, flags, expires_at);
+        check_entry_size(key.len(), val.len())?;
+
         /*
         dbg!(CRC_SIZE);
         dbg!(TS_SIZE);
@@ -59,7 +273,8 @@ impl<'a> LogWriter<'a> {
         */
 
         let ts_tamp = utils::timestamp();
-        let entry_bytes = create_entry(key, val, ts_tamp);
+        let entry_bytes = create_entry(key, &val, ts_tamp, flags);
+        self.rotate_if_full(entry_bytes.len() as u64, u32::MAX)?;
         let entry_start_pos = self.position;
 
         self.write_to_file(&entry_bytes)?;
@@ -67,9 +282,11 @@ impl<'a> LogWriter<'a> {
 
         let header = Header {
             file_id: self.file_id,
-            val_size: val.len() as u32,
-            val_offset: entry_start_pos + (KEY_OFFSET + key.len()) as u32,
+            val_size,
+            val_offset: entry_start_pos + (KEY_OFFSET + key.len()) as u32 + (val.len() as u32 - val_size),
             ts_tamp,
+            flags,
+            expires_at,
         };
 
         /*if key == &[107, 95, 50] {
@@ -84,14 +301,34 @@ impl<'a> LogWriter<'a> {
     }
 
 
+    /// Starts a new active file if a record of `entry_size` bytes would end after `limit`.
+    /// Offsets are u32, so no file grows past `u32::MAX`.
+    fn rotate_if_full(&mut self, entry_size: u64, limit: u32) -> anyhow::Result<()> {
+        if self.position > 0 && self.position as u64 + entry_size > limit as u64 {
+            self.new_active_file()?;
+        }
+        Ok(())
+    }
+
     fn new_active_file(&mut self) -> anyhow::Result<()> {
-        let new_file_id = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        self.rotate_after(self.file_id)
+    }
+
+    /// Continues in a new active file whose id is greater than `file_id`, so files added
+    /// to the store up to `file_id` are older than the next writes.
+    pub fn rotate_after(&mut self, file_id: u64) -> anyhow::Result<()> {
+        let new_file_id = next_file_id(self.file_id.max(file_id));
         let new_filename = build_data_file_name(new_file_id);
 
+        self.flush_buffer()?;
         self.file.sync_all()?;
+        self.publish();
         self.file = open_file_for_write(&self.conf.path, &new_filename)?;
         self.file_id = new_file_id;
+        self.active_file_id.store(new_file_id, Ordering::Release);
         self.position = 0;
+        self.flushed = 0;
+        self.file_stats.write().unwrap().entry(new_file_id).or_default();
 
         Ok(())
     }
@@ -99,9 +336,12 @@ impl<'a> LogWriter<'a> {
 
     #[inline]
     fn sync(&mut self) -> anyhow::Result<()> {
-        // TODO: we can create flush_on_put config for flushing after puts.
-        // flushing content can be not needed for some use cases?
-        self.file.flush()?;
+        if self.conf.write_buffer_size > 0 && !self.conf.sync_on_put {
+            // buffered content is written when the buffer is full or on explicit flush
+            return Ok(());
+        }
+
+        self.flush_buffer()?;
         if self.conf.sync_on_put {
             self.file.sync_data()?;
         }
@@ -109,51 +349,95 @@ impl<'a> LogWriter<'a> {
     }
 
     fn write_to_file(&mut self, buf: &[u8]) -> anyhow::Result<()> {
-        self.file.write_all(buf).context("file write failed")?;
+        if self.conf.write_buffer_size == 0 {
+            self.file.write_all(buf).context("file write failed")?;
+            self.position += buf.len() as u32;
+            self.flushed = self.position;
+            return Ok(());
+        }
+
+        if self.buffer.len() + buf.len() > self.conf.write_buffer_size {
+            self.flush_buffer()?;
+        }
+
+        self.buffer.extend_from_slice(buf);
         self.position += buf.len() as u32;
 
         Ok(())
     }
+
+    fn flush_buffer(&mut self) -> anyhow::Result<()> {
+        if !self.buffer.is_empty() {
+            self.file.write_all(&self.buffer).context("file write failed")?;
+            self.buffer.clear();
+        }
+        self.file.flush()?;
+        self.flushed = self.position;
+
+        Ok(())
+    }
 }
 
 impl Drop for LogWriter<'_> {
     fn drop(&mut self) {
+        if let Err(e) = self.flush_buffer() {
+            write!(stderr(), "error while flushing write buffer: {:?}", e).expect("error writing to stderr");
+        }
+
         if let Err(e) = self.file.sync_all() {
             write!(stderr(), "error while closing active file: {:?}", e).expect("error writing to stderr");
+            return;
         }
+        self.publish();
     }
 }
 
-fn create_entry(key: &[u8], val: &[u8], ts_tamp: u32) -> Vec<u8> {
-    let mut payload = Vec::with_capacity(KEY_OFFSET + key.len() + val.len());
+#[inline]
+fn bucket_flag(bucket: Option<&[u8]>) -> u8 {
+    if bucket.is_some() { FLAG_BUCKET } else { 0 }
+}
+
+/// Offsets are u32, so a record must fit into the addressable part of a data file.
+pub(crate) fn check_entry_size(key_size: usize, val_size: usize) -> anyhow::Result<()> {
+    if key_size > MAX_KEY_SIZE {
+        return Err(Error::KeyTooLarge { size: key_size, limit: MAX_KEY_SIZE }.into());
+    }
+
+    let val_limit = u32::MAX as usize - KEY_OFFSET - key_size;
+    if val_size > val_limit {
+        return Err(Error::ValueTooLarge { size: val_size, limit: val_limit }.into());
+    }
+
+    Ok(())
+}
+
+/// File ids are creation timestamps. Returns a timestamp which is greater than `last_file_id`.
+pub(crate) fn next_file_id(last_file_id: u64) -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    now.max(last_file_id + 1)
+}
+
+/// Returns the record bytes before the value, with an empty crc.
+fn create_entry_header(key: &[u8], val_size: u32, ts_tamp: u32, flags: u8) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(KEY_OFFSET + key.len());
 
     payload.put_u32(0); // empty space for crc
     payload.put_u32(ts_tamp);
-    payload.put_u32(key.len() as u32);
-    payload.put_u32(val.len() as u32);
+    payload.put_u32(((flags as u32) << FLAGS_SHIFT) | key.len() as u32);
+    payload.put_u32(val_size);
     payload.put(key);
-    payload.put(val);
-
-    let checksum = crc32fast::hash(&payload[CRC_OFFSET + CRC_SIZE..]);
-    payload.splice(0..CRC_SIZE, checksum.to_be_bytes());
 
     payload
 }
 
+pub(crate) fn create_entry(key: &[u8], val: &[u8], ts_tamp: u32, flags: u8) -> Vec<u8> {
+    let mut payload = create_entry_header(key, val.len() as u32, ts_tamp, flags);
+    payload.put(val);
 
-fn debug_entry(payload: &[u8]) {
-    let key_size = u32::from_be_bytes(payload[KEY_SIZE_OFFSET..VAL_SIZE_OFFSET].try_into().unwrap()) as usize;
-    let val_size = u32::from_be_bytes(payload[VAL_SIZE_OFFSET..KEY_OFFSET].try_into().unwrap()) as usize;
-
-    let key = &payload[KEY_OFFSET..KEY_OFFSET + key_size];
+    let checksum = crc32fast::hash(&payload[CRC_OFFSET + CRC_SIZE..]);
+    payload.splice(0..CRC_SIZE, checksum.to_be_bytes());
 
-    let val_offset = KEY_OFFSET + key_size;
-    let val = &payload[val_offset..val_offset + val_size];
-    if val == [8] {
-        println!("DeleteEntry<key={}>", std::str::from_utf8(key).unwrap())
-    } else {
-        println!("PutEntry<key={}, val={}>", std::str::from_utf8(key).unwrap(), std::str::from_utf8(val).unwrap())
-    }
+    payload
 }
 
 
@@ -164,10 +448,12 @@ mod test {
 
     use tempdir::TempDir;
 
-    use crate::storage::{Config, utils};
+    use crate::storage::{codec, compression, Compression, Config, Encryption, utils};
+    use crate::storage::log::{KEY_SIZE_OFFSET, VAL_SIZE_OFFSET};
     use crate::storage::log_reader::LogReader;
+    use crate::storage::rebuild::rebuild_storage;
 
-    use super::{CRC_OFFSET, CRC_SIZE, KEY_OFFSET, KEY_SIZE_OFFSET, LogWriter, VAL_SIZE_OFFSET};
+    use super::{CRC_OFFSET, CRC_SIZE, KEY_OFFSET, LogWriter};
 
     #[test]
     fn it_should_create_new_log() {
@@ -176,7 +462,7 @@ mod test {
             ..Default::default()
         };
 
-        let writer = LogWriter::new(&conf, Default::default()).unwrap();
+        let writer = LogWriter::new(&conf, Default::default(), Default::default()).unwrap();
 
         assert_eq!(0, writer.position);
         assert_ne!(0, writer.file_id);
@@ -192,13 +478,13 @@ mod test {
             ..Default::default()
         };
 
-        let mut writer = LogWriter::new(&conf, Default::default()).unwrap();
+        let mut writer = LogWriter::new(&conf, Default::default(), Default::default()).unwrap();
 
         let key = b"foo";
         let val = b"bar";
 
         // when
-        writer.put(key, val).unwrap();
+        writer.put(None, key, val).unwrap();
 
         // then
         let mut payload = vec![0; KEY_OFFSET + key.len() + val.len()];
@@ -223,11 +509,11 @@ mod test {
         assert_eq!(payload[val_offset..val_offset + val.len()], *val.as_slice());
 
         let key_dir = writer.key_dir.read().unwrap();
-        let header = key_dir.get(key.as_slice()).unwrap();
+        let header = key_dir.get(None, key.as_slice()).unwrap();
         assert_eq!(header.file_id, writer.file_id);
         assert_eq!(header.val_size, val.len() as u32);
-        assert_eq!(header.val_offset, (KEY_OFFSET + key.len()).try_into().unwrap());
-        assert_eq!(writer.position, (KEY_OFFSET + key.len() + val.len()).try_into().unwrap());
+        assert_eq!(header.val_offset, u32::try_from(KEY_OFFSET + key.len()).unwrap());
+        assert_eq!(writer.position, u32::try_from(KEY_OFFSET + key.len() + val.len()).unwrap());
     }
 
 
@@ -241,7 +527,7 @@ mod test {
         };
 
         let key_dir = Arc::new(RwLock::new(Default::default()));
-        let mut writer = LogWriter::new(&conf, key_dir.clone()).unwrap();
+        let mut writer = LogWriter::new(&conf, key_dir.clone(), Default::default()).unwrap();
         let reader = LogReader::new(&conf.path, writer.file_id).unwrap();
 
         let pairs: Vec<(&[u8], &[u8])> = vec![
@@ -251,9 +537,9 @@ mod test {
         ];
 
         for (key, val) in pairs {
-            writer.put(key, val).unwrap();
+            writer.put(None, key, val).unwrap();
             let key_dir_guard = key_dir.read().unwrap();
-            let actual = reader.read(key_dir_guard.get(key).unwrap().val_offset, key_dir_guard.get(key).unwrap().val_size).unwrap();
+            let actual = reader.read(key_dir_guard.get(None, key).unwrap().val_offset, key_dir_guard.get(None, key).unwrap().val_size).unwrap();
             assert_eq!(val, actual.as_slice());
         }
     }
@@ -271,18 +557,121 @@ mod test {
         };
 
         let key_dir = Arc::new(RwLock::new(Default::default()));
-        let mut writer = LogWriter::new(&conf, key_dir.clone()).unwrap();
-        let reader = LogReader::new(&conf.path, writer.file_id).unwrap();
+        let mut writer = LogWriter::new(&conf, key_dir.clone(), Default::default()).unwrap();
+        let _reader = LogReader::new(&conf.path, writer.file_id).unwrap();
 
         let key = b"k1";
 
         // when
-        writer.put(key, b"val1").unwrap();
+        writer.put(None, key, b"val1").unwrap();
 
         // then
-        assert!(writer.delete(key).is_ok());
+        assert!(writer.delete(None, key).is_ok());
 
         let key_dir_guard = key_dir.read().unwrap();
-        assert!(key_dir_guard.get(key.as_slice()).is_none());
+        assert!(key_dir_guard.get(None, key.as_slice()).is_none());
     }
-}
\ No newline at end of file
+
+
+    #[test]
+    fn it_should_buffer_writes_until_flush() {
+        // given
+        let dir = TempDir::new("bitcask-").unwrap().into_path();
+        let conf = Config {
+            path: dir.clone(),
+            write_buffer_size: 1024,
+            ..Default::default()
+        };
+
+        let key_dir = Arc::new(RwLock::new(Default::default()));
+        let mut writer = LogWriter::new(&conf, key_dir.clone(), Default::default()).unwrap();
+        let filename = format!("{}.bitcask.data", writer.file_id);
+
+        // when
+        writer.put(None, b"foo", b"bar").unwrap();
+
+        // then
+        assert_eq!(0, std::fs::metadata(dir.join(&filename)).unwrap().len());
+
+        let (offset, size) = {
+            let key_dir_guard = key_dir.read().unwrap();
+            let header = key_dir_guard.get(None, b"foo".as_slice()).unwrap();
+            (header.val_offset, header.val_size)
+        };
+        assert_eq!(Some(b"bar".to_vec()), writer.read_buffered(writer.file_id, offset, size));
+
+        writer.flush().unwrap();
+
+        assert_eq!(writer.position as u64, std::fs::metadata(dir.join(&filename)).unwrap().len());
+        assert!(writer.read_buffered(writer.file_id, offset, size).is_none());
+
+        let reader = LogReader::new(&conf.path, writer.file_id).unwrap();
+        assert_eq!(b"bar".to_vec(), reader.read(offset, size).unwrap());
+    }
+
+    #[test]
+    fn it_should_read_mixed_compressed_files() {
+        // given
+        let dir = TempDir::new("bitcask-").unwrap().into_path();
+        let raw_conf = Config { path: dir.clone(), ..Default::default() };
+        let lz4_conf = Config { path: dir.clone(), compression: Compression::Lz4, ..Default::default() };
+        let val = b"{\"name\":\"fakir\",\"name\":\"fakir\",\"name\":\"fakir\"}".to_vec();
+
+        // when
+        let raw_file_id = {
+            let mut writer = LogWriter::new(&raw_conf, Default::default(), Default::default()).unwrap();
+            writer.put(None, b"raw", &val).unwrap();
+            writer.file_id
+        };
+        let lz4_file_id = {
+            let mut writer = LogWriter::new(&lz4_conf, Default::default(), Default::default()).unwrap();
+            writer.put(None, b"lz4", &val).unwrap();
+            writer.file_id
+        };
+
+        // then
+        assert!(lz4_file_id > raw_file_id);
+
+        let (key_dir, _) = rebuild_storage(&dir).unwrap();
+        for (key, file_id) in [(b"raw", raw_file_id), (b"lz4", lz4_file_id)] {
+            let header = key_dir.get(None, key.as_slice()).unwrap();
+            assert_eq!(file_id, header.file_id);
+
+            let stored = LogReader::new(&dir, header.file_id).unwrap().read(header.val_offset, header.val_size).unwrap();
+            assert_eq!(val, compression::decompress(header.flags, stored).unwrap());
+        }
+
+        assert!(key_dir.get(None, b"lz4".as_slice()).unwrap().val_size < val.len() as u32);
+    }
+
+    #[test]
+    fn it_should_encrypt_values() {
+        // given
+        let dir = TempDir::new("bitcask-").unwrap().into_path();
+        let conf = Config {
+            path: dir.clone(),
+            compression: Compression::Zstd(3),
+            encryption: Some(Encryption::new(1, [42; 32])),
+            ..Default::default()
+        };
+
+        let key_dir = Arc::new(RwLock::new(Default::default()));
+        let mut writer = LogWriter::new(&conf, key_dir.clone(), Default::default()).unwrap();
+        let val = b"customer@example.com ".repeat(8);
+
+        // when
+        writer.put(None, b"k1", &val).unwrap();
+
+        // then
+        let content = std::fs::read(dir.join(format!("{}.bitcask.data", writer.file_id))).unwrap();
+        assert!(!content.windows(b"customer".len()).any(|w| w == b"customer"));
+
+        let key_dir_guard = key_dir.read().unwrap();
+        let header = key_dir_guard.get(None, b"k1".as_slice()).unwrap();
+        let stored = LogReader::new(&dir, writer.file_id).unwrap().read(header.val_offset, header.val_size).unwrap();
+        assert_eq!(val, codec::decode_value(&conf, b"k1", header.flags, stored.clone()).unwrap());
+
+        let without_key = Config { path: dir.clone(), ..Default::default() };
+        assert!(codec::decode_value(&without_key, b"k1", header.flags, stored).is_err());
+    }
+}
@@ -0,0 +1,681 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context};
+use log::{debug, error, info};
+use serde::Serialize;
+
+use crate::storage::{bucket, Config, Header, KeyDir, utils};
+use crate::storage::hint::remove_hint;
+use crate::storage::log::{FLAG_BUCKET, FLAG_ENCRYPTED, FLAG_TOMBSTONE, KEY_OFFSET, LogEntry, LogIterator, TOMBSTONE_MARKER_CHAR, with_expiry};
+use crate::storage::log_writer::create_entry;
+use crate::storage::rate_limit::RateLimiter;
+use crate::storage::rebuild::extract_data_file_ids;
+use crate::storage::stats::{FileStats, FileStatsMap, record_size};
+use crate::storage::utils::{build_data_file_name, open_file_for_read, sync_dir};
+use crate::storage::watch::{Change, ChangeEvent, ChangeKind, Watchers};
+
+/// Conditions for the background merger. A merge starts when enough files are eligible,
+/// and the current time is in the merge window.
+#[derive(Debug, Clone)]
+pub struct MergePolicy {
+    /// A file is eligible for merge when the ratio of its dead bytes reaches this value.
+    pub dead_ratio: f64,
+    /// All files which have dead bytes are eligible when total dead bytes of the store reaches this value.
+    pub dead_bytes: u64,
+    /// Minimum number of eligible files to start a merge.
+    pub min_files: usize,
+    /// Merges only run between these hours of the day(UTC). Start can be greater than end, e.g. `(22, 4)`.
+    pub window: Option<(u8, u8)>,
+    /// How often the background merger checks the policy.
+    pub check_interval: Duration,
+}
+
+impl Default for MergePolicy {
+    fn default() -> Self {
+        Self {
+            dead_ratio: 0.5,
+            dead_bytes: 512 << 20, // 512MB
+            min_files: 1,
+            window: None,
+            check_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+impl MergePolicy {
+    /// Returns ids of the files which should be merged now. Active file is never selected.
+    pub(crate) fn select(&self, files: &FileStatsMap, active_file_id: u64, hour: u8) -> Vec<u64> {
+        if !self.in_window(hour) {
+            return vec![];
+        }
+
+        let immutable = files.iter().filter(|(id, _)| **id != active_file_id);
+        let total_dead: u64 = immutable.clone().map(|(_, f)| f.dead_bytes).sum();
+
+        let eligible: Vec<u64> = immutable
+            .filter(|(_, f)| f.dead_bytes > 0)
+            .filter(|(_, f)| total_dead >= self.dead_bytes || f.dead_ratio() >= self.dead_ratio)
+            .map(|(id, _)| *id)
+            .collect();
+
+        if eligible.is_empty() || eligible.len() < self.min_files {
+            return vec![];
+        }
+
+        eligible
+    }
+
+    fn in_window(&self, hour: u8) -> bool {
+        match self.window {
+            None => true,
+            Some((start, end)) if start <= end => hour >= start && hour < end,
+            Some((start, end)) => hour >= start || hour < end,
+        }
+    }
+}
+
+/// Result of a merge.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct MergeReport {
+    /// Merged file ids. They are replaced by `file_id`.
+    pub files: Vec<u64>,
+    pub file_id: u64,
+    pub reclaimed_bytes: u64,
+}
+
+/// A record copied to the merge output. Key directory is pointed to `new` if it still points to `old`.
+/// Keys are the record keys, which include the bucket name.
+struct Moved {
+    key: Vec<u8>,
+    old: Header,
+    new: Header,
+}
+
+/// Output of the copy phase of a merge.
+struct Copied {
+    moved: Vec<Moved>,
+    /// Expired records, they are removed from the key directory if they are still live.
+    reaped: Vec<(Vec<u8>, Header)>,
+    output_size: u64,
+}
+
+/// Compacts immutable data files by copying live records into a new file.
+///
+/// Merge runs beside the writer: records are copied without locking the key directory,
+/// then headers are swapped only for the keys which are not overwritten in the meantime.
+pub(crate) struct Merger {
+    conf: Config,
+    key_dir: Arc<RwLock<KeyDir>>,
+    file_stats: Arc<RwLock<FileStatsMap>>,
+    active_file_id: Arc<AtomicU64>,
+    rate_limiter: Arc<RateLimiter>,
+    watchers: Arc<Watchers>,
+    /// Incremented after each merge, readers drop cached file handles when it changes.
+    epoch: AtomicU64,
+    running: Mutex<()>,
+}
+
+impl Merger {
+    pub fn new(conf: Config, key_dir: Arc<RwLock<KeyDir>>, file_stats: Arc<RwLock<FileStatsMap>>,
+               active_file_id: Arc<AtomicU64>, rate_limiter: Arc<RateLimiter>, watchers: Arc<Watchers>) -> Self {
+        Self { conf, key_dir, file_stats, active_file_id, rate_limiter, watchers, epoch: AtomicU64::new(0), running: Mutex::new(()) }
+    }
+
+    /// Blocks merges until the guard is dropped, so data files are not replaced.
+    pub fn pause(&self) -> MutexGuard<'_, ()> {
+        self.running.lock().unwrap()
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::Acquire)
+    }
+
+    /// Merges all immutable files.
+    pub fn merge_all(&self) -> anyhow::Result<MergeReport> {
+        let active_file_id = self.active_file_id.load(Ordering::Acquire);
+        let file_ids: Vec<u64> = extract_data_file_ids(&self.conf.path)?
+            .filter(|id| *id < active_file_id)
+            .collect();
+        self.merge(&file_ids)
+    }
+
+    /// Merges files selected by the policy. Returns `None` if no file is eligible.
+    pub fn merge_by_policy(&self, policy: &MergePolicy) -> anyhow::Result<Option<MergeReport>> {
+        let hour = (SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() / 3600 % 24) as u8;
+        let file_ids = {
+            let files = self.file_stats.read().unwrap();
+            policy.select(&files, self.active_file_id.load(Ordering::Acquire), hour)
+        };
+
+        if file_ids.is_empty() {
+            return Ok(None);
+        }
+
+        self.merge(&file_ids).map(Some)
+    }
+
+    pub fn merge(&self, file_ids: &[u64]) -> anyhow::Result<MergeReport> {
+        let _running = self.running.lock().unwrap();
+
+        let merged: BTreeSet<u64> = file_ids.iter().copied().collect();
+        let output_id = match merged.last() {
+            None => return Ok(MergeReport::default()),
+            Some(id) => *id,
+        };
+
+        if output_id >= self.active_file_id.load(Ordering::Acquire) {
+            bail!("active file cannot be merged");
+        }
+
+        // tombstones must be kept if an older file which is not merged may have the key
+        let all_files: Vec<u64> = extract_data_file_ids(&self.conf.path)?.collect();
+        let oldest_kept = all_files.iter().find(|id| !merged.contains(id)).copied();
+
+        let tmp_path = self.conf.path.join(build_merge_file_name(output_id));
+        let result = self.copy_live_records(&merged, output_id, oldest_kept, &tmp_path)
+            .and_then(|copied| {
+                write_manifest(&self.conf.path, output_id, &merged)?;
+                self.commit(&merged, output_id, &tmp_path, copied)
+            });
+
+        // after the output is renamed, the manifest is kept for recover_merge
+        if result.is_err() && tmp_path.exists() {
+            let _ = fs::remove_file(&tmp_path);
+            let _ = fs::remove_file(self.conf.path.join(MERGE_MANIFEST));
+        }
+
+        result
+    }
+
+    /// Copies live records of the merged files into the merge output.
+    fn copy_live_records(&self, merged: &BTreeSet<u64>, output_id: u64, oldest_kept: Option<u64>, tmp_path: &Path) -> anyhow::Result<Copied> {
+        let mut out = Output::create(tmp_path, self.rate_limiter.clone())?;
+        let mut moved = Vec::new();
+        let mut reaped = Vec::new();
+        let mut tombstones = HashSet::new();
+
+        for file_id in merged {
+            let keep_tombstones = oldest_kept.is_some_and(|id| id < *file_id);
+            let file = open_file_for_read(&self.conf.path, &build_data_file_name(*file_id))?;
+
+            for entry in LogIterator::new(*file_id, file) {
+                let LogEntry { key, val, header } = entry?;
+                self.rate_limiter.acquire(record_size(key.len(), header.stored_size()));
+                let (bucket, bucket_key) = bucket::split_record_key(header.flags, &key)?;
+
+                if header.is_bucket_drop() {
+                    // marker keeps its original position, so it only drops the records written before it
+                    if keep_tombstones {
+                        out.write_entry(output_id, &key, &val, header.ts_tamp, header.flags, 0)?;
+                    }
+                    continue;
+                }
+
+                if header.is_tombstone() {
+                    if keep_tombstones && !tombstones.contains(&key) && !self.key_dir.read().unwrap().contains_key(bucket, bucket_key) {
+                        out.write_tombstone(&key, header.ts_tamp, header.flags)?;
+                        tombstones.insert(key);
+                    }
+                    continue;
+                }
+
+                if !self.is_live(bucket, bucket_key, &header) {
+                    continue;
+                }
+
+                if header.is_expired(utils::expiry_time(self.conf.expiry_secs_of(bucket)), utils::now_millis()) {
+                    if keep_tombstones && tombstones.insert(key.clone()) {
+                        out.write_tombstone(&key, header.ts_tamp, header.flags)?;
+                    }
+                    reaped.push((key, header));
+                    continue;
+                }
+
+                let val = self.rotate_encryption(&key, header.flags, val)?;
+                let new = out.write_entry(output_id, &key, &val, header.ts_tamp, header.flags, header.expires_at)?;
+                moved.push(Moved { key, old: header, new });
+            }
+        }
+
+        out.finish()?;
+        Ok(Copied { moved, reaped, output_size: out.position })
+    }
+
+    /// Replaces the merged files with the merge output and points the key directory to it.
+    ///
+    /// Rename of the output is the commit point. If the process crashes after it, merged files
+    /// listed in the manifest are removed on the next open, see [recover_merge].
+    fn commit(&self, merged: &BTreeSet<u64>, output_id: u64, tmp_path: &Path, copied: Copied) -> anyhow::Result<MergeReport> {
+        let Copied { moved, reaped, output_size } = copied;
+        let mut key_dir = self.key_dir.write().unwrap();
+        let mut file_stats = self.file_stats.write().unwrap();
+
+        // hints of the merged files do not match the output
+        for id in merged {
+            remove_hint(&self.conf.path, *id)?;
+        }
+        fs::rename(tmp_path, self.conf.path.join(build_data_file_name(output_id))).context("merge output rename failed")?;
+        sync_dir(&self.conf.path)?;
+
+        let mut output_stats = FileStats { total_bytes: output_size, ..Default::default() };
+        for Moved { key, old, new } in moved {
+            let (bucket, bucket_key) = bucket::split_record_key(old.flags, &key)?;
+            if key_dir.relocate(bucket, bucket_key, &old, new) {
+                output_stats.live_bytes += record_size(key.len(), new.stored_size());
+                output_stats.live_keys += 1;
+            }
+        }
+
+        let mut expired = Vec::new();
+        let now = utils::timestamp();
+        for (key, old) in reaped {
+            let (bucket, bucket_key) = bucket::split_record_key(old.flags, &key)?;
+            if key_dir.get(bucket, bucket_key).is_some_and(|current| current.file_id == old.file_id && current.val_offset == old.val_offset) {
+                key_dir.remove(bucket, bucket_key);
+                if !self.watchers.is_empty() {
+                    expired.push(Change {
+                        bucket: bucket.map(<[u8]>::to_vec),
+                        event: ChangeEvent { kind: ChangeKind::Expire, key: bucket_key.to_vec(), ts_tamp: now },
+                    });
+                }
+            }
+        }
+
+        output_stats.dead_bytes = output_stats.total_bytes - output_stats.live_bytes;
+        let before: u64 = merged.iter().filter_map(|id| file_stats.get(id)).map(|f| f.total_bytes).sum();
+        for id in merged {
+            file_stats.remove(id);
+        }
+        file_stats.insert(output_id, output_stats);
+
+        self.epoch.fetch_add(1, Ordering::AcqRel);
+        drop(file_stats);
+        drop(key_dir);
+
+        remove_merged_files(&self.conf.path, output_id, merged.iter().copied())?;
+        self.watchers.publish(expired);
+
+        let report = MergeReport {
+            files: merged.iter().copied().collect(),
+            file_id: output_id,
+            reclaimed_bytes: before.saturating_sub(output_size),
+        };
+        info!("merge completed: {:?}", report);
+
+        Ok(report)
+    }
+
+    fn is_live(&self, bucket: Option<&[u8]>, key: &[u8], header: &Header) -> bool {
+        self.key_dir.read().unwrap().get(bucket, key)
+            .is_some_and(|current| current.file_id == header.file_id && current.val_offset == header.val_offset)
+    }
+
+    /// Seals the value with the current key if it is sealed with a retired key.
+    fn rotate_encryption(&self, key: &[u8], flags: u8, val: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        match &self.conf.encryption {
+            Some(enc) if flags & FLAG_ENCRYPTED != 0 && enc.needs_rotation(&val) => {
+                enc.seal(key, &enc.open(key, &val)?)
+            }
+            _ => Ok(val),
+        }
+    }
+}
+
+/// Runs merges selected by the policy until a message is received from `stop`.
+pub(crate) fn run_background_merger(merger: Arc<Merger>, policy: MergePolicy, stop: Receiver<()>) {
+    while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(policy.check_interval) {
+        match merger.merge_by_policy(&policy) {
+            Ok(None) => debug!("no files to merge"),
+            Ok(Some(_)) => {}
+            Err(e) => error!("background merge failed: {:?}", e),
+        }
+    }
+}
+
+pub(crate) fn build_merge_file_name(file_id: u64) -> String {
+    format!("{file_id}.bitcask.data.merge")
+}
+
+pub(crate) const MERGE_MANIFEST: &str = "merge.manifest";
+
+/// Writes the output file id and the merged file ids before the merge output is renamed.
+pub(crate) fn write_manifest(dir: &Path, output_id: u64, merged: &BTreeSet<u64>) -> anyhow::Result<()> {
+    let mut content = format!("{output_id}\n");
+    for id in merged {
+        content.push_str(&format!("{id}\n"));
+    }
+
+    let mut file = fs::File::create(dir.join(MERGE_MANIFEST))?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    sync_dir(dir)
+}
+
+/// Returns the output file id and the merged file ids of the running merge, if any.
+pub(crate) fn read_manifest(dir: &Path) -> anyhow::Result<Option<(u64, Vec<u64>)>> {
+    let content = match fs::read_to_string(dir.join(MERGE_MANIFEST)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut ids = content.lines().map_while(|l| l.parse().ok());
+    Ok(ids.next().map(|output_id| (output_id, ids.collect())))
+}
+
+/// Returns true if the merge output is renamed, which is the commit point of the merge.
+/// The output is renamed only after the manifest is completely written.
+pub(crate) fn is_committed(dir: &Path, output_id: u64) -> bool {
+    !dir.join(build_merge_file_name(output_id)).exists()
+}
+
+/// Completes or rolls back a merge which is interrupted by a crash. Must be called before
+/// the key directory is rebuilt.
+///
+/// If the merge output is not renamed yet, the merged files are intact and the output is dropped.
+/// Otherwise the output replaces them, so the remaining merged files are removed. Keeping them
+/// could bring back the keys whose tombstones are dropped by the merge.
+pub(crate) fn recover_merge(dir: &Path) -> anyhow::Result<()> {
+    if let Some((output_id, merged)) = read_manifest(dir)? {
+        if is_committed(dir, output_id) {
+            info!("completing interrupted merge: {} {:?}", output_id, merged);
+            remove_merged_files(dir, output_id, merged.into_iter())?;
+        } else {
+            info!("rolling back interrupted merge");
+            fs::remove_file(dir.join(MERGE_MANIFEST))?;
+        }
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "merge") {
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn remove_merged_files(dir: &Path, output_id: u64, merged: impl Iterator<Item=u64>) -> anyhow::Result<()> {
+    for id in merged.filter(|id| *id != output_id) {
+        match fs::remove_file(dir.join(build_data_file_name(id))) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e).context("merged file removal failed"),
+            _ => {}
+        }
+        remove_hint(dir, id)?;
+    }
+    sync_dir(dir)?;
+
+    match fs::remove_file(dir.join(MERGE_MANIFEST)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+struct Output {
+    writer: BufWriter<fs::File>,
+    position: u64,
+    rate_limiter: Arc<RateLimiter>,
+}
+
+impl Output {
+    fn create(path: &Path, rate_limiter: Arc<RateLimiter>) -> anyhow::Result<Self> {
+        let file = fs::OpenOptions::new().write(true).create(true).truncate(true).open(path)?;
+        Ok(Self { writer: BufWriter::new(file), position: 0, rate_limiter })
+    }
+
+    fn write_entry(&mut self, file_id: u64, key: &[u8], val: &[u8], ts_tamp: u32, flags: u8, expires_at: u64) -> anyhow::Result<Header> {
+        let val_size = val.len() as u32;
+        let (flags, val) = with_expiry(val, flags, expires_at);
+        let entry = create_entry(key, &val, ts_tamp, flags);
+        let val_offset = u32::try_from(self.position + (KEY_OFFSET + key.len() + val.len()) as u64 - val_size as u64)
+            .context("merge output is too large")?;
+
+        self.rate_limiter.acquire(entry.len() as u64);
+        self.writer.write_all(&entry)?;
+        self.position += entry.len() as u64;
+
+        Ok(Header { file_id, val_size, val_offset, ts_tamp, flags, expires_at })
+    }
+
+    /// Writes a tombstone of the record key. Only the bucket flag of `flags` is kept.
+    fn write_tombstone(&mut self, key: &[u8], ts_tamp: u32, flags: u8) -> anyhow::Result<()> {
+        self.write_entry(0, key, &[TOMBSTONE_MARKER_CHAR; 1], ts_tamp, FLAG_TOMBSTONE | (flags & FLAG_BUCKET), 0)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    use std::time::Duration;
+
+    use tempdir::TempDir;
+
+    use crate::storage::{Config, Handle, KeyDir};
+    use crate::storage::log_reader::LogReader;
+    use crate::storage::log_writer::LogWriter;
+    use crate::storage::rebuild::rebuild_storage;
+    use crate::storage::stats::{FileStats, FileStatsMap};
+
+    use super::{build_merge_file_name, MERGE_MANIFEST, MergePolicy, Merger, write_manifest};
+
+    fn file(total_bytes: u64, dead_bytes: u64) -> FileStats {
+        FileStats { total_bytes, dead_bytes, live_bytes: total_bytes - dead_bytes, ..Default::default() }
+    }
+
+    #[test]
+    fn it_should_select_files_by_dead_ratio() {
+        let policy = MergePolicy { dead_ratio: 0.5, dead_bytes: u64::MAX, min_files: 1, window: None, check_interval: Duration::from_secs(1) };
+        let files: FileStatsMap = [(1, file(100, 60)), (2, file(100, 10)), (3, file(100, 90))].into_iter().collect();
+
+        assert_eq!(vec![1], policy.select(&files, 3, 0));
+        assert!(MergePolicy { min_files: 2, ..policy.clone() }.select(&files, 3, 0).is_empty());
+    }
+
+    #[test]
+    fn it_should_select_files_by_total_dead_bytes() {
+        let policy = MergePolicy { dead_ratio: 1.0, dead_bytes: 50, ..Default::default() };
+        let files: FileStatsMap = [(1, file(100, 30)), (2, file(100, 0)), (3, file(100, 30)), (4, file(100, 50))].into_iter().collect();
+
+        assert_eq!(vec![1, 3], policy.select(&files, 4, 0));
+    }
+
+    #[test]
+    fn it_should_select_files_in_window() {
+        let policy = MergePolicy { dead_ratio: 0.1, window: Some((22, 4)), ..Default::default() };
+        let files: FileStatsMap = [(1, file(100, 60)), (2, file(0, 0))].into_iter().collect();
+
+        assert_eq!(vec![1], policy.select(&files, 2, 23));
+        assert_eq!(vec![1], policy.select(&files, 2, 3));
+        assert!(policy.select(&files, 2, 12).is_empty());
+    }
+
+    fn read_all(dir: &Path, key_dir: &KeyDir) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut pairs: Vec<(Vec<u8>, Vec<u8>)> = key_dir.keys(None).unwrap().iter()
+            .map(|(key, h)| (key.clone(), LogReader::new(dir, h.file_id).unwrap().read(h.val_offset, h.val_size).unwrap()))
+            .collect();
+        pairs.sort();
+        pairs
+    }
+
+    #[test]
+    fn it_should_keep_writes_during_merge() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            max_file_size: 256,
+            ..Default::default()
+        };
+        let key_dir = Arc::new(RwLock::new(KeyDir::new()));
+        let file_stats = Arc::new(RwLock::new(FileStatsMap::new()));
+        let mut writer = LogWriter::new(&conf, key_dir.clone(), file_stats.clone()).unwrap();
+        for i in 0..500 {
+            writer.put(None, format!("k{}", i % 20).as_bytes(), format!("old{i}").as_bytes()).unwrap();
+        }
+        let merger = Merger::new(conf.clone(), key_dir.clone(), file_stats.clone(), writer.active_file_id(), Default::default(), writer.watchers());
+
+        // when
+        thread::scope(|scope| {
+            let merge = scope.spawn(|| merger.merge_all().unwrap());
+            for i in 0..500 {
+                writer.put(None, format!("k{}", i % 30).as_bytes(), format!("new{i}").as_bytes()).unwrap();
+            }
+            assert!(!merge.join().unwrap().files.is_empty());
+        });
+
+        // then
+        let expected: Vec<(Vec<u8>, Vec<u8>)> = {
+            let mut pairs: Vec<(Vec<u8>, Vec<u8>)> = (470..500)
+                .map(|i| (format!("k{}", i % 30).into_bytes(), format!("new{i}").into_bytes()))
+                .collect();
+            pairs.sort();
+            pairs
+        };
+        assert_eq!(expected, read_all(&conf.path, &key_dir.read().unwrap()));
+
+        drop(writer);
+        let (rebuilt, rebuilt_stats) = rebuild_storage(&conf.path).unwrap();
+        assert_eq!(expected, read_all(&conf.path, &rebuilt));
+        assert_eq!(*file_stats.read().unwrap(), rebuilt_stats);
+    }
+
+    #[test]
+    fn it_should_keep_bucket_drops_of_partially_merged_files() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            max_file_size: 64,
+            ..Default::default()
+        };
+        let key_dir = Arc::new(RwLock::new(KeyDir::new()));
+        let file_stats = Arc::new(RwLock::new(FileStatsMap::new()));
+        let mut writer = LogWriter::new(&conf, key_dir.clone(), file_stats.clone()).unwrap();
+        let roll = |writer: &mut LogWriter<'_>| {
+            let file_id = writer.file_id();
+            while writer.file_id() == file_id {
+                writer.put(None, b"filler", b"filler").unwrap();
+            }
+        };
+
+        writer.put(Some(b"users"), b"k0", b"old").unwrap();
+        roll(&mut writer);
+        let drop_file = writer.file_id();
+        writer.drop_bucket(b"users").unwrap();
+        roll(&mut writer);
+        let new_file = writer.file_id();
+        writer.put(Some(b"users"), b"k1", b"new").unwrap();
+        roll(&mut writer);
+        roll(&mut writer);
+        let merger = Merger::new(conf.clone(), key_dir.clone(), file_stats.clone(), writer.active_file_id(), Default::default(), writer.watchers());
+
+        // when: the drop marker is moved after the file which has the new key
+        let last = writer.file_id() - 1;
+        assert!(new_file < last);
+        merger.merge(&[drop_file, last]).unwrap();
+
+        // then
+        drop(writer);
+        let (rebuilt, _) = rebuild_storage(&conf.path).unwrap();
+        assert!(rebuilt.get(Some(b"users"), b"k0").is_none());
+        assert!(rebuilt.get(Some(b"users"), b"k1").is_some());
+    }
+
+    fn snapshot(dir: &Path) -> Vec<(String, Vec<u8>)> {
+        fs::read_dir(dir).unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.to_string_lossy().ends_with(".bitcask.data"))
+            .map(|p| (p.file_name().unwrap().to_string_lossy().to_string(), fs::read(&p).unwrap()))
+            .collect()
+    }
+
+    fn populate(conf: &Config) {
+        let mut handle = Handle::open(conf).unwrap();
+        for i in 0..40 {
+            handle.put(format!("k{}", i % 8).as_bytes(), format!("val{i}").as_bytes()).unwrap();
+        }
+        handle.delete(b"k1").unwrap();
+        handle.put(b"last", b"value").unwrap();
+    }
+
+    fn assert_populated(handle: &mut Handle<'_>) {
+        assert_eq!(None, handle.get(b"k1").unwrap());
+        assert_eq!(Some(b"val39".to_vec()), handle.get(b"k7").unwrap());
+        assert_eq!(Some(b"val32".to_vec()), handle.get(b"k0").unwrap());
+        assert_eq!(Some(b"value".to_vec()), handle.get(b"last").unwrap());
+    }
+
+    #[test]
+    fn it_should_complete_merge_interrupted_after_rename() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            max_file_size: 128,
+            ..Default::default()
+        };
+        populate(&conf);
+        let before = snapshot(&conf.path);
+
+        let report = Handle::open(&conf).unwrap().merge().unwrap();
+
+        // when: merged files are still there, as if the process crashed before removing them
+        let merged: BTreeSet<u64> = report.files.iter().copied().collect();
+        for (name, content) in &before {
+            let id: u64 = name.split('.').next().unwrap().parse().unwrap();
+            if merged.contains(&id) && id != report.file_id {
+                fs::write(conf.path.join(name), content).unwrap();
+            }
+        }
+        write_manifest(&conf.path, report.file_id, &merged).unwrap();
+
+        // then
+        let mut handle = Handle::open(&conf).unwrap();
+        assert_populated(&mut handle);
+        assert!(!conf.path.join(MERGE_MANIFEST).exists());
+        for id in merged.iter().filter(|id| **id != report.file_id) {
+            assert!(!conf.path.join(format!("{id}.bitcask.data")).exists());
+        }
+    }
+
+    #[test]
+    fn it_should_roll_back_merge_interrupted_before_rename() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            max_file_size: 128,
+            ..Default::default()
+        };
+        populate(&conf);
+        let before = snapshot(&conf.path);
+        let file_ids: BTreeSet<u64> = before.iter().map(|(name, _)| name.split('.').next().unwrap().parse().unwrap()).collect();
+        let output_id = *file_ids.last().unwrap();
+
+        // when: output is partially written
+        fs::write(conf.path.join(build_merge_file_name(output_id)), b"partial").unwrap();
+        write_manifest(&conf.path, output_id, &file_ids).unwrap();
+
+        // then
+        let mut handle = Handle::open(&conf).unwrap();
+        assert_populated(&mut handle);
+        assert!(!conf.path.join(MERGE_MANIFEST).exists());
+        assert!(!conf.path.join(build_merge_file_name(output_id)).exists());
+        assert_eq!(before.len() + 1, snapshot(&conf.path).len());
+    }
+}
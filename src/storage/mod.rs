@@ -1,33 +1,119 @@
-use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
+use std::time::Duration;
 
+pub use backup::BackupReport;
+pub use bucket::Bucket;
+pub use bulk::BulkLoadReport;
+pub use changelog::{ChangeRecord, Changelog, Cursor, Op};
+pub use compression::Compression;
 pub use config::Config;
+pub use encryption::Encryption;
+pub use export::ExportOptions;
 pub use handle::Handle;
+pub use inspect::{Dump, DumpEntry, FileReport, Problem, Record, Records, verify, VerifyReport, Warning};
+pub use log_reader::ValueReader;
+pub use merge::{MergePolicy, MergeReport};
+pub use raft::{Command, Entry, InMemoryTransport, Message, NodeId, Proposal, ProposalStatus, RaftConfig, RaftNode, Role, Transport};
+pub use read_only::ReadOnlyHandle;
+pub use repair::{LostKey, RepairedFile, RepairReport};
+pub use replication::{Follower, Leader};
+pub use riak::BitcaskImportReport;
+pub use stats::{BucketStats, FileStats, Stats};
+pub use watch::{ChangeEvent, ChangeKind, Watcher};
 
 mod file_lock;
 mod utils;
 mod log_reader;
 mod handle;
+mod read_only;
 mod config;
-mod context;
 mod log_writer;
 mod rebuild;
 mod log;
+mod compression;
+mod encryption;
+mod codec;
+mod stats;
+mod merge;
+mod rate_limit;
+mod key_dir;
+mod bucket;
+mod watch;
+mod changelog;
+mod backup;
+mod bulk;
+mod hint;
+mod export;
+mod repair;
+mod riak;
+mod replication;
+mod raft;
+mod raft_state;
+mod inspect;
 
-// TODO: We can benchmark BtreeMap: https://www.dotnetperls.com/btreemap-rust
-type KeyDir = HashMap<Vec<u8>, Header>;
+use key_dir::KeyDir;
 
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct Header {
     file_id: u64,
     val_size: u32,
     val_offset: u32,
     ts_tamp: u32,
+    flags: u8,
+    /// Expiry time of the key in milliseconds since epoch, `0` if the key has no own time to live.
+    /// `val_offset` and `val_size` point after the stored expiry time.
+    expires_at: u64,
 }
 
 
+impl Header {
+    #[inline]
+    fn is_tombstone(&self) -> bool {
+        self.flags & log::FLAG_TOMBSTONE != 0
+    }
+
+    #[inline]
+    fn is_bucket_drop(&self) -> bool {
+        self.flags & log::FLAG_BUCKET_DROP != 0
+    }
+
+    /// Returns true if the key is older than `expiry_time` in seconds, or its own expiry time is passed.
+    #[inline]
+    fn is_expired(&self, expiry_time: u32, now_millis: u64) -> bool {
+        self.ts_tamp <= expiry_time || (self.expires_at != 0 && self.expires_at <= now_millis)
+    }
+
+    /// Returns the remaining time to live of the key, the earliest of its own expiry time and the one
+    /// given by `expiry_secs`. `None` if the key never expires.
+    fn ttl(&self, expiry_secs: u32, now_millis: u64) -> Option<Duration> {
+        let expiry_secs = expiry_secs as u64;
+        [self.expires_at, if expiry_secs > 0 { (self.ts_tamp as u64 + expiry_secs) * 1000 } else { 0 }]
+            .into_iter()
+            .filter(|at| *at > 0)
+            .min()
+            .map(|at| Duration::from_millis(at.saturating_sub(now_millis)))
+    }
+
+    /// Size of the value in the data file, including the expiry time.
+    #[inline]
+    fn stored_size(&self) -> u32 {
+        if self.flags & log::FLAG_EXPIRES != 0 {
+            self.val_size + log::EXPIRES_AT_SIZE as u32
+        } else {
+            self.val_size
+        }
+    }
+
+    /// Returns true if the stored value is compressed or encrypted.
+    #[inline]
+    fn is_encoded(&self) -> bool {
+        self.flags & (log::FLAG_COMPRESSION_MASK | log::FLAG_ENCRYPTED) != 0
+    }
+}
+
 impl Debug for Header {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Header<fid={}, vsz={}, offset={}, ts={}>", self.file_id, self.val_size, self.val_offset, self.ts_tamp)
+        write!(f, "Header<fid={}, vsz={}, offset={}, ts={}, flags={:#04b}, expires_at={}>", self.file_id, self.val_size, self.val_offset, self.ts_tamp, self.flags, self.expires_at)
     }
 }
 
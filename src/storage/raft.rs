@@ -0,0 +1,910 @@
+// Raft consensus over a store. Nodes are driven by `tick` and `step`, so they can run on any runtime
+// and be tested with the in-memory transport. Commands are applied to the store only after they are
+// committed by a majority, so a write is acknowledged once it is replicated.
+//
+// Lagging members which need compacted entries receive a snapshot made of the data files of the leader.
+// It is sent in chunks while merges are paused, and installed once the last chunk is received.
+// Term, vote and log are synced to the data directory before a member replies, so a restarted member
+// keeps its promises. Entries after the snapshot are applied again after a restart.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+
+use log::info;
+
+use crate::{Error, Result};
+use crate::storage::{Config, Handle};
+use crate::storage::raft_state::{HardState, RaftStorage, remove_snapshot, SnapshotWriter, swap_in_snapshot};
+use crate::storage::utils::build_data_file_name;
+
+pub type NodeId = u64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Appended by a new leader to commit the entries of the previous terms.
+    Noop,
+    Put { key: Vec<u8>, val: Vec<u8> },
+    Delete { key: Vec<u8> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub term: u64,
+    pub index: u64,
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    RequestVote { term: u64, last_index: u64, last_term: u64 },
+    Vote { term: u64, granted: bool },
+    AppendEntries { term: u64, prev_index: u64, prev_term: u64, entries: Vec<Entry>, commit: u64 },
+    /// `match_index` is the last replicated index on success, otherwise the index to retry after.
+    AppendResponse { term: u64, success: bool, match_index: u64 },
+    /// A chunk of the data files of the leader, which contain the entries up to `last_index`. `offset` is
+    /// the position of the chunk in the snapshot, and `done` is set on the last chunk.
+    InstallSnapshot { term: u64, last_index: u64, last_term: u64, offset: u64, file_id: u64, data: Vec<u8>, done: bool },
+}
+
+impl Message {
+    fn term(&self) -> u64 {
+        match self {
+            Message::RequestVote { term, .. }
+            | Message::Vote { term, .. }
+            | Message::AppendEntries { term, .. }
+            | Message::AppendResponse { term, .. }
+            | Message::InstallSnapshot { term, .. } => *term,
+        }
+    }
+}
+
+/// Delivers messages between the members. Messages can be lost, the sender retries on the next heartbeat.
+pub trait Transport {
+    fn send(&self, from: NodeId, to: NodeId, msg: Message);
+}
+
+/// Keeps the messages in a queue, tests deliver them by calling [RaftNode::step].
+#[derive(Clone, Default)]
+pub struct InMemoryTransport {
+    inner: Arc<Mutex<InMemoryQueue>>,
+}
+
+#[derive(Default)]
+struct InMemoryQueue {
+    messages: VecDeque<(NodeId, NodeId, Message)>,
+    isolated: HashSet<NodeId>,
+}
+
+impl InMemoryTransport {
+    /// Returns the next message with its sender and receiver.
+    pub fn pop(&self) -> Option<(NodeId, NodeId, Message)> {
+        self.inner.lock().unwrap().messages.pop_front()
+    }
+
+    /// Drops the messages from and to the node until it is healed.
+    pub fn isolate(&self, node: NodeId) {
+        self.inner.lock().unwrap().isolated.insert(node);
+    }
+
+    pub fn heal(&self, node: NodeId) {
+        self.inner.lock().unwrap().isolated.remove(&node);
+    }
+}
+
+impl Transport for InMemoryTransport {
+    fn send(&self, from: NodeId, to: NodeId, msg: Message) {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.isolated.contains(&from) && !inner.isolated.contains(&to) {
+            inner.messages.push_back((from, to, msg));
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    /// Ticks without a leader before an election starts. Each member adds a jitter based on its id.
+    pub election_ticks: u32,
+    pub heartbeat_ticks: u32,
+    /// Applied entries are dropped from the log when their count reaches this value.
+    pub snapshot_threshold: usize,
+    /// Maximum entries in a single append message.
+    pub max_batch: usize,
+    /// Maximum bytes of data files in a single snapshot message.
+    pub snapshot_chunk_size: usize,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        Self { election_ticks: 10, heartbeat_ticks: 2, snapshot_threshold: 1000, max_batch: 64, snapshot_chunk_size: 1 << 20 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// A write accepted by the leader, see [RaftNode::status].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Proposal {
+    pub index: u64,
+    pub term: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProposalStatus {
+    Pending,
+    /// Replicated to a majority and applied to the store.
+    Committed,
+    /// Overwritten by another leader, it must be proposed again.
+    Dropped,
+}
+
+/// A member of a Raft group which applies the committed commands to its store.
+pub struct RaftNode<'a> {
+    id: NodeId,
+    peers: Vec<NodeId>,
+    conf: &'a Config,
+    raft_conf: RaftConfig,
+    handle: Option<Handle<'a>>,
+    storage: RaftStorage,
+    transport: Arc<dyn Transport>,
+
+    role: Role,
+    term: u64,
+    voted_for: Option<NodeId>,
+    leader: Option<NodeId>,
+    votes: HashSet<NodeId>,
+
+    /// Entries after the snapshot.
+    log: Vec<Entry>,
+    snapshot_index: u64,
+    snapshot_term: u64,
+    commit_index: u64,
+    last_applied: u64,
+    /// Snapshot which is being received from the leader.
+    snapshot: Option<SnapshotWriter>,
+
+    next_index: HashMap<NodeId, u64>,
+    match_index: HashMap<NodeId, u64>,
+    /// Proposals of this node which are not applied yet, by index.
+    proposals: HashMap<u64, u64>,
+    resolved: HashMap<u64, (u64, ProposalStatus)>,
+
+    elapsed: u32,
+    election_timeout: u32,
+}
+
+impl<'a> RaftNode<'a> {
+    /// Opens the store at `conf.path` with the term, vote and log saved by the previous run.
+    pub fn new(id: NodeId, peers: Vec<NodeId>, conf: &'a Config, raft_conf: RaftConfig, transport: Arc<dyn Transport>) -> Result<Self> {
+        fs::create_dir_all(&conf.path)?;
+        let (storage, state, log) = RaftStorage::open(&conf.path)?;
+        let peer_count = peers.len() as u32 + 1;
+        let election_timeout = raft_conf.election_ticks + (id as u32 % peer_count) * raft_conf.election_ticks / peer_count;
+
+        let mut node = Self {
+            id,
+            peers,
+            conf,
+            raft_conf,
+            handle: None,
+            storage,
+            transport,
+            role: Role::Follower,
+            term: state.term,
+            voted_for: state.voted_for,
+            leader: None,
+            votes: HashSet::new(),
+            log,
+            snapshot_index: state.snapshot_index,
+            snapshot_term: state.snapshot_term,
+            commit_index: state.snapshot_index,
+            last_applied: state.snapshot_index,
+            snapshot: None,
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            proposals: HashMap::new(),
+            resolved: HashMap::new(),
+            elapsed: 0,
+            election_timeout,
+        };
+        // a snapshot install is interrupted
+        node.swap_in_snapshot()?;
+        node.handle = Some(Handle::open(conf)?);
+        Ok(node)
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn leader(&self) -> Option<NodeId> {
+        self.leader
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    /// Reads the local store. Followers can return stale values.
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.store()?.get(key)
+    }
+
+    /// Proposes a write. It is applied to the store after it is replicated to a majority.
+    pub fn put(&mut self, key: &[u8], val: &[u8]) -> Result<Proposal> {
+        self.propose(Command::Put { key: key.to_vec(), val: val.to_vec() })
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<Proposal> {
+        self.propose(Command::Delete { key: key.to_vec() })
+    }
+
+    pub fn propose(&mut self, command: Command) -> Result<Proposal> {
+        if self.role != Role::Leader {
+            return Err(Error::NotLeader { leader: self.leader });
+        }
+
+        let proposal = Proposal { index: self.last_index() + 1, term: self.term };
+        self.push_entry(Entry { term: self.term, index: proposal.index, command })?;
+        self.proposals.insert(proposal.index, proposal.term);
+        self.broadcast_append()?;
+        // a single member commits alone
+        self.advance_commit()?;
+        Ok(proposal)
+    }
+
+    pub fn status(&self, proposal: &Proposal) -> ProposalStatus {
+        match self.resolved.get(&proposal.index) {
+            Some((term, status)) if *term == proposal.term => *status,
+            Some(_) => ProposalStatus::Dropped,
+            None if self.proposals.get(&proposal.index) == Some(&proposal.term) => ProposalStatus::Pending,
+            None if proposal.index <= self.last_applied => ProposalStatus::Dropped,
+            None => ProposalStatus::Pending,
+        }
+    }
+
+    /// Advances the logical clock. Leaders send heartbeats, others start an election after the timeout.
+    pub fn tick(&mut self) -> Result<()> {
+        self.elapsed += 1;
+        match self.role {
+            Role::Leader if self.elapsed >= self.raft_conf.heartbeat_ticks => {
+                self.elapsed = 0;
+                self.broadcast_append()
+            }
+            Role::Follower | Role::Candidate if self.elapsed >= self.election_timeout => {
+                self.start_election()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Handles a message from another member.
+    pub fn step(&mut self, from: NodeId, msg: Message) -> Result<()> {
+        if msg.term() > self.term {
+            self.become_follower(msg.term(), None);
+            self.persist_state()?;
+        }
+
+        match msg {
+            Message::RequestVote { term, last_index, last_term } => {
+                let up_to_date = (last_term, last_index) >= (self.last_term(), self.last_index());
+                let granted = term == self.term && self.voted_for.is_none_or(|id| id == from) && up_to_date;
+                if granted {
+                    self.voted_for = Some(from);
+                    self.elapsed = 0;
+                    self.persist_state()?;
+                }
+                self.send(from, Message::Vote { term: self.term, granted });
+            }
+            Message::Vote { term, granted } => {
+                if self.role == Role::Candidate && term == self.term && granted {
+                    self.votes.insert(from);
+                    if self.votes.len() > self.quorum_size() / 2 {
+                        self.become_leader()?;
+                    }
+                }
+            }
+            Message::AppendEntries { term, prev_index, prev_term, entries, commit } => {
+                if term < self.term {
+                    self.send(from, Message::AppendResponse { term: self.term, success: false, match_index: 0 });
+                    return Ok(());
+                }
+                self.become_follower(term, Some(from));
+                let response = self.append_entries(prev_index, prev_term, entries, commit)?;
+                self.send(from, response);
+            }
+            Message::AppendResponse { term, success, match_index } => {
+                if self.role != Role::Leader || term != self.term {
+                    return Ok(());
+                }
+                if success {
+                    let matched = self.match_index.entry(from).or_default();
+                    *matched = (*matched).max(match_index);
+                    self.next_index.insert(from, match_index + 1);
+                    self.advance_commit()?;
+                } else {
+                    self.next_index.insert(from, match_index + 1);
+                    self.send_append(from)?;
+                }
+            }
+            Message::InstallSnapshot { term, last_index, last_term, offset, file_id, data, done } => {
+                if term < self.term {
+                    self.send(from, Message::AppendResponse { term: self.term, success: false, match_index: 0 });
+                    return Ok(());
+                }
+                self.become_follower(term, Some(from));
+                let installed = if last_index > self.commit_index {
+                    self.receive_snapshot(last_index, last_term, offset, file_id, &data, done)?
+                } else {
+                    self.snapshot = None;
+                    true
+                };
+                // a snapshot with a lost chunk is sent again after the response
+                if done {
+                    let match_index = if installed { last_index.max(self.commit_index) } else { self.commit_index };
+                    self.send(from, Message::AppendResponse { term: self.term, success: installed, match_index });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn append_entries(&mut self, prev_index: u64, prev_term: u64, entries: Vec<Entry>, commit: u64) -> Result<Message> {
+        // entries before the snapshot are already committed
+        let (prev_index, prev_term, entries) = if prev_index < self.snapshot_index {
+            let entries: Vec<Entry> = entries.into_iter().filter(|e| e.index > self.snapshot_index).collect();
+            (self.snapshot_index, self.snapshot_term, entries)
+        } else {
+            (prev_index, prev_term, entries)
+        };
+
+        if self.term_at(prev_index) != Some(prev_term) {
+            let retry_after = self.last_index().min(prev_index.saturating_sub(1)).max(self.snapshot_index);
+            return Ok(Message::AppendResponse { term: self.term, success: false, match_index: retry_after });
+        }
+
+        let last_new = prev_index + entries.len() as u64;
+        let mut truncated = false;
+        let mut appended = Vec::new();
+        for entry in entries {
+            match self.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    self.log.truncate((entry.index - self.snapshot_index - 1) as usize);
+                    truncated = true;
+                }
+                None => {}
+            }
+            appended.push(entry.clone());
+            self.log.push(entry);
+        }
+        // entries are durable before they are acknowledged
+        if truncated {
+            self.storage.rewrite(&self.log)?;
+        } else if !appended.is_empty() {
+            self.storage.append(&appended)?;
+        }
+
+        if commit > self.commit_index {
+            self.commit_index = commit.min(last_new);
+            self.apply()?;
+        }
+
+        Ok(Message::AppendResponse { term: self.term, success: true, match_index: last_new })
+    }
+
+    fn start_election(&mut self) -> Result<()> {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.voted_for = Some(self.id);
+        self.leader = None;
+        self.votes = HashSet::from([self.id]);
+        self.elapsed = 0;
+        self.persist_state()?;
+        info!("node {} starts election for term {}", self.id, self.term);
+
+        if self.peers.is_empty() {
+            return self.become_leader();
+        }
+
+        let msg = Message::RequestVote { term: self.term, last_index: self.last_index(), last_term: self.last_term() };
+        for peer in self.peers.clone() {
+            self.send(peer, msg.clone());
+        }
+        Ok(())
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.elapsed = 0;
+    }
+
+    fn become_leader(&mut self) -> Result<()> {
+        info!("node {} is the leader of term {}", self.id, self.term);
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.elapsed = 0;
+        for peer in &self.peers {
+            self.next_index.insert(*peer, self.last_index() + 1);
+            self.match_index.insert(*peer, 0);
+        }
+
+        self.push_entry(Entry { term: self.term, index: self.last_index() + 1, command: Command::Noop })?;
+        self.broadcast_append()?;
+        self.advance_commit()
+    }
+
+    fn broadcast_append(&mut self) -> Result<()> {
+        for peer in self.peers.clone() {
+            self.send_append(peer)?;
+        }
+        Ok(())
+    }
+
+    fn send_append(&mut self, peer: NodeId) -> Result<()> {
+        let next = self.next_index.get(&peer).copied().unwrap_or(self.last_index() + 1);
+        if next <= self.snapshot_index {
+            return self.send_snapshot(peer);
+        }
+
+        let prev_index = next - 1;
+        let prev_term = self.term_at(prev_index).unwrap_or_default();
+        let start = (next - self.snapshot_index - 1) as usize;
+        let end = (start + self.raft_conf.max_batch).min(self.log.len());
+        let entries = self.log[start..end].to_vec();
+        self.send(peer, Message::AppendEntries { term: self.term, prev_index, prev_term, entries, commit: self.commit_index });
+        Ok(())
+    }
+
+    /// Sends the data files, which contain the applied entries, in chunks. Merges are paused until the
+    /// files are read, so they are not removed or replaced by a merge output while they are sent.
+    fn send_snapshot(&mut self, peer: NodeId) -> Result<()> {
+        let last_index = self.last_applied;
+        let last_term = self.term_at(last_index).unwrap_or_default();
+        info!("node {} sends snapshot at {} to {}", self.id, last_index, peer);
+
+        let (id, term, transport) = (self.id, self.term, self.transport.clone());
+        let chunk_size = self.raft_conf.snapshot_chunk_size.max(1) as u64;
+        self.store()?.with_data_files(|dir, file_ids| {
+            let mut offset = 0;
+            for (i, file_id) in file_ids.iter().enumerate() {
+                let mut file = fs::File::open(dir.join(build_data_file_name(*file_id)))?;
+                let mut remaining = file.metadata()?.len();
+                loop {
+                    let mut data = vec![0u8; remaining.min(chunk_size) as usize];
+                    file.read_exact(&mut data)?;
+                    remaining -= data.len() as u64;
+                    let done = remaining == 0 && i + 1 == file_ids.len();
+                    let size = data.len() as u64;
+                    transport.send(id, peer, Message::InstallSnapshot { term, last_index, last_term, offset, file_id: *file_id, data, done });
+                    offset += size;
+                    if remaining == 0 {
+                        break;
+                    }
+                }
+            }
+            Ok(())
+        })?;
+
+        self.next_index.insert(peer, last_index + 1);
+        Ok(())
+    }
+
+    /// Writes the chunk of a snapshot, and installs the snapshot after its last chunk. Returns false
+    /// if the snapshot is not installed, because it is not complete.
+    fn receive_snapshot(&mut self, last_index: u64, last_term: u64, offset: u64, file_id: u64, data: &[u8], done: bool) -> Result<bool> {
+        if offset == 0 {
+            self.snapshot = Some(SnapshotWriter::create(&self.conf.path, last_index, last_term)?);
+        }
+        let Some(writer) = self.snapshot.as_mut().filter(|writer| writer.is_next(last_index, last_term, offset)) else {
+            self.snapshot = None;
+            return Ok(false);
+        };
+        writer.write(file_id, data)?;
+        if !done {
+            return Ok(false);
+        }
+
+        info!("node {} installs snapshot at {}", self.id, last_index);
+        self.snapshot.take().unwrap().finish()?;
+        // store is reopened, so the directory is unlocked first
+        self.handle = None;
+        self.swap_in_snapshot()?;
+        self.handle = Some(Handle::open(self.conf)?);
+        Ok(true)
+    }
+
+    /// Replaces the data files with the written snapshot and moves the log after it. The store must be closed.
+    fn swap_in_snapshot(&mut self) -> Result<()> {
+        let Some((last_index, last_term)) = swap_in_snapshot(&self.conf.path)? else {
+            return Ok(());
+        };
+
+        if self.term_at(last_index) == Some(last_term) {
+            self.log.drain(..(last_index - self.snapshot_index) as usize);
+        } else {
+            self.log.clear();
+        }
+        self.snapshot_index = last_index;
+        self.snapshot_term = last_term;
+        self.commit_index = last_index;
+        self.last_applied = last_index;
+        self.persist_state()?;
+        self.storage.rewrite(&self.log)?;
+        Ok(remove_snapshot(&self.conf.path)?)
+    }
+
+    fn advance_commit(&mut self) -> Result<()> {
+        let mut commit = self.commit_index;
+        for index in self.commit_index + 1..=self.last_index() {
+            // only entries of the current term are committed by counting replicas
+            if self.term_at(index) != Some(self.term) {
+                continue;
+            }
+            let replicas = 1 + self.match_index.values().filter(|m| **m >= index).count();
+            if replicas > self.quorum_size() / 2 {
+                commit = index;
+            }
+        }
+
+        if commit > self.commit_index {
+            self.commit_index = commit;
+            self.apply()?;
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self) -> Result<()> {
+        while self.last_applied < self.commit_index {
+            let index = self.last_applied + 1;
+            let entry = self.log[(index - self.snapshot_index - 1) as usize].clone();
+            match &entry.command {
+                Command::Noop => {}
+                Command::Put { key, val } => self.store()?.put(key, val)?,
+                Command::Delete { key } => self.store()?.delete(key)?,
+            }
+            self.last_applied = index;
+
+            if let Some(term) = self.proposals.remove(&index) {
+                let status = if term == entry.term { ProposalStatus::Committed } else { ProposalStatus::Dropped };
+                self.resolved.insert(index, (term, status));
+            }
+        }
+
+        self.compact()
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        let applied = (self.last_applied - self.snapshot_index) as usize;
+        if applied < self.raft_conf.snapshot_threshold {
+            return Ok(());
+        }
+
+        // dropped entries are only kept by the store
+        self.store()?.flush()?;
+        self.snapshot_term = self.term_at(self.last_applied).unwrap_or_default();
+        self.snapshot_index = self.last_applied;
+        self.log.drain(..applied);
+        self.persist_state()?;
+        Ok(self.storage.rewrite(&self.log)?)
+    }
+
+    fn push_entry(&mut self, entry: Entry) -> Result<()> {
+        self.storage.append(std::slice::from_ref(&entry))?;
+        self.log.push(entry);
+        Ok(())
+    }
+
+    /// Syncs the term, vote and snapshot position, if they are changed.
+    fn persist_state(&mut self) -> Result<()> {
+        let state = HardState { term: self.term, voted_for: self.voted_for, snapshot_index: self.snapshot_index, snapshot_term: self.snapshot_term };
+        Ok(self.storage.save_state(state)?)
+    }
+
+    fn store(&mut self) -> Result<&mut Handle<'a>> {
+        self.handle.as_mut().ok_or(Error::Closed)
+    }
+
+    fn send(&self, to: NodeId, msg: Message) {
+        self.transport.send(self.id, to, msg);
+    }
+
+    fn quorum_size(&self) -> usize {
+        self.peers.len() + 1
+    }
+
+    fn last_index(&self) -> u64 {
+        self.snapshot_index + self.log.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log.last().map(|e| e.term).unwrap_or(self.snapshot_term)
+    }
+
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        if index < self.snapshot_index {
+            return None;
+        }
+        self.log.get((index - self.snapshot_index - 1) as usize).map(|e| e.term)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use tempdir::TempDir;
+
+    use crate::Error;
+    use crate::storage::{Config, Handle};
+    use crate::storage::raft_state::SnapshotWriter;
+
+    use super::{InMemoryTransport, Message, ProposalStatus, RaftConfig, RaftNode, Role};
+
+    fn configs(count: usize) -> Vec<Config> {
+        (0..count).map(|_| Config { path: TempDir::new("bitcask-").unwrap().into_path(), ..Default::default() }).collect()
+    }
+
+    fn cluster<'a>(confs: &'a [Config], raft_conf: &RaftConfig, transport: &InMemoryTransport) -> Vec<RaftNode<'a>> {
+        let ids: Vec<u64> = (1..=confs.len() as u64).collect();
+        confs.iter().zip(&ids)
+            .map(|(conf, id)| {
+                let peers = ids.iter().filter(|p| *p != id).copied().collect();
+                RaftNode::new(*id, peers, conf, raft_conf.clone(), Arc::new(transport.clone())).unwrap()
+            })
+            .collect()
+    }
+
+    /// Ticks all nodes and delivers the messages.
+    fn run(nodes: &mut [RaftNode<'_>], transport: &InMemoryTransport, ticks: usize) {
+        for _ in 0..ticks {
+            nodes.iter_mut().for_each(|n| n.tick().unwrap());
+            while let Some((from, to, msg)) = transport.pop() {
+                nodes[to as usize - 1].step(from, msg).unwrap();
+            }
+        }
+    }
+
+    fn leader(nodes: &[RaftNode<'_>]) -> usize {
+        let leaders: Vec<usize> = (0..nodes.len()).filter(|i| nodes[*i].role() == Role::Leader).collect();
+        assert_eq!(1, leaders.len());
+        leaders[0]
+    }
+
+    #[test]
+    fn it_should_commit_after_majority_replication() {
+        // given
+        let confs = configs(3);
+        let transport = InMemoryTransport::default();
+        let mut nodes = cluster(&confs, &RaftConfig::default(), &transport);
+        run(&mut nodes, &transport, 30);
+        let l = leader(&nodes);
+        let follower = (l + 1) % 3;
+
+        // when
+        let proposal = nodes[l].put(b"k1", b"v1").unwrap();
+
+        // then: leader does not apply the write before the followers acknowledge it
+        assert_eq!(ProposalStatus::Pending, nodes[l].status(&proposal));
+        assert_eq!(None, nodes[l].get(b"k1").unwrap());
+        assert!(matches!(nodes[follower].put(b"k2", b"v2"), Err(Error::NotLeader { leader: Some(id) }) if id == l as u64 + 1));
+
+        run(&mut nodes, &transport, 5);
+        assert_eq!(ProposalStatus::Committed, nodes[l].status(&proposal));
+        for node in nodes.iter_mut() {
+            assert_eq!(Some(b"v1".to_vec()), node.get(b"k1").unwrap());
+        }
+    }
+
+    #[test]
+    fn it_should_not_commit_without_majority() {
+        // given
+        let confs = configs(3);
+        let transport = InMemoryTransport::default();
+        let mut nodes = cluster(&confs, &RaftConfig::default(), &transport);
+        run(&mut nodes, &transport, 30);
+        let l = leader(&nodes);
+
+        // when
+        for node in nodes.iter().filter(|n| n.id() != l as u64 + 1) {
+            transport.isolate(node.id());
+        }
+        let proposal = nodes[l].put(b"k1", b"v1").unwrap();
+        run(&mut nodes, &transport, 5);
+
+        // then
+        assert_eq!(ProposalStatus::Pending, nodes[l].status(&proposal));
+        assert_eq!(None, nodes[l].get(b"k1").unwrap());
+    }
+
+    #[test]
+    fn it_should_send_snapshot_to_lagging_member() {
+        // given
+        let confs = configs(3);
+        let transport = InMemoryTransport::default();
+        let raft_conf = RaftConfig { snapshot_threshold: 10, snapshot_chunk_size: 64, ..Default::default() };
+        let mut nodes = cluster(&confs, &raft_conf, &transport);
+        run(&mut nodes, &transport, 30);
+        let l = leader(&nodes);
+        let lagging = (l + 1) % 3;
+        transport.isolate(nodes[lagging].id());
+
+        // when
+        for i in 0..50 {
+            nodes[l].put(format!("k{i}").as_bytes(), format!("v{i}").as_bytes()).unwrap();
+            run(&mut nodes, &transport, 1);
+        }
+        nodes[l].delete(b"k0").unwrap();
+        run(&mut nodes, &transport, 3);
+        assert!(nodes[l].snapshot_index > 0);
+
+        // isolated member has a higher term, so a new leader is elected after it is healed
+        transport.heal(nodes[lagging].id());
+        run(&mut nodes, &transport, 40);
+
+        // then
+        let lagging = &mut nodes[lagging];
+        assert_eq!(None, lagging.get(b"k0").unwrap());
+        assert_eq!(Some(b"v49".to_vec()), lagging.get(b"k49").unwrap());
+        assert_eq!(Some(b"v10".to_vec()), lagging.get(b"k10").unwrap());
+    }
+
+    #[test]
+    fn it_should_elect_new_leader() {
+        // given
+        let confs = configs(3);
+        let transport = InMemoryTransport::default();
+        let mut nodes = cluster(&confs, &RaftConfig::default(), &transport);
+        run(&mut nodes, &transport, 30);
+        let old = leader(&nodes);
+        nodes[old].put(b"k1", b"v1").unwrap();
+        run(&mut nodes, &transport, 3);
+
+        // when
+        transport.isolate(old as u64 + 1);
+        run(&mut nodes, &transport, 40);
+
+        // then
+        let new = (0..3).find(|i| *i != old && nodes[*i].role() == Role::Leader).unwrap();
+        assert!(nodes[new].term() > nodes[old].term());
+        let proposal = nodes[new].put(b"k2", b"v2").unwrap();
+        run(&mut nodes, &transport, 3);
+        assert_eq!(ProposalStatus::Committed, nodes[new].status(&proposal));
+        assert_eq!(Some(b"v1".to_vec()), nodes[new].get(b"k1").unwrap());
+
+        transport.heal(old as u64 + 1);
+        run(&mut nodes, &transport, 5);
+        assert_eq!(Role::Follower, nodes[old].role());
+        assert_eq!(Some(b"v2".to_vec()), nodes[old].get(b"k2").unwrap());
+    }
+
+    #[test]
+    fn it_should_keep_term_vote_and_log_after_restart() {
+        // given
+        let confs = configs(3);
+        let transport = InMemoryTransport::default();
+        let mut nodes = cluster(&confs, &RaftConfig::default(), &transport);
+        run(&mut nodes, &transport, 30);
+        let l = leader(&nodes);
+        nodes[l].put(b"k1", b"v1").unwrap();
+        run(&mut nodes, &transport, 3);
+        let states: Vec<_> = nodes.iter().map(|n| (n.term(), n.voted_for, n.last_index())).collect();
+
+        // when
+        drop(nodes);
+        while transport.pop().is_some() {}
+        let mut nodes = cluster(&confs, &RaftConfig::default(), &transport);
+
+        // then
+        assert_eq!(states, nodes.iter().map(|n| (n.term(), n.voted_for, n.last_index())).collect::<Vec<_>>());
+        run(&mut nodes, &transport, 40);
+        let l = leader(&nodes);
+        let proposal = nodes[l].put(b"k2", b"v2").unwrap();
+        run(&mut nodes, &transport, 3);
+        assert_eq!(ProposalStatus::Committed, nodes[l].status(&proposal));
+        for node in nodes.iter_mut() {
+            assert_eq!(Some(b"v1".to_vec()), node.get(b"k1").unwrap());
+            assert_eq!(Some(b"v2".to_vec()), node.get(b"k2").unwrap());
+        }
+    }
+
+    #[test]
+    fn it_should_complete_interrupted_snapshot_install() {
+        // given
+        let confs = configs(2);
+        let (source, target) = (&confs[0], &confs[1]);
+        Handle::open(source).unwrap().put(b"k1", b"v1").unwrap();
+        let mut files: Vec<(u64, Vec<u8>)> = std::fs::read_dir(&source.path).unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.extension().is_some_and(|ext| ext == "data"))
+            .map(|p| (p.file_name().unwrap().to_string_lossy().split('.').next().unwrap().parse().unwrap(), std::fs::read(&p).unwrap()))
+            .collect();
+        files.sort();
+        Handle::open(target).unwrap().put(b"old", b"v").unwrap();
+        std::fs::write(target.path.join("1.bitcask.hint"), b"stale").unwrap();
+        std::fs::write(target.path.join("merge.manifest"), b"1\n").unwrap();
+
+        // when
+        let mut writer = SnapshotWriter::create(&target.path, 5, 2).unwrap();
+        for (file_id, content) in &files {
+            writer.write(*file_id, content).unwrap();
+        }
+        writer.finish().unwrap();
+        let mut node = RaftNode::new(1, vec![2], target, RaftConfig::default(), Arc::new(InMemoryTransport::default())).unwrap();
+
+        // then
+        assert_eq!(5, node.commit_index());
+        assert_eq!(Some(b"v1".to_vec()), node.get(b"k1").unwrap());
+        assert_eq!(None, node.get(b"old").unwrap());
+        for name in ["1.bitcask.hint", "merge.manifest", "raft.snapshot.ready"] {
+            assert!(!target.path.join(name).exists(), "{name}");
+        }
+    }
+
+    #[test]
+    fn it_should_send_snapshot_again_if_chunk_is_lost() {
+        // given
+        let confs = configs(3);
+        let transport = InMemoryTransport::default();
+        let raft_conf = RaftConfig { snapshot_threshold: 10, snapshot_chunk_size: 64, ..Default::default() };
+        let mut nodes = cluster(&confs, &raft_conf, &transport);
+        run(&mut nodes, &transport, 30);
+        let l = leader(&nodes);
+        let lagging = (l + 1) % 3;
+        transport.isolate(nodes[lagging].id());
+        for i in 0..20 {
+            nodes[l].put(format!("k{i}").as_bytes(), format!("v{i}").as_bytes()).unwrap();
+            run(&mut nodes, &transport, 1);
+        }
+        assert!(nodes[l].snapshot_index > 0);
+        transport.heal(nodes[lagging].id());
+
+        // when: the second chunk of the first snapshot is dropped
+        let (mut chunks, mut snapshots) = (0, 0);
+        for _ in 0..40 {
+            nodes.iter_mut().for_each(|n| n.tick().unwrap());
+            while let Some((from, to, msg)) = transport.pop() {
+                if let Message::InstallSnapshot { offset, .. } = msg {
+                    chunks += 1;
+                    snapshots += (offset == 0) as usize;
+                    if chunks == 2 {
+                        continue;
+                    }
+                }
+                nodes[to as usize - 1].step(from, msg).unwrap();
+            }
+        }
+
+        // then
+        assert_eq!(2, snapshots);
+        for name in ["raft.snapshot", "raft.snapshot.ready"] {
+            assert!(!confs[lagging].path.join(name).exists(), "{name}");
+        }
+        for i in 0..20 {
+            assert_eq!(Some(format!("v{i}").into_bytes()), nodes[lagging].get(format!("k{i}").as_bytes()).unwrap());
+        }
+    }
+}
@@ -0,0 +1,370 @@
+// Durable state of a Raft member, kept in the data directory next to the data files.
+//
+// raft.state: [crc|term|voted|voted_for|snapshot_index|snapshot_term], replaced with a rename
+// raft.log: entries after the snapshot, [crc|size|term|index|kind|ksz|key|val]. Entries are appended,
+// the file is rewritten when entries are truncated or compacted.
+//
+// A snapshot is received into raft.snapshot, then renamed to raft.snapshot.ready. Its manifest has the
+// last index and term, and the ids of the data files. Swapping it in is repeated on the next start
+// until the directory is removed, so a crash leaves either the old or the new data files.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use bytes::{Buf, BufMut};
+
+use crate::storage::bulk::LOAD_MANIFEST;
+use crate::storage::merge::MERGE_MANIFEST;
+use crate::storage::raft::{Command, Entry, NodeId};
+use crate::storage::rebuild::extract_data_file_ids;
+use crate::storage::utils::{build_data_file_name, sync_dir};
+
+const STATE_FILE: &str = "raft.state";
+const LOG_FILE: &str = "raft.log";
+const SNAPSHOT_DIR: &str = "raft.snapshot";
+const SNAPSHOT_READY_DIR: &str = "raft.snapshot.ready";
+const SNAPSHOT_MANIFEST: &str = "manifest";
+
+const STATE_SIZE: usize = 37;
+const COMMAND_NOOP: u8 = 0;
+const COMMAND_PUT: u8 = 1;
+const COMMAND_DELETE: u8 = 2;
+
+/// Term and vote of the member, and the last entry which is compacted into the store.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct HardState {
+    pub term: u64,
+    pub voted_for: Option<NodeId>,
+    pub snapshot_index: u64,
+    pub snapshot_term: u64,
+}
+
+/// Writes the state and the log, each write is synced before it returns.
+pub(crate) struct RaftStorage {
+    dir: PathBuf,
+    log: BufWriter<fs::File>,
+    saved: HardState,
+}
+
+impl RaftStorage {
+    /// Opens the state and the log in `dir`. An entry which is not completely written is dropped.
+    pub fn open(dir: &Path) -> anyhow::Result<(Self, HardState, Vec<Entry>)> {
+        let state = match fs::read(dir.join(STATE_FILE)) {
+            Ok(content) => decode_state(&content)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HardState::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let content = match fs::read(dir.join(LOG_FILE)) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        };
+        let (entries, size) = decode_entries(&content);
+        // entries up to the snapshot stay in the log if the process stops before it is rewritten
+        let entries: Vec<Entry> = entries.into_iter().filter(|e| e.index > state.snapshot_index).collect();
+        if entries.iter().enumerate().any(|(i, e)| e.index != state.snapshot_index + 1 + i as u64) {
+            bail!("raft log does not follow the snapshot at {}", state.snapshot_index);
+        }
+
+        let log = fs::OpenOptions::new().create(true).append(true).open(dir.join(LOG_FILE))?;
+        log.set_len(size as u64)?;
+        let storage = Self { dir: dir.to_path_buf(), log: BufWriter::new(log), saved: state };
+        Ok((storage, state, entries))
+    }
+
+    /// Replaces the saved state, unless it is not changed.
+    pub fn save_state(&mut self, state: HardState) -> anyhow::Result<()> {
+        if state == self.saved {
+            return Ok(());
+        }
+
+        let mut payload = Vec::with_capacity(STATE_SIZE);
+        payload.put_u32(0); // empty space for crc
+        payload.put_u64(state.term);
+        payload.put_u8(state.voted_for.is_some() as u8);
+        payload.put_u64(state.voted_for.unwrap_or_default());
+        payload.put_u64(state.snapshot_index);
+        payload.put_u64(state.snapshot_term);
+        let crc = crc32fast::hash(&payload[4..]);
+        payload[..4].copy_from_slice(&crc.to_be_bytes());
+
+        let tmp_path = self.dir.join(format!("{STATE_FILE}.tmp"));
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(&payload)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, self.dir.join(STATE_FILE)).context("raft state rename failed")?;
+        sync_dir(&self.dir)?;
+        self.saved = state;
+        Ok(())
+    }
+
+    pub fn append(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+        for entry in entries {
+            self.log.write_all(&encode_entry(entry))?;
+        }
+        self.log.flush()?;
+        self.log.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// Replaces the log with `entries`, after entries are truncated or compacted.
+    pub fn rewrite(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+        let tmp_path = self.dir.join(format!("{LOG_FILE}.tmp"));
+        let mut file = BufWriter::new(fs::File::create(&tmp_path)?);
+        for entry in entries {
+            file.write_all(&encode_entry(entry))?;
+        }
+        file.flush()?;
+        file.get_ref().sync_all()?;
+        fs::rename(&tmp_path, self.dir.join(LOG_FILE)).context("raft log rename failed")?;
+        sync_dir(&self.dir)?;
+
+        let log = fs::OpenOptions::new().append(true).open(self.dir.join(LOG_FILE))?;
+        self.log = BufWriter::new(log);
+        Ok(())
+    }
+}
+
+fn decode_state(content: &[u8]) -> anyhow::Result<HardState> {
+    if content.len() != STATE_SIZE || crc32fast::hash(&content[4..]) != u32::from_be_bytes(content[..4].try_into()?) {
+        bail!("raft state is corrupt");
+    }
+
+    let mut buf = &content[4..];
+    let term = buf.get_u64();
+    let voted = buf.get_u8() != 0;
+    let voted_for = buf.get_u64();
+    Ok(HardState {
+        term,
+        voted_for: voted.then_some(voted_for),
+        snapshot_index: buf.get_u64(),
+        snapshot_term: buf.get_u64(),
+    })
+}
+
+fn encode_entry(entry: &Entry) -> Vec<u8> {
+    let mut payload = Vec::new();
+    payload.put_u64(entry.term);
+    payload.put_u64(entry.index);
+    match &entry.command {
+        Command::Noop => payload.put_u8(COMMAND_NOOP),
+        Command::Put { key, val } => {
+            payload.put_u8(COMMAND_PUT);
+            payload.put_u32(key.len() as u32);
+            payload.put_slice(key);
+            payload.put_slice(val);
+        }
+        Command::Delete { key } => {
+            payload.put_u8(COMMAND_DELETE);
+            payload.put_u32(key.len() as u32);
+            payload.put_slice(key);
+        }
+    }
+
+    let mut frame = Vec::with_capacity(8 + payload.len());
+    frame.put_u32(crc32fast::hash(&payload));
+    frame.put_u32(payload.len() as u32);
+    frame.put_slice(&payload);
+    frame
+}
+
+/// Returns the entries and the size of the readable part of the log.
+fn decode_entries(content: &[u8]) -> (Vec<Entry>, usize) {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while let Some((entry, size)) = content.get(offset..).and_then(decode_entry) {
+        offset += 8 + size;
+        entries.push(entry);
+    }
+    (entries, offset)
+}
+
+fn decode_entry(mut buf: &[u8]) -> Option<(Entry, usize)> {
+    if buf.len() < 8 {
+        return None;
+    }
+    let crc = buf.get_u32();
+    let size = buf.get_u32() as usize;
+    let mut payload = buf.get(..size)?;
+    if crc32fast::hash(payload) != crc || payload.len() < 17 {
+        return None;
+    }
+
+    let term = payload.get_u64();
+    let index = payload.get_u64();
+    let kind = payload.get_u8();
+    let command = match kind {
+        COMMAND_NOOP => Command::Noop,
+        _ => {
+            if payload.len() < 4 {
+                return None;
+            }
+            let key_size = payload.get_u32() as usize;
+            let key = payload.get(..key_size)?.to_vec();
+            let rest = payload[key_size..].to_vec();
+            match kind {
+                COMMAND_PUT => Command::Put { key, val: rest },
+                COMMAND_DELETE => Command::Delete { key },
+                _ => return None,
+            }
+        }
+    };
+    Some((Entry { term, index, command }, size))
+}
+
+/// Writes the data files of a snapshot beside the store as they are received, see [swap_in_snapshot].
+pub(crate) struct SnapshotWriter {
+    dir: PathBuf,
+    last_index: u64,
+    last_term: u64,
+    file_ids: Vec<u64>,
+    file: Option<fs::File>,
+    size: u64,
+}
+
+impl SnapshotWriter {
+    /// Starts the snapshot which contains the entries up to `last_index`. A snapshot which is
+    /// not completely received is dropped.
+    pub fn create(dir: &Path, last_index: u64, last_term: u64) -> anyhow::Result<Self> {
+        let tmp_dir = dir.join(SNAPSHOT_DIR);
+        if tmp_dir.exists() {
+            fs::remove_dir_all(&tmp_dir)?;
+        }
+        fs::create_dir(&tmp_dir)?;
+        Ok(Self { dir: dir.to_path_buf(), last_index, last_term, file_ids: vec![], file: None, size: 0 })
+    }
+
+    /// Returns true if the chunk at `offset` of the snapshot is the next one.
+    pub fn is_next(&self, last_index: u64, last_term: u64, offset: u64) -> bool {
+        (self.last_index, self.last_term, self.size) == (last_index, last_term, offset)
+    }
+
+    /// Appends the chunk to the data file. Files are written one after another in id order.
+    pub fn write(&mut self, file_id: u64, data: &[u8]) -> anyhow::Result<()> {
+        match self.file_ids.last() {
+            Some(last) if *last == file_id => {}
+            Some(last) if *last > file_id => bail!("snapshot file {file_id} is out of order"),
+            _ => {
+                self.sync_file()?;
+                self.file = Some(fs::File::create(self.dir.join(SNAPSHOT_DIR).join(build_data_file_name(file_id)))?);
+                self.file_ids.push(file_id);
+            }
+        }
+        self.file.as_mut().unwrap().write_all(data)?;
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    /// Writes the manifest, then renames the snapshot, so it is swapped in even after a crash.
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        self.sync_file()?;
+        let tmp_dir = self.dir.join(SNAPSHOT_DIR);
+        let mut manifest = format!("{}\n{}\n", self.last_index, self.last_term);
+        for file_id in &self.file_ids {
+            manifest.push_str(&format!("{file_id}\n"));
+        }
+        let mut file = fs::File::create(tmp_dir.join(SNAPSHOT_MANIFEST))?;
+        file.write_all(manifest.as_bytes())?;
+        file.sync_all()?;
+        sync_dir(&tmp_dir)?;
+
+        fs::rename(&tmp_dir, self.dir.join(SNAPSHOT_READY_DIR)).context("snapshot rename failed")?;
+        sync_dir(&self.dir)
+    }
+
+    fn sync_file(&mut self) -> anyhow::Result<()> {
+        if let Some(file) = self.file.take() {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// Replaces the data files of the store with the written snapshot, and returns its last index and
+/// term. Returns `None` if there is no snapshot to install. Hint files, and manifests of interrupted
+/// merges and bulk loads are removed with the replaced files. The store must be closed.
+pub(crate) fn swap_in_snapshot(dir: &Path) -> anyhow::Result<Option<(u64, u64)>> {
+    let ready_dir = dir.join(SNAPSHOT_READY_DIR);
+    let content = match fs::read_to_string(ready_dir.join(SNAPSHOT_MANIFEST)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let ids: Vec<u64> = content.lines().map(str::parse).collect::<Result<_, _>>().context("snapshot manifest is corrupt")?;
+    let [last_index, last_term, file_ids @ ..] = ids.as_slice() else {
+        bail!("snapshot manifest is corrupt");
+    };
+
+    // files of the snapshot are kept, they are moved already if a swap is interrupted
+    let file_ids: HashSet<u64> = file_ids.iter().copied().collect();
+    for file_id in extract_data_file_ids(dir)?.filter(|id| !file_ids.contains(id)) {
+        fs::remove_file(dir.join(build_data_file_name(file_id)))?;
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let stale = path.extension().is_some_and(|ext| ext == "hint" || ext == "merge" || ext == "load")
+            || path.file_name().is_some_and(|name| name == MERGE_MANIFEST || name == LOAD_MANIFEST);
+        if stale && path.is_file() {
+            fs::remove_file(path)?;
+        }
+    }
+    for file_id in &file_ids {
+        let name = build_data_file_name(*file_id);
+        if ready_dir.join(&name).exists() {
+            fs::rename(ready_dir.join(&name), dir.join(&name)).context("snapshot file rename failed")?;
+        }
+    }
+    sync_dir(dir)?;
+    Ok(Some((*last_index, *last_term)))
+}
+
+/// Removes the snapshot after the member state points to it.
+pub(crate) fn remove_snapshot(dir: &Path) -> anyhow::Result<()> {
+    fs::remove_dir_all(dir.join(SNAPSHOT_READY_DIR))?;
+    sync_dir(dir)
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+
+    use crate::storage::raft::{Command, Entry};
+
+    use super::{HardState, RaftStorage};
+
+    #[test]
+    fn it_should_reload_state_and_log() {
+        // given
+        let dir = TempDir::new("bitcask-").unwrap().into_path();
+        let entries: Vec<Entry> = (1..=4)
+            .map(|index| Entry { term: 2, index, command: Command::Put { key: format!("k{index}").into_bytes(), val: b"v".to_vec() } })
+            .collect();
+        let state = HardState { term: 3, voted_for: Some(2), snapshot_index: 1, snapshot_term: 2 };
+        {
+            let (mut storage, _, _) = RaftStorage::open(&dir).unwrap();
+            storage.append(&entries[..3]).unwrap();
+            storage.save_state(state).unwrap();
+            storage.rewrite(&entries[1..2]).unwrap();
+            storage.append(&[Entry { term: 3, index: 3, command: Command::Delete { key: b"k1".to_vec() } }]).unwrap();
+        }
+        // entry torn by a crash
+        std::fs::OpenOptions::new().append(true).open(dir.join("raft.log")).unwrap().set_len(1000).unwrap();
+
+        // when
+        let (mut storage, loaded, log) = RaftStorage::open(&dir).unwrap();
+
+        // then
+        assert_eq!(state, loaded);
+        assert_eq!(vec![2, 3], log.iter().map(|e| e.index).collect::<Vec<_>>());
+        assert_eq!(Command::Delete { key: b"k1".to_vec() }, log[1].command);
+
+        storage.append(&[Entry { term: 3, index: 4, command: Command::Noop }]).unwrap();
+        drop(storage);
+        let (_, _, log) = RaftStorage::open(&dir).unwrap();
+        assert_eq!(Some(&Entry { term: 3, index: 4, command: Command::Noop }), log.last());
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::Result;
+use crate::storage::{codec, Config, Header, KeyDir, Stats, utils};
+use crate::storage::log_reader::LogReader;
+use crate::storage::rebuild::rebuild_storage_read_only;
+use crate::storage::stats::FileStatsMap;
+
+/// Reads a store without taking its lock, so it can be opened next to a running writer.
+/// Keys are loaded on open and on [ReadOnlyHandle::refresh], writes made after that are not visible.
+pub struct ReadOnlyHandle<'a> {
+    conf: &'a Config,
+    key_dir: KeyDir,
+    file_stats: FileStatsMap,
+    readers: HashMap<u64, LogReader>,
+}
+
+impl<'a> ReadOnlyHandle<'a> {
+    pub fn open(conf: &'a Config) -> Result<ReadOnlyHandle<'a>> {
+        let (key_dir, file_stats) = rebuild_storage_read_only(&conf.path)?;
+        Ok(ReadOnlyHandle { conf, key_dir, file_stats, readers: HashMap::new() })
+    }
+
+    /// Loads the keys again to see the writes made since the handle was opened.
+    pub fn refresh(&mut self) -> Result<()> {
+        let (key_dir, file_stats) = rebuild_storage_read_only(&self.conf.path)?;
+        self.key_dir = key_dir;
+        self.file_stats = file_stats;
+        self.readers.clear();
+        Ok(())
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let Some(header) = self.live_header(key) else {
+            return Ok(None);
+        };
+
+        let val = match self.read(&header) {
+            Ok(val) => val,
+            // the file can be removed by a merge of the writer
+            Err(_) => {
+                self.refresh()?;
+                match self.live_header(key) {
+                    None => return Ok(None),
+                    Some(header) => self.read(&header)?,
+                }
+            }
+        };
+        Ok(Some(codec::decode_value(self.conf, key, header.flags, val)?))
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.live_header(key).is_some()
+    }
+
+    /// Same as [crate::storage::Handle::scan].
+    pub fn scan(&self, prefix: &[u8], after: Option<&[u8]>, limit: usize) -> Vec<Vec<u8>> {
+        let expiry_time = utils::expiry_time(self.conf.expiry_secs_of(None));
+        let now = utils::now_millis();
+        self.key_dir.scan(None, prefix, after)
+            .filter(|(_, header)| !header.is_expired(expiry_time, now))
+            .take(limit)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Same as [crate::storage::Handle::ttl].
+    pub fn ttl(&self, key: &[u8]) -> Option<Duration> {
+        self.live_header(key)?.ttl(self.conf.expiry_secs_of(None), utils::now_millis())
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats::new(&self.key_dir, &self.file_stats, self.conf)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.conf.path
+    }
+
+    fn live_header(&self, key: &[u8]) -> Option<Header> {
+        let expiry_time = utils::expiry_time(self.conf.expiry_secs_of(None));
+        self.key_dir.get(None, key)
+            .filter(|header| !header.is_expired(expiry_time, utils::now_millis()))
+            .copied()
+    }
+
+    fn read(&mut self, header: &Header) -> anyhow::Result<Vec<u8>> {
+        if !self.readers.contains_key(&header.file_id) {
+            self.readers.insert(header.file_id, LogReader::new(&self.conf.path, header.file_id)?);
+        }
+        self.readers[&header.file_id].read(header.val_offset, header.val_size)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use tempdir::TempDir;
+
+    use crate::storage::{Config, Handle};
+
+    use super::ReadOnlyHandle;
+
+    #[test]
+    fn it_should_read_next_to_a_running_writer() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        let mut handle = Handle::open(&conf).unwrap();
+        handle.put(b"k1", b"v1").unwrap();
+        handle.put(b"k2", b"v2").unwrap();
+        handle.flush().unwrap();
+
+        // when
+        let mut read_only = ReadOnlyHandle::open(&conf).unwrap();
+        handle.put(b"k3", b"v3").unwrap();
+        handle.delete(b"k1").unwrap();
+        handle.flush().unwrap();
+
+        // then
+        assert_eq!(Some(b"v1".to_vec()), read_only.get(b"k1").unwrap());
+        assert_eq!(vec![b"k1".to_vec(), b"k2".to_vec()], read_only.scan(b"k", None, 10));
+
+        read_only.refresh().unwrap();
+        assert_eq!(None, read_only.get(b"k1").unwrap());
+        assert_eq!(Some(b"v3".to_vec()), read_only.get(b"k3").unwrap());
+        assert_eq!(2, read_only.stats().live_keys);
+
+        // a record which is not completely written yet is skipped
+        let newest = std::fs::read_dir(&conf.path).unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.to_string_lossy().ends_with(".data"))
+            .max()
+            .unwrap();
+        OpenOptions::new().append(true).open(newest).unwrap().write_all(&[1, 2, 3]).unwrap();
+        read_only.refresh().unwrap();
+        assert_eq!(Some(b"v2".to_vec()), read_only.get(b"k2").unwrap());
+    }
+}
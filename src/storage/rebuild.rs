@@ -1,37 +1,118 @@
 use std::collections::BTreeSet;
 use std::ffi::OsStr;
 use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
 
-use crate::storage::KeyDir;
-use crate::storage::log::LogIterator;
+use log::warn;
+
+use crate::storage::{bucket, Header, KeyDir};
+use crate::storage::hint::{read_hints, remove_hint};
+use crate::storage::log::{find_next_record, LogEntry, LogIterator};
+use crate::storage::stats::{FileStatsMap, record_dead, record_delete, record_put};
 use crate::storage::utils::{build_data_file_name, open_file_for_read};
 
-pub fn rebuild_storage<P>(path: P) -> anyhow::Result<KeyDir> where P: AsRef<Path> {
+pub fn rebuild_storage<P>(path: P) -> anyhow::Result<(KeyDir, FileStatsMap)> where P: AsRef<Path> {
     let mut key_dir = KeyDir::new();
+    let mut file_stats = FileStatsMap::new();
     extract_data_file_ids(&path)?
         .try_for_each(|file_id| -> anyhow::Result<()> {
-            load_from_data_file(&path, file_id, &mut key_dir)
+            load_from_data_file(&path, file_id, &mut key_dir, &mut file_stats, false)
         })?;
-    Ok(key_dir)
+    Ok((key_dir, file_stats))
+}
+
+/// Like [rebuild_storage], but the newest file can end with a record which a running writer
+/// has not finished yet. Reading the file stops before such a record.
+pub(crate) fn rebuild_storage_read_only<P>(path: P) -> anyhow::Result<(KeyDir, FileStatsMap)> where P: AsRef<Path> {
+    let mut key_dir = KeyDir::new();
+    let mut file_stats = FileStatsMap::new();
+    let mut file_ids = extract_data_file_ids(&path)?.peekable();
+    while let Some(file_id) = file_ids.next() {
+        let is_newest = file_ids.peek().is_none();
+        load_from_data_file(&path, file_id, &mut key_dir, &mut file_stats, is_newest)?;
+    }
+    Ok((key_dir, file_stats))
+}
+
+/// Cuts a record which is not completely written off the end of the newest data file, e.g. after a
+/// crash in the middle of a write. A corrupt record which is followed by readable ones is kept, so
+/// opening the store fails until it is repaired. The directory must be locked by the caller.
+pub(crate) fn truncate_torn_tail<P>(path: P) -> anyhow::Result<()> where P: AsRef<Path> {
+    let Some(file_id) = extract_data_file_ids(&path)?.last() else {
+        return Ok(());
+    };
+    let file_name = build_data_file_name(file_id);
+    let Some(Err(e)) = LogIterator::new(file_id, open_file_for_read(&path, &file_name)?).find(Result::is_err) else {
+        return Ok(());
+    };
+    let Some(&crate::Error::Corrupt { offset, .. }) = e.downcast_ref() else {
+        return Err(e);
+    };
+
+    let mut file = OpenOptions::new().read(true).write(true).open(path.as_ref().join(&file_name))?;
+    if !is_torn_tail(&mut file, offset)? {
+        return Ok(());
+    }
+
+    warn!("truncating {} bytes of an incomplete record at {} of {}", file.metadata()?.len() - offset, offset, file_name);
+    remove_hint(path.as_ref(), file_id)?;
+    file.set_len(offset)?;
+    file.sync_all()?;
+    Ok(())
 }
 
-fn load_from_data_file<P>(path: P, file_id: u64, key_dir: &mut KeyDir) -> anyhow::Result<()>
+/// Returns true if no readable record follows the corrupt record at `offset`, so the record may
+/// be the one a writer is in the middle of, or the one torn by a crash.
+pub(crate) fn is_torn_tail(file: &mut File, offset: u64) -> anyhow::Result<bool> {
+    let mut tail = Vec::new();
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_to_end(&mut tail)?;
+    Ok(find_next_record(&tail, 1).is_none())
+}
+
+fn load_from_data_file<P>(path: P, file_id: u64, key_dir: &mut KeyDir, file_stats: &mut FileStatsMap, partial_tail: bool) -> anyhow::Result<()>
     where P: AsRef<Path> {
+    file_stats.entry(file_id).or_default();
+    if let Some(hints) = read_hints(path.as_ref(), file_id)? {
+        return hints.into_iter().try_for_each(|(record_key, header)| apply_record(key_dir, file_stats, &record_key, &[], header));
+    }
+
     let file = open_file_for_read(path, &build_data_file_name(file_id))?;
     LogIterator::new(file_id, file)
+        .take_while(|result| !partial_tail || !matches!(result, Err(e) if matches!(e.downcast_ref(), Some(crate::Error::Corrupt { .. }))))
         .try_for_each(|result| -> anyhow::Result<()> {
-            let (key, header) = result?;
-            // println!("{:?}, {:?}", header,  std::str::from_utf8(&key));
-            key_dir.insert(key, header);
-
-            Ok(())
+            let LogEntry { key: record_key, val, header } = result?;
+            apply_record(key_dir, file_stats, &record_key, &val, header)
         })?;
 
     Ok(())
 }
 
-fn extract_data_file_ids<P>(path: P) -> anyhow::Result<impl Iterator<Item=u64>> where P: AsRef<Path> {
+/// Applies a record to the key directory. `val` is only read for bucket drop markers.
+pub(crate) fn apply_record(key_dir: &mut KeyDir, file_stats: &mut FileStatsMap, record_key: &[u8], val: &[u8], header: Header) -> anyhow::Result<()> {
+    let (bucket, key) = bucket::split_record_key(header.flags, record_key)?;
+    if header.is_bucket_drop() {
+        // a merge can move the marker after the records written once the bucket is dropped
+        let (file_id, offset) = bucket::decode_drop_marker(val)?;
+        let name = bucket.unwrap_or_default();
+        for (key, dropped) in key_dir.drop_bucket_before(name, file_id, offset) {
+            record_dead(file_stats, 1 + name.len() + key.len(), &dropped);
+        }
+        record_delete(file_stats, record_key.len(), &header, None);
+    } else if header.is_tombstone() {
+        let removed = key_dir.remove(bucket, key);
+        record_delete(file_stats, record_key.len(), &header, removed.as_ref());
+    } else {
+        let replaced = key_dir.insert(bucket, key.to_vec(), header);
+        record_put(file_stats, record_key.len(), &header, replaced.as_ref());
+    }
+
+    Ok(())
+}
+
+pub(crate) fn extract_data_file_ids<P>(path: P) -> anyhow::Result<impl Iterator<Item=u64>> where P: AsRef<Path> {
     Ok(fs::read_dir(path)?
         .filter_map(Result::ok)
         .map(|e| e.path())
@@ -0,0 +1,203 @@
+use std::fs;
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::storage::bucket;
+use crate::storage::hint::remove_hint;
+use crate::storage::log::{find_next_record, FLAG_BUCKET_DROP, KEY_OFFSET, read_sizes, valid_record_size};
+use crate::storage::log_writer::create_entry;
+use crate::storage::rebuild::extract_data_file_ids;
+use crate::storage::utils::{build_data_file_name, now_millis, sync_dir};
+
+/// Result of [crate::storage::Handle::repair].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RepairReport {
+    /// Rewritten data files. Files without corrupt records are not touched.
+    pub files: Vec<RepairedFile>,
+    /// Directory which keeps the original of the rewritten files.
+    pub quarantine: Option<PathBuf>,
+    /// Keys of the records which are dropped. Their latest value or deletion may be lost.
+    pub lost_keys: Vec<LostKey>,
+    /// Size of the dropped bytes in which no key can be found.
+    pub unknown_bytes: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RepairedFile {
+    pub file_id: u64,
+    pub records: u64,
+    pub dropped_bytes: u64,
+}
+
+/// Key of a dropped record. The key itself can be damaged if its record is.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LostKey {
+    pub file_id: u64,
+    pub offset: u64,
+    pub bucket: Option<Vec<u8>>,
+    pub key: Vec<u8>,
+}
+
+/// Rewrites the data files which have corrupt records with their readable records only.
+/// The directory must be locked by the caller.
+pub(crate) fn repair_data_files(dir: &Path) -> anyhow::Result<RepairReport> {
+    let mut report = RepairReport::default();
+    let quarantine = dir.join("quarantine").join(now_millis().to_string());
+    for file_id in extract_data_file_ids(dir)? {
+        let file_name = build_data_file_name(file_id);
+        let data = fs::read(dir.join(&file_name)).with_context(|| format!("{file_name} cannot be read"))?;
+        let Some(salvaged) = salvage(file_id, &data, &mut report) else {
+            continue;
+        };
+
+        let tmp_path = dir.join(format!("{file_name}.repair"));
+        let mut tmp = fs::File::create(&tmp_path)?;
+        tmp.write_all(&salvaged)?;
+        tmp.sync_all()?;
+
+        // original is linked first, so it is kept if the process stops before the rename
+        fs::create_dir_all(&quarantine)?;
+        fs::hard_link(dir.join(&file_name), quarantine.join(&file_name)).context("data file cannot be quarantined")?;
+        sync_dir(&quarantine)?;
+        remove_hint(dir, file_id)?;
+        fs::rename(&tmp_path, dir.join(&file_name)).context("repaired file rename failed")?;
+        sync_dir(dir)?;
+        report.quarantine = Some(quarantine.clone());
+    }
+    Ok(report)
+}
+
+/// Returns the readable records of the file, or `None` if all records are readable.
+fn salvage(file_id: u64, data: &[u8], report: &mut RepairReport) -> Option<Vec<u8>> {
+    let mut kept: Vec<Range<usize>> = Vec::new();
+    let mut dropped: Vec<Range<usize>> = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        match valid_record_size(&data[offset..]).filter(|size| split_key(&data[offset..offset + size]).is_some()) {
+            Some(size) => {
+                kept.push(offset..offset + size);
+                offset += size;
+            }
+            None => {
+                let end = find_next_record(data, offset + 1).unwrap_or(data.len());
+                dropped.push(offset..end);
+                offset = end;
+            }
+        }
+    }
+
+    if dropped.is_empty() {
+        return None;
+    }
+
+    let mut file = RepairedFile { file_id, records: kept.len() as u64, ..Default::default() };
+    for range in &dropped {
+        file.dropped_bytes += range.len() as u64;
+        report.unknown_bytes += find_lost_keys(file_id, data, range.clone(), &mut report.lost_keys);
+    }
+    report.files.push(file);
+
+    let mut salvaged = Vec::with_capacity(data.len());
+    for range in kept {
+        let record = &data[range.clone()];
+        let (flags, key_size, _) = read_sizes(record).unwrap();
+        if flags & FLAG_BUCKET_DROP == 0 {
+            salvaged.extend_from_slice(record);
+            continue;
+        }
+
+        // drop markers point to their own position, which moves if bytes before it are dropped
+        let val = &record[KEY_OFFSET + key_size..];
+        match bucket::decode_drop_marker(val) {
+            Ok((marker_file_id, marker_offset)) if marker_file_id == file_id => {
+                let removed: usize = dropped.iter().filter(|r| r.start < marker_offset as usize).map(|r| r.len()).sum();
+                let marker = bucket::encode_drop_marker(file_id, marker_offset - removed as u32);
+                let ts_tamp = u32::from_be_bytes(record[4..8].try_into().unwrap());
+                salvaged.extend_from_slice(&create_entry(&record[KEY_OFFSET..KEY_OFFSET + key_size], &marker, ts_tamp, flags));
+            }
+            _ => salvaged.extend_from_slice(record),
+        }
+    }
+    Some(salvaged)
+}
+
+/// Collects the keys of the records in the dropped range, following record sizes while the headers look intact.
+/// Returns the size of the bytes in which no key is found.
+fn find_lost_keys(file_id: u64, data: &[u8], range: Range<usize>, lost_keys: &mut Vec<LostKey>) -> u64 {
+    let mut offset = range.start;
+    while let Some((flags, key_size, val_size)) = read_sizes(&data[offset..range.end]) {
+        let key_end = offset + KEY_OFFSET + key_size;
+        if key_end > range.end {
+            break;
+        }
+        let Ok((bucket, key)) = bucket::split_record_key(flags, &data[offset + KEY_OFFSET..key_end]) else {
+            break;
+        };
+        lost_keys.push(LostKey { file_id, offset: offset as u64, bucket: bucket.map(<[u8]>::to_vec), key: key.to_vec() });
+
+        match key_end.checked_add(val_size) {
+            Some(end) if end < range.end => offset = end,
+            _ => return 0,
+        }
+    }
+    (range.end - offset) as u64
+}
+
+fn split_key(record: &[u8]) -> Option<()> {
+    let (flags, key_size, _) = read_sizes(record)?;
+    bucket::split_record_key(flags, &record[KEY_OFFSET..KEY_OFFSET + key_size]).ok().map(|_| ())
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+
+    use crate::storage::{Config, Handle};
+    use crate::storage::inspect::{Records, verify};
+
+    #[test]
+    fn it_should_keep_readable_records_and_report_lost_keys() {
+        // given
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        {
+            let mut handle = Handle::open(&conf).unwrap();
+            handle.put(b"k1", b"v1").unwrap();
+            handle.put(b"k2", b"v2").unwrap();
+            handle.bucket("b1").unwrap().put(b"k3", b"v3").unwrap();
+            handle.drop_bucket("b1").unwrap();
+            handle.bucket("b1").unwrap().put(b"k4", b"v4").unwrap();
+        }
+        let path = std::fs::read_dir(&conf.path).unwrap()
+            .map(|e| e.unwrap().path())
+            .find(|p| p.to_string_lossy().ends_with(".data"))
+            .unwrap();
+        let records: Vec<_> = Records::open(&path).unwrap().map(Result::unwrap).collect();
+        let mut content = std::fs::read(&path).unwrap();
+        content[records[1].offset as usize + records[1].size as usize - 1] ^= 0xff;
+        std::fs::write(&path, content).unwrap();
+
+        // when
+        let report = Handle::repair(&conf.path).unwrap();
+
+        // then
+        assert_eq!(1, report.files.len());
+        assert_eq!(records[1].size, report.files[0].dropped_bytes);
+        assert_eq!(vec![b"k2".to_vec()], report.lost_keys.iter().map(|k| k.key.clone()).collect::<Vec<_>>());
+        assert_eq!(0, report.unknown_bytes);
+        assert!(verify(&conf.path).unwrap().is_ok());
+        assert!(report.quarantine.unwrap().join(path.file_name().unwrap()).exists());
+
+        let mut handle = Handle::open(&conf).unwrap();
+        assert_eq!(Some(b"v1".to_vec()), handle.get(b"k1").unwrap());
+        assert_eq!(None, handle.get(b"k2").unwrap());
+        assert_eq!(None, handle.bucket("b1").unwrap().get(b"k3").unwrap());
+        assert_eq!(Some(b"v4".to_vec()), handle.bucket("b1").unwrap().get(b"k4").unwrap());
+    }
+}
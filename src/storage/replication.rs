@@ -0,0 +1,684 @@
+// Log shipping between a leader and followers.
+//
+// Follower sends the id, size and crc of its data files. Leader keeps the files whose content is
+// a prefix of its own file, replaces the others, then polls the data directory and ships appended
+// records. Only complete records are shipped from the active file, partial ones wait for the next poll.
+// Merged files are detected by their inode and shipped again under a temporary name. Follower renames
+// it over the replaced file and removes the merged files with the manifest of merge.rs. Merged file ids
+// are read from the leader manifest, which is kept until the merged files are removed.
+//
+// frame: [type|size|payload]
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use bytes::{Buf, BufMut};
+use log::{error, info};
+
+use crate::Result;
+use crate::storage::{Config, file_lock, Handle};
+use crate::storage::file_lock::FileLock;
+use crate::storage::hint::remove_hint;
+use crate::storage::log::LogIterator;
+use crate::storage::merge::{build_merge_file_name, is_committed, read_manifest, recover_merge, remove_merged_files, write_manifest};
+use crate::storage::rebuild::extract_data_file_ids;
+use crate::storage::utils::{build_data_file_name, open_file_for_read, sync_dir};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+const CHUNK_SIZE: u64 = 1 << 20;
+
+const MSG_HELLO: u8 = 1;
+const MSG_REMOVE: u8 = 2;
+const MSG_TRUNCATE: u8 = 3;
+const MSG_WRITE: u8 = 4;
+const MSG_MERGE_START: u8 = 5;
+const MSG_MERGE_WRITE: u8 = 6;
+const MSG_MERGE_COMMIT: u8 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileState {
+    file_id: u64,
+    size: u64,
+    crc: u32,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Message {
+    /// Data files of the follower.
+    Hello(Vec<FileState>),
+    Remove { file_id: u64 },
+    /// Creates the file if it does not exist and sets its size.
+    Truncate { file_id: u64, size: u64 },
+    Write { file_id: u64, offset: u64, data: Vec<u8> },
+    /// Creates an empty merge output which replaces the file.
+    MergeStart { file_id: u64 },
+    MergeWrite { file_id: u64, offset: u64, data: Vec<u8> },
+    /// Replaces the file with the merge output and removes the merged files.
+    MergeCommit { file_id: u64, merged: Vec<u64> },
+}
+
+fn write_message(out: &mut impl Write, msg: &Message) -> anyhow::Result<()> {
+    let mut payload = Vec::new();
+    let kind = match msg {
+        Message::Hello(files) => {
+            for f in files {
+                payload.put_u64(f.file_id);
+                payload.put_u64(f.size);
+                payload.put_u32(f.crc);
+            }
+            MSG_HELLO
+        }
+        Message::Remove { file_id } => {
+            payload.put_u64(*file_id);
+            MSG_REMOVE
+        }
+        Message::Truncate { file_id, size } => {
+            payload.put_u64(*file_id);
+            payload.put_u64(*size);
+            MSG_TRUNCATE
+        }
+        Message::Write { file_id, offset, data } => {
+            payload.put_u64(*file_id);
+            payload.put_u64(*offset);
+            payload.put_slice(data);
+            MSG_WRITE
+        }
+        Message::MergeStart { file_id } => {
+            payload.put_u64(*file_id);
+            MSG_MERGE_START
+        }
+        Message::MergeWrite { file_id, offset, data } => {
+            payload.put_u64(*file_id);
+            payload.put_u64(*offset);
+            payload.put_slice(data);
+            MSG_MERGE_WRITE
+        }
+        Message::MergeCommit { file_id, merged } => {
+            payload.put_u64(*file_id);
+            merged.iter().for_each(|id| payload.put_u64(*id));
+            MSG_MERGE_COMMIT
+        }
+    };
+
+    out.write_all(&[kind])?;
+    out.write_all(&(payload.len() as u32).to_be_bytes())?;
+    out.write_all(&payload)?;
+    Ok(())
+}
+
+fn read_message(input: &mut impl Read) -> anyhow::Result<Message> {
+    let mut frame_header = [0u8; 5];
+    input.read_exact(&mut frame_header)?;
+    let size = u32::from_be_bytes(frame_header[1..].try_into()?) as usize;
+    let mut payload = vec![0u8; size];
+    input.read_exact(&mut payload)?;
+
+    let mut buf = payload.as_slice();
+    let msg = match frame_header[0] {
+        MSG_HELLO if size.is_multiple_of(20) => {
+            let files = (0..size / 20)
+                .map(|_| FileState { file_id: buf.get_u64(), size: buf.get_u64(), crc: buf.get_u32() })
+                .collect();
+            Message::Hello(files)
+        }
+        MSG_REMOVE if size == 8 => Message::Remove { file_id: buf.get_u64() },
+        MSG_TRUNCATE if size == 16 => Message::Truncate { file_id: buf.get_u64(), size: buf.get_u64() },
+        MSG_WRITE if size >= 16 => Message::Write { file_id: buf.get_u64(), offset: buf.get_u64(), data: buf.to_vec() },
+        MSG_MERGE_START if size == 8 => Message::MergeStart { file_id: buf.get_u64() },
+        MSG_MERGE_WRITE if size >= 16 => Message::MergeWrite { file_id: buf.get_u64(), offset: buf.get_u64(), data: buf.to_vec() },
+        MSG_MERGE_COMMIT if size >= 8 && size.is_multiple_of(8) => {
+            let file_id = buf.get_u64();
+            Message::MergeCommit { file_id, merged: (1..size / 8).map(|_| buf.get_u64()).collect() }
+        }
+        kind => bail!("invalid replication message: type={kind}, size={size}"),
+    };
+    Ok(msg)
+}
+
+/// Returns the crc of the first `size` bytes of the file.
+fn file_crc(path: &Path, size: u64) -> anyhow::Result<u32> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut reader = BufReader::new(fs::File::open(path)?.take(size));
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        match reader.read(&mut buf)? {
+            0 => break,
+            n => hasher.update(&buf[..n]),
+        }
+    }
+    Ok(hasher.finalize())
+}
+
+/// Serves the data files of a store to followers. It reads the data directory without locking it,
+/// so it runs next to the [Handle] writing to the store.
+pub struct Leader {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    acceptor: Option<thread::JoinHandle<()>>,
+}
+
+impl Leader {
+    pub fn start(conf: &Config, addr: impl ToSocketAddrs) -> Result<Leader> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let dir = conf.path.clone();
+        let acceptor = {
+            let stop = stop.clone();
+            thread::spawn(move || accept_followers(listener, dir, stop))
+        };
+
+        info!("replication leader is listening on {addr}");
+        Ok(Leader { addr, stop, acceptor: Some(acceptor) })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(acceptor) = self.acceptor.take() {
+            let _ = acceptor.join();
+        }
+    }
+}
+
+fn accept_followers(listener: TcpListener, dir: PathBuf, stop: Arc<AtomicBool>) {
+    let mut followers = Vec::new();
+    while !stop.load(Ordering::Acquire) {
+        match listener.accept() {
+            Ok((stream, addr)) => {
+                info!("follower connected: {addr}");
+                let dir = dir.clone();
+                let stop = stop.clone();
+                followers.push(thread::spawn(move || {
+                    if let Err(e) = serve_follower(stream, dir, stop) {
+                        info!("follower {addr} is disconnected: {e:?}");
+                    }
+                }));
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+            Err(e) => error!("follower connection failed: {e:?}"),
+        }
+    }
+
+    for follower in followers {
+        let _ = follower.join();
+    }
+}
+
+fn serve_follower(stream: TcpStream, dir: PathBuf, stop: Arc<AtomicBool>) -> anyhow::Result<()> {
+    stream.set_nonblocking(false)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let Message::Hello(files) = read_message(&mut reader)? else {
+        bail!("follower must start with hello");
+    };
+
+    let mut shipper = Shipper { dir, out: BufWriter::new(stream), shipped: BTreeMap::new(), merged: BTreeSet::new() };
+    shipper.handshake(files)?;
+    while !stop.load(Ordering::Acquire) {
+        shipper.ship_changes()?;
+        thread::sleep(POLL_INTERVAL);
+    }
+    Ok(())
+}
+
+/// Size and inode of a file on the follower.
+#[derive(Debug, Clone, Copy)]
+struct Shipped {
+    size: u64,
+    ino: u64,
+}
+
+// a follower file which does not match the leader file is replaced, because no leader file has this inode
+const STALE_INODE: u64 = u64::MAX;
+
+struct Shipper {
+    dir: PathBuf,
+    out: BufWriter<TcpStream>,
+    shipped: BTreeMap<u64, Shipped>,
+    // files which are removed from the follower but not from the leader yet
+    merged: BTreeSet<u64>,
+}
+
+impl Shipper {
+    fn handshake(&mut self, files: Vec<FileState>) -> anyhow::Result<()> {
+        for FileState { file_id, size, crc } in files {
+            let path = self.dir.join(build_data_file_name(file_id));
+            let shipped = match fs::metadata(&path) {
+                Ok(meta) if meta.len() >= size && file_crc(&path, size)? == crc => Shipped { size, ino: meta.ino() },
+                _ => Shipped { size: 0, ino: STALE_INODE },
+            };
+            self.shipped.insert(file_id, shipped);
+        }
+        self.ship_changes()
+    }
+
+    /// Ships new and replaced files, appended records, then removes the files which do not exist anymore.
+    fn ship_changes(&mut self) -> anyhow::Result<()> {
+        let mut current = BTreeMap::new();
+        for file_id in extract_data_file_ids(&self.dir)? {
+            // merge can remove the file after the listing
+            if let Ok(meta) = fs::metadata(self.dir.join(build_data_file_name(file_id))) {
+                current.insert(file_id, meta.ino());
+            }
+        }
+        self.merged.retain(|id| current.contains_key(id));
+        let mut removed: BTreeSet<u64> = self.shipped.keys().filter(|id| !current.contains_key(id)).copied().collect();
+
+        for (file_id, ino) in &current {
+            if self.merged.contains(file_id) {
+                continue;
+            }
+            let result = match self.shipped.get(file_id) {
+                Some(shipped) if shipped.ino == *ino => self.ship_records(*file_id, shipped.size, false),
+                Some(_) => self.ship_merge_output(*file_id, *ino, &mut removed),
+                None => {
+                    write_message(&mut self.out, &Message::Truncate { file_id: *file_id, size: 0 })?;
+                    self.ship_records(*file_id, 0, false)
+                }
+            };
+
+            let size = match result {
+                Ok(size) => size,
+                Err(e) if is_not_found(&e) => continue,
+                Err(e) => return Err(e),
+            };
+            self.shipped.insert(*file_id, Shipped { size, ino: *ino });
+        }
+
+        for file_id in removed {
+            write_message(&mut self.out, &Message::Remove { file_id })?;
+            self.shipped.remove(&file_id);
+        }
+
+        self.out.flush()?;
+        Ok(())
+    }
+
+    /// Ships the file which replaces a follower file, then the follower swaps it in and removes the
+    /// merged files.
+    fn ship_merge_output(&mut self, file_id: u64, ino: u64, removed: &mut BTreeSet<u64>) -> anyhow::Result<u64> {
+        let merged = self.merged_files(file_id, ino)?;
+        write_message(&mut self.out, &Message::MergeStart { file_id })?;
+        let size = self.ship_records(file_id, 0, true)?;
+
+        for id in &merged {
+            removed.remove(id);
+            self.shipped.remove(id);
+            if self.dir.join(build_data_file_name(*id)).exists() {
+                self.merged.insert(*id);
+            }
+        }
+        write_message(&mut self.out, &Message::MergeCommit { file_id, merged })?;
+        Ok(size)
+    }
+
+    /// Returns the files merged into the output with the inode `ino`. While the leader removes them,
+    /// they are listed in its manifest. After that, all of them are removed.
+    fn merged_files(&self, file_id: u64, ino: u64) -> anyhow::Result<Vec<u64>> {
+        if let Some((output_id, merged)) = read_manifest(&self.dir)? {
+            // the manifest belongs to a later merge if the output is replaced again
+            let current = fs::metadata(self.dir.join(build_data_file_name(file_id)))?.ino();
+            if output_id == file_id && is_committed(&self.dir, output_id) && current == ino {
+                return Ok(merged.into_iter().filter(|id| *id != file_id).collect());
+            }
+        }
+
+        Ok(self.shipped.keys()
+            .filter(|id| **id < file_id && !self.dir.join(build_data_file_name(**id)).exists())
+            .copied()
+            .collect())
+    }
+
+    /// Ships the complete records after `from` and returns the shipped size of the file.
+    /// Records of a merge output are written to its temporary file.
+    fn ship_records(&mut self, file_id: u64, from: u64, merge: bool) -> anyhow::Result<u64> {
+        let file = open_file_for_read(&self.dir, &build_data_file_name(file_id))?;
+        if file.metadata()?.len() <= from {
+            return Ok(from);
+        }
+
+        let mut end = from;
+        for entry in LogIterator::with_offset(file_id, file.try_clone()?, from)? {
+            match entry {
+                Ok(entry) => end = entry.header.val_offset as u64 + entry.header.val_size as u64,
+                Err(_) => break,
+            }
+        }
+
+        let mut file = file;
+        file.seek(SeekFrom::Start(from))?;
+        let mut offset = from;
+        while offset < end {
+            let mut data = vec![0u8; (end - offset).min(CHUNK_SIZE) as usize];
+            file.read_exact(&mut data)?;
+            let size = data.len() as u64;
+            let msg = if merge { Message::MergeWrite { file_id, offset, data } } else { Message::Write { file_id, offset, data } };
+            write_message(&mut self.out, &msg)?;
+            offset += size;
+        }
+
+        Ok(end)
+    }
+}
+
+fn is_not_found(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound)
+}
+
+/// Keeps a copy of the leader data files in `conf.path` until it is promoted.
+/// The data directory is locked, so the store cannot be opened while following.
+pub struct Follower<'a> {
+    conf: &'a Config,
+    stream: TcpStream,
+    files: Arc<Mutex<BTreeMap<u64, u64>>>,
+    applier: Option<thread::JoinHandle<anyhow::Result<()>>>,
+    // declared last, so the directory is unlocked after the applier is stopped
+    _lock: FileLock,
+}
+
+impl<'a> Follower<'a> {
+    pub fn start(conf: &'a Config, leader: impl ToSocketAddrs) -> Result<Follower<'a>> {
+        fs::create_dir_all(&conf.path).context("data directory creation failed")?;
+        let lock = file_lock::try_lock_db(&conf.path)?;
+        recover_merge(&conf.path)?;
+
+        let mut files = BTreeMap::new();
+        let mut states = Vec::new();
+        for file_id in extract_data_file_ids(&conf.path)? {
+            let path = conf.path.join(build_data_file_name(file_id));
+            let size = fs::metadata(&path)?.len();
+            states.push(FileState { file_id, size, crc: file_crc(&path, size)? });
+            files.insert(file_id, size);
+        }
+
+        let mut stream = TcpStream::connect(leader)?;
+        write_message(&mut stream, &Message::Hello(states))?;
+
+        let files = Arc::new(Mutex::new(files));
+        let applier = {
+            let dir = conf.path.clone();
+            let reader = BufReader::new(stream.try_clone()?);
+            let files = files.clone();
+            thread::spawn(move || apply_messages(reader, dir, files))
+        };
+
+        Ok(Follower { conf, stream, files, applier: Some(applier), _lock: lock })
+    }
+
+    /// Sizes of the replicated data files by file id.
+    pub fn files(&self) -> BTreeMap<u64, u64> {
+        self.files.lock().unwrap().clone()
+    }
+
+    /// Returns false if the connection to the leader is lost.
+    pub fn is_connected(&self) -> bool {
+        self.applier.as_ref().is_some_and(|applier| !applier.is_finished())
+    }
+
+    /// Stops following and opens the replicated store, so it can serve writes and a [Leader] can be started on it.
+    pub fn promote(mut self) -> Result<Handle<'a>> {
+        let conf = self.conf;
+        self.stop();
+        drop(self);
+        Handle::open(conf)
+    }
+
+    fn stop(&mut self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+        if let Some(applier) = self.applier.take() {
+            if let Ok(Err(e)) = applier.join() {
+                info!("replication is stopped: {e:?}");
+            }
+        }
+    }
+}
+
+impl Drop for Follower<'_> {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn apply_messages(mut reader: BufReader<TcpStream>, dir: PathBuf, files: Arc<Mutex<BTreeMap<u64, u64>>>) -> anyhow::Result<()> {
+    loop {
+        match read_message(&mut reader)? {
+            Message::Hello(_) => bail!("unexpected hello from leader"),
+            Message::Remove { file_id } => {
+                match fs::remove_file(dir.join(build_data_file_name(file_id))) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+                files.lock().unwrap().remove(&file_id);
+            }
+            Message::Truncate { file_id, size } => {
+                let file = fs::OpenOptions::new().write(true).create(true).truncate(false).open(dir.join(build_data_file_name(file_id)))?;
+                file.set_len(size)?;
+                file.sync_all()?;
+                files.lock().unwrap().insert(file_id, size);
+            }
+            Message::Write { file_id, offset, data } => {
+                let mut file = fs::OpenOptions::new().write(true).create(true).truncate(false).open(dir.join(build_data_file_name(file_id)))?;
+                file.seek(SeekFrom::Start(offset))?;
+                file.write_all(&data)?;
+                file.sync_data()?;
+                files.lock().unwrap().insert(file_id, offset + data.len() as u64);
+            }
+            Message::MergeStart { file_id } => {
+                fs::File::create(dir.join(build_merge_file_name(file_id)))?;
+            }
+            Message::MergeWrite { file_id, offset, data } => {
+                let mut file = fs::OpenOptions::new().write(true).open(dir.join(build_merge_file_name(file_id)))?;
+                file.seek(SeekFrom::Start(offset))?;
+                file.write_all(&data)?;
+                file.sync_data()?;
+            }
+            Message::MergeCommit { file_id, merged } => {
+                // same steps as the merge on the leader, so recover_merge completes an interrupted commit
+                let tmp_path = dir.join(build_merge_file_name(file_id));
+                fs::File::open(&tmp_path)?.sync_all()?;
+                let merged: BTreeSet<u64> = merged.into_iter().collect();
+                write_manifest(&dir, file_id, &merged)?;
+                remove_hint(&dir, file_id)?;
+                fs::rename(&tmp_path, dir.join(build_data_file_name(file_id)))?;
+                sync_dir(&dir)?;
+                remove_merged_files(&dir, file_id, merged.iter().copied())?;
+
+                let size = fs::metadata(dir.join(build_data_file_name(file_id)))?.len();
+                let mut files = files.lock().unwrap();
+                files.insert(file_id, size);
+                merged.iter().for_each(|id| {
+                    files.remove(id);
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::path::Path;
+    use std::time::{Duration, Instant};
+
+    use tempdir::TempDir;
+
+    use crate::storage::{Config, Handle};
+    use crate::storage::merge::{build_merge_file_name, remove_merged_files, write_manifest};
+    use crate::storage::utils::build_data_file_name;
+
+    use super::{Follower, Leader, Message, FileState, read_message, write_message};
+
+    #[test]
+    fn it_should_encode_messages() {
+        let messages = vec![
+            Message::Hello(vec![FileState { file_id: 1, size: 2, crc: 3 }]),
+            Message::Remove { file_id: 1 },
+            Message::Truncate { file_id: 1, size: 10 },
+            Message::Write { file_id: 1, offset: 10, data: b"abc".to_vec() },
+            Message::MergeStart { file_id: 2 },
+            Message::MergeWrite { file_id: 2, offset: 0, data: b"abc".to_vec() },
+            Message::MergeCommit { file_id: 2, merged: vec![] },
+            Message::MergeCommit { file_id: 3, merged: vec![1, 2] },
+        ];
+
+        let mut buf = Vec::new();
+        messages.iter().for_each(|msg| write_message(&mut buf, msg).unwrap());
+
+        let mut input = buf.as_slice();
+        let decoded: Vec<Message> = messages.iter().map(|_| read_message(&mut input).unwrap()).collect();
+        assert_eq!(messages, decoded);
+    }
+
+    fn data_files(dir: &Path) -> BTreeMap<u64, u64> {
+        fs::read_dir(dir).unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.extension().is_some_and(|ext| ext == "data"))
+            .map(|p| (p.file_name().unwrap().to_string_lossy().split('.').next().unwrap().parse().unwrap(), fs::metadata(&p).unwrap().len()))
+            .collect()
+    }
+
+    fn wait_for_sync(follower: &Follower<'_>, leader_dir: &Path) {
+        let started = Instant::now();
+        while follower.files() != data_files(leader_dir) {
+            assert!(started.elapsed() < Duration::from_secs(10), "follower is not synced");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn it_should_replicate_and_promote_follower() {
+        // given
+        let leader_conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            max_file_size: 128,
+            ..Default::default()
+        };
+        let follower_conf = Config { path: TempDir::new("bitcask-").unwrap().into_path(), ..leader_conf.clone() };
+
+        let mut handle = Handle::open(&leader_conf).unwrap();
+        for i in 0..20 {
+            handle.put(format!("k{}", i % 5).as_bytes(), format!("old{i}").as_bytes()).unwrap();
+        }
+        let leader = Leader::start(&leader_conf, "127.0.0.1:0").unwrap();
+
+        // when
+        let follower = Follower::start(&follower_conf, leader.local_addr()).unwrap();
+        wait_for_sync(&follower, &leader_conf.path);
+
+        for i in 0..20 {
+            handle.put(format!("k{}", i % 10).as_bytes(), format!("new{i}").as_bytes()).unwrap();
+        }
+        handle.delete(b"k0").unwrap();
+        wait_for_sync(&follower, &leader_conf.path);
+        handle.merge().unwrap();
+        handle.put(b"last", b"value").unwrap();
+        wait_for_sync(&follower, &leader_conf.path);
+        assert!(follower.is_connected());
+        let names: Vec<_> = fs::read_dir(&follower_conf.path).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().to_string()).collect();
+        assert!(names.iter().all(|name| !name.contains("merge")), "{names:?}");
+
+        // then
+        drop(handle);
+        drop(leader);
+        let mut promoted = follower.promote().unwrap();
+        assert_eq!(None, promoted.get(b"k0").unwrap());
+        assert_eq!(Some(b"new19".to_vec()), promoted.get(b"k9").unwrap());
+        assert_eq!(Some(b"value".to_vec()), promoted.get(b"last").unwrap());
+        promoted.put(b"k1", b"promoted").unwrap();
+        assert_eq!(Some(b"promoted".to_vec()), promoted.get(b"k1").unwrap());
+    }
+
+    #[test]
+    fn it_should_resume_from_existing_files() {
+        // given
+        let leader_conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            max_file_size: 128,
+            ..Default::default()
+        };
+        let follower_conf = Config { path: TempDir::new("bitcask-").unwrap().into_path(), ..leader_conf.clone() };
+        let mut handle = Handle::open(&leader_conf).unwrap();
+        let leader = Leader::start(&leader_conf, "127.0.0.1:0").unwrap();
+        for i in 0..20 {
+            handle.put(format!("k{i}").as_bytes(), b"v1").unwrap();
+        }
+
+        let follower = Follower::start(&follower_conf, leader.local_addr()).unwrap();
+        wait_for_sync(&follower, &leader_conf.path);
+        drop(follower);
+
+        // when: a follower file is diverged, e.g. it is written after a failover
+        let (stale_id, _) = data_files(&follower_conf.path).into_iter().next().unwrap();
+        fs::write(follower_conf.path.join(format!("{stale_id}.bitcask.data")), b"stale").unwrap();
+        fs::write(follower_conf.path.join("1.bitcask.data"), b"unknown").unwrap();
+        handle.put(b"k0", b"v2").unwrap();
+
+        // then
+        let follower = Follower::start(&follower_conf, leader.local_addr()).unwrap();
+        wait_for_sync(&follower, &leader_conf.path);
+        drop(handle);
+        let mut promoted = follower.promote().unwrap();
+        assert_eq!(Some(b"v2".to_vec()), promoted.get(b"k0").unwrap());
+        assert_eq!(Some(b"v1".to_vec()), promoted.get(b"k19").unwrap());
+    }
+
+    #[test]
+    fn it_should_remove_merged_files_listed_before_their_removal() {
+        // given
+        let leader_conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            max_file_size: 128,
+            ..Default::default()
+        };
+        let follower_conf = Config { path: TempDir::new("bitcask-").unwrap().into_path(), ..leader_conf.clone() };
+        let mut handle = Handle::open(&leader_conf).unwrap();
+        for i in 0..20 {
+            handle.put(format!("k{i}").as_bytes(), b"v1").unwrap();
+        }
+        drop(handle);
+        let leader = Leader::start(&leader_conf, "127.0.0.1:0").unwrap();
+        let follower = Follower::start(&follower_conf, leader.local_addr()).unwrap();
+        wait_for_sync(&follower, &leader_conf.path);
+
+        // when: the merge output is renamed, but the merged file is not removed yet
+        let dir = &leader_conf.path;
+        let ids: Vec<u64> = data_files(dir).into_keys().take(2).collect();
+        let (merged_id, output_id) = (ids[0], ids[1]);
+        fs::copy(dir.join(build_data_file_name(output_id)), dir.join(build_merge_file_name(output_id))).unwrap();
+        write_manifest(dir, output_id, &ids.iter().copied().collect()).unwrap();
+        fs::rename(dir.join(build_merge_file_name(output_id)), dir.join(build_data_file_name(output_id))).unwrap();
+
+        // then
+        let mut expected = data_files(dir);
+        expected.remove(&merged_id);
+        let started = Instant::now();
+        while follower.files() != expected {
+            assert!(started.elapsed() < Duration::from_secs(10), "merged file is not removed");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(expected, follower.files());
+        assert!(!follower_conf.path.join(build_data_file_name(merged_id)).exists());
+
+        remove_merged_files(dir, output_id, ids.into_iter()).unwrap();
+        wait_for_sync(&follower, dir);
+        assert!(follower.is_connected());
+    }
+}
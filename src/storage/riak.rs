@@ -0,0 +1,303 @@
+// Riak Bitcask files, integers are big endian:
+// data: [crc32|tstamp32|keysz16|valsz32|key|val], crc covers everything after itself
+// hint: [tstamp32|keysz16|totalsz32|tombstone1+offset63|key], newer versions end the file with an
+//       entry which has an empty key, the max offset and the crc of the previous entries as totalsz
+// Deletes are written with the value "bitcask_tombstone", or "bitcask_tombstone1" and
+// "bitcask_tombstone2" followed by a 32 bit file id.
+
+use std::collections::{BTreeMap, btree_map, hash_map, HashMap};
+use std::fs;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use log::warn;
+use serde::Serialize;
+
+use crate::storage::hint::build_hint_file_name;
+use crate::storage::rebuild::extract_data_file_ids;
+use crate::storage::utils::{build_data_file_name, open_file_for_read};
+
+const DATA_HEADER_SIZE: usize = 14;
+const HINT_HEADER_SIZE: usize = 18;
+const MAX_OFFSET: u64 = (1 << 63) - 1;
+const TOMBSTONE: &[u8] = b"bitcask_tombstone";
+
+/// Result of [crate::storage::Handle::import_bitcask].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct BitcaskImportReport {
+    /// Number of the read data files.
+    pub files: u64,
+    /// Number of the written keys.
+    pub keys: u64,
+    /// Number of the keys which are deleted by their latest record.
+    pub deleted: u64,
+    /// Size of the unreadable bytes at the end of data files, usually a partial record written at a crash.
+    pub corrupt_bytes: u64,
+}
+
+/// Key and location of a hint file entry.
+type Hint = (Vec<u8>, Location);
+
+/// Latest record of a key.
+pub(crate) struct Location {
+    file_id: u64,
+    offset: u64,
+    size: u64,
+    ts_tamp: u32,
+    tombstone: bool,
+}
+
+impl Location {
+    // merge output has greater file ids than the records written during the merge, so the
+    // timestamp is compared first like Bitcask does
+    fn is_newer_than(&self, other: &Location) -> bool {
+        (self.ts_tamp, self.file_id, self.offset) > (other.ts_tamp, other.file_id, other.offset)
+    }
+}
+
+/// Reads the latest record location of every key in the directory, from hint files when they
+/// are valid and from data files otherwise.
+pub(crate) fn read_key_dir(dir: &Path, report: &mut BitcaskImportReport) -> anyhow::Result<BTreeMap<Vec<u8>, Location>> {
+    let mut key_dir = BTreeMap::new();
+    for file_id in extract_data_file_ids(dir)? {
+        report.files += 1;
+        match read_hint_file(dir, file_id)? {
+            Some(hints) => hints.into_iter().for_each(|(key, location)| insert(&mut key_dir, key, location)),
+            None => report.corrupt_bytes += scan_data_file(dir, file_id, &mut key_dir)?,
+        }
+    }
+    Ok(key_dir)
+}
+
+fn insert(key_dir: &mut BTreeMap<Vec<u8>, Location>, key: Vec<u8>, location: Location) {
+    match key_dir.entry(key) {
+        btree_map::Entry::Occupied(mut e) => {
+            if location.is_newer_than(e.get()) {
+                e.insert(location);
+            }
+        }
+        btree_map::Entry::Vacant(e) => {
+            e.insert(location);
+        }
+    }
+}
+
+/// Returns `None` if there is no hint file, or it does not match the data file.
+fn read_hint_file(dir: &Path, file_id: u64) -> anyhow::Result<Option<Vec<Hint>>> {
+    let content = match fs::read(dir.join(build_hint_file_name(file_id))) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let data_size = fs::metadata(dir.join(build_data_file_name(file_id)))?.len();
+
+    let mut hints = Vec::new();
+    let mut position = 0;
+    while position < content.len() {
+        let entry = &content[position..];
+        if entry.len() < HINT_HEADER_SIZE {
+            return Ok(invalid_hint_file(file_id));
+        }
+        let ts_tamp = u32::from_be_bytes(entry[0..4].try_into().unwrap());
+        let key_size = u16::from_be_bytes(entry[4..6].try_into().unwrap()) as usize;
+        let size = u32::from_be_bytes(entry[6..10].try_into().unwrap()) as u64;
+        let packed = u64::from_be_bytes(entry[10..18].try_into().unwrap());
+        let end = HINT_HEADER_SIZE + key_size;
+        if entry.len() < end {
+            return Ok(invalid_hint_file(file_id));
+        }
+
+        if key_size == 0 && packed == MAX_OFFSET {
+            // size of the last entry is the crc of the file
+            if end != entry.len() || crc32fast::hash(&content[..position]) as u64 != size {
+                return Ok(invalid_hint_file(file_id));
+            }
+            break;
+        }
+
+        let offset = packed & MAX_OFFSET;
+        if offset + size > data_size {
+            return Ok(invalid_hint_file(file_id));
+        }
+        let location = Location { file_id, offset, size, ts_tamp, tombstone: packed > MAX_OFFSET };
+        hints.push((entry[HINT_HEADER_SIZE..end].to_vec(), location));
+        position += end;
+    }
+    Ok(Some(hints))
+}
+
+fn invalid_hint_file<T>(file_id: u64) -> Option<T> {
+    warn!("hint file of {} is invalid, reading the data file", file_id);
+    None
+}
+
+/// Reads the records of the data file until the first unreadable one. Returns the size of the unread bytes.
+fn scan_data_file(dir: &Path, file_id: u64, key_dir: &mut BTreeMap<Vec<u8>, Location>) -> anyhow::Result<u64> {
+    let file = open_file_for_read(dir, &build_data_file_name(file_id))?;
+    let file_size = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut header = [0u8; DATA_HEADER_SIZE];
+    let mut offset = 0;
+    while file_size - offset >= DATA_HEADER_SIZE as u64 {
+        reader.read_exact(&mut header)?;
+        let (crc, ts_tamp, key_size, val_size) = parse_data_header(&header);
+        let size = (DATA_HEADER_SIZE + key_size + val_size) as u64;
+        if size > file_size - offset {
+            break;
+        }
+
+        let mut body = vec![0u8; key_size + val_size];
+        reader.read_exact(&mut body)?;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header[4..]);
+        hasher.update(&body);
+        if hasher.finalize() != crc {
+            break;
+        }
+
+        let tombstone = is_tombstone(&body[key_size..]);
+        body.truncate(key_size);
+        insert(key_dir, body, Location { file_id, offset, size, ts_tamp, tombstone });
+        offset += size;
+    }
+
+    if offset < file_size {
+        warn!("data file {} has {} unreadable bytes at {}", file_id, file_size - offset, offset);
+    }
+    Ok(file_size - offset)
+}
+
+fn parse_data_header(header: &[u8]) -> (u32, u32, usize, usize) {
+    (
+        u32::from_be_bytes(header[0..4].try_into().unwrap()),
+        u32::from_be_bytes(header[4..8].try_into().unwrap()),
+        u16::from_be_bytes(header[8..10].try_into().unwrap()) as usize,
+        u32::from_be_bytes(header[10..14].try_into().unwrap()) as usize,
+    )
+}
+
+fn is_tombstone(val: &[u8]) -> bool {
+    val == TOMBSTONE || (val.len() == TOMBSTONE.len() + 5 && val.starts_with(TOMBSTONE) && matches!(val[TOMBSTONE.len()], b'1' | b'2'))
+}
+
+/// Reads values of the data files in a Riak Bitcask directory.
+pub(crate) struct DataFiles {
+    dir: PathBuf,
+    files: HashMap<u64, fs::File>,
+}
+
+impl DataFiles {
+    pub fn new(dir: &Path) -> Self {
+        Self { dir: dir.to_path_buf(), files: HashMap::new() }
+    }
+
+    /// Returns `None` if the key is deleted.
+    pub fn read_value(&mut self, key: &[u8], location: &Location) -> anyhow::Result<Option<Vec<u8>>> {
+        if location.tombstone {
+            return Ok(None);
+        }
+
+        let file = match self.files.entry(location.file_id) {
+            hash_map::Entry::Occupied(e) => e.into_mut(),
+            hash_map::Entry::Vacant(e) => e.insert(open_file_for_read(&self.dir, &build_data_file_name(location.file_id))?),
+        };
+        let mut record = vec![0u8; location.size as usize];
+        file.seek(SeekFrom::Start(location.offset))?;
+        file.read_exact(&mut record).with_context(|| format!("record at {}:{} cannot be read", location.file_id, location.offset))?;
+
+        let valid = record.len() >= DATA_HEADER_SIZE && {
+            let (crc, _, key_size, val_size) = parse_data_header(&record);
+            DATA_HEADER_SIZE + key_size + val_size == record.len()
+                && crc32fast::hash(&record[4..]) == crc
+                && &record[DATA_HEADER_SIZE..DATA_HEADER_SIZE + key_size] == key
+        };
+        if !valid {
+            bail!("record at {}:{} is corrupt", location.file_id, location.offset);
+        }
+
+        let val = record.split_off(DATA_HEADER_SIZE + key.len());
+        Ok(Some(val).filter(|val| !is_tombstone(val)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use bytes::BufMut;
+    use tempdir::TempDir;
+
+    use crate::storage::{Config, Handle};
+
+    const MAX_OFFSET: u64 = (1 << 63) - 1;
+
+    fn record(ts_tamp: u32, key: &[u8], val: &[u8]) -> Vec<u8> {
+        let mut record = vec![0u8; 4];
+        record.put_u32(ts_tamp);
+        record.put_u16(key.len() as u16);
+        record.put_u32(val.len() as u32);
+        record.put(key);
+        record.put(val);
+        let crc = crc32fast::hash(&record[4..]);
+        record[..4].copy_from_slice(&crc.to_be_bytes());
+        record
+    }
+
+    fn hint(ts_tamp: u32, key: &[u8], size: u32, offset: u64) -> Vec<u8> {
+        let mut hint = Vec::new();
+        hint.put_u32(ts_tamp);
+        hint.put_u16(key.len() as u16);
+        hint.put_u32(size);
+        hint.put_u64(offset);
+        hint.put(key);
+        hint
+    }
+
+    #[test]
+    fn it_should_import_latest_values_of_riak_bitcask_files() {
+        // given
+        let riak_dir = TempDir::new("riak-").unwrap().into_path();
+        let mut data = Vec::new();
+        for (ts_tamp, key, val) in [(10, "k1", "v1"), (10, "k2", "v2"), (11, "k1", "v1-new"), (12, "k3", "v3")] {
+            data.extend(record(ts_tamp, key.as_bytes(), val.as_bytes()));
+        }
+        data.extend(record(13, b"k3", b"bitcask_tombstone"));
+        data.extend(&record(14, b"k4", b"v4")[..10]);
+        fs::write(riak_dir.join("1.bitcask.data"), &data).unwrap();
+
+        // a merged file has older records than the active file
+        let mut tombstone = b"bitcask_tombstone2".to_vec();
+        tombstone.put_u32(1);
+        let merged = [record(9, b"k2", b"v2-old"), record(10, b"k5", b"v5"), record(15, b"k6", &tombstone)];
+        let merged_hints = [
+            hint(9, b"k2", merged[0].len() as u32, 0),
+            hint(10, b"k5", merged[1].len() as u32, merged[0].len() as u64),
+            hint(15, b"k6", merged[2].len() as u32, (1 << 63) | (merged[0].len() + merged[1].len()) as u64),
+        ].concat();
+        let trailer = hint(0, b"", crc32fast::hash(&merged_hints), MAX_OFFSET);
+        fs::write(riak_dir.join("2.bitcask.data"), merged.concat()).unwrap();
+        fs::write(riak_dir.join("2.bitcask.hint"), [merged_hints, trailer].concat()).unwrap();
+
+        let conf = Config {
+            path: TempDir::new("bitcask-").unwrap().into_path(),
+            ..Default::default()
+        };
+        let mut handle = Handle::open(&conf).unwrap();
+
+        // when
+        let report = handle.import_bitcask(&riak_dir).unwrap();
+
+        // then
+        assert_eq!(2, report.files);
+        assert_eq!(3, report.keys);
+        assert_eq!(2, report.deleted);
+        assert_eq!(10, report.corrupt_bytes);
+        assert_eq!(Some(b"v1-new".to_vec()), handle.get(b"k1").unwrap());
+        assert_eq!(Some(b"v2".to_vec()), handle.get(b"k2").unwrap());
+        assert_eq!(None, handle.get(b"k3").unwrap());
+        assert_eq!(None, handle.get(b"k4").unwrap());
+        assert_eq!(Some(b"v5".to_vec()), handle.get(b"k5").unwrap());
+        assert_eq!(None, handle.get(b"k6").unwrap());
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::BTreeMap;
+use std::mem::size_of;
+
+use serde::Serialize;
+
+use crate::storage::{Config, Header, KeyDir, utils};
+use crate::storage::key_dir::KeyMap;
+use crate::storage::log::KEY_OFFSET;
+
+pub(crate) type FileStatsMap = BTreeMap<u64, FileStats>;
+
+/// Counters of a single data file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct FileStats {
+    /// Size of all records in the file.
+    pub total_bytes: u64,
+    /// Size of the records which are pointed by the key directory.
+    pub live_bytes: u64,
+    /// Size of overwritten, deleted records and tombstones.
+    pub dead_bytes: u64,
+    pub live_keys: u64,
+    pub tombstones: u64,
+}
+
+impl FileStats {
+    /// Ratio of dead bytes to all bytes of the file.
+    pub fn dead_ratio(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 0.0;
+        }
+        self.dead_bytes as f64 / self.total_bytes as f64
+    }
+}
+
+/// Snapshot of the store returned by [crate::storage::Handle::stats].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Stats {
+    pub live_keys: u64,
+    /// Keys which are expired but not deleted yet. They are deleted on read or merge.
+    pub expired_keys: u64,
+    /// Approximate memory used by the key directory in bytes.
+    pub key_dir_memory: u64,
+    pub oldest_file_id: Option<u64>,
+    pub newest_file_id: Option<u64>,
+    pub total_bytes: u64,
+    pub live_bytes: u64,
+    pub dead_bytes: u64,
+    pub tombstones: u64,
+    pub files: BTreeMap<u64, FileStats>,
+}
+
+/// Counters of a bucket returned by [crate::storage::Bucket::stats].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct BucketStats {
+    pub live_keys: u64,
+    pub expired_keys: u64,
+    /// Approximate memory used by the key directory of the bucket in bytes.
+    pub key_dir_memory: u64,
+    /// Size of the records which are pointed by the bucket keys.
+    pub live_bytes: u64,
+}
+
+impl BucketStats {
+    pub(crate) fn new(bucket: Option<&[u8]>, keys: &KeyMap, expiry_time: u32) -> Self {
+        let now = utils::now_millis();
+        let mut stats = BucketStats { live_keys: keys.len() as u64, ..Default::default() };
+        for (key, header) in keys {
+            stats.key_dir_memory += key_dir_memory(key);
+            // stored keys are prefixed with the bucket name
+            let prefix_size = bucket.map_or(0, |name| 1 + name.len());
+            stats.live_bytes += record_size(prefix_size + key.len(), header.stored_size());
+            if header.is_expired(expiry_time, now) {
+                stats.expired_keys += 1;
+            }
+        }
+        stats
+    }
+}
+
+impl Stats {
+    pub(crate) fn new(key_dir: &KeyDir, files: &FileStatsMap, conf: &Config) -> Self {
+        let mut stats = Stats {
+            live_keys: key_dir.len() as u64,
+            oldest_file_id: files.keys().next().copied(),
+            newest_file_id: files.keys().next_back().copied(),
+            files: files.clone(),
+            ..Default::default()
+        };
+
+        let now = utils::now_millis();
+        for (bucket, keys) in key_dir.keyspaces() {
+            let expiry_time = utils::expiry_time(conf.expiry_secs_of(bucket));
+            for (key, header) in keys {
+                stats.key_dir_memory += key_dir_memory(key);
+                if header.is_expired(expiry_time, now) {
+                    stats.expired_keys += 1;
+                }
+            }
+        }
+
+        for file in files.values() {
+            stats.total_bytes += file.total_bytes;
+            stats.live_bytes += file.live_bytes;
+            stats.dead_bytes += file.dead_bytes;
+            stats.tombstones += file.tombstones;
+        }
+
+        stats
+    }
+}
+
+/// Every entry keeps the key bytes, the key vector and the header.
+fn key_dir_memory(key: &Vec<u8>) -> u64 {
+    (key.capacity() + size_of::<Vec<u8>>() + size_of::<Header>() + size_of::<u64>()) as u64
+}
+
+#[inline]
+pub(crate) fn record_size(key_size: usize, val_size: u32) -> u64 {
+    (KEY_OFFSET + key_size) as u64 + val_size as u64
+}
+
+/// Counts a new record of the key. `replaced` is the previous record of the key which becomes dead.
+pub(crate) fn record_put(files: &mut FileStatsMap, key_size: usize, header: &Header, replaced: Option<&Header>) {
+    let size = record_size(key_size, header.stored_size());
+    let file = files.entry(header.file_id).or_default();
+    file.total_bytes += size;
+    file.live_bytes += size;
+    file.live_keys += 1;
+
+    if let Some(replaced) = replaced {
+        record_dead(files, key_size, replaced);
+    }
+}
+
+/// Counts a tombstone of the key. `removed` is the previous record of the key which becomes dead.
+pub(crate) fn record_delete(files: &mut FileStatsMap, key_size: usize, tombstone: &Header, removed: Option<&Header>) {
+    let size = record_size(key_size, tombstone.stored_size());
+    let file = files.entry(tombstone.file_id).or_default();
+    file.total_bytes += size;
+    file.dead_bytes += size;
+    file.tombstones += 1;
+
+    if let Some(removed) = removed {
+        record_dead(files, key_size, removed);
+    }
+}
+
+pub(crate) fn record_dead(files: &mut FileStatsMap, key_size: usize, header: &Header) {
+    records_dead(files, header.file_id, record_size(key_size, header.stored_size()), 1);
+}
+
+/// Counts `keys` records of the file with `bytes` size in total as dead.
+pub(crate) fn records_dead(files: &mut FileStatsMap, file_id: u64, bytes: u64, keys: u64) {
+    if let Some(file) = files.get_mut(&file_id) {
+        file.live_bytes = file.live_bytes.saturating_sub(bytes);
+        file.dead_bytes += bytes;
+        file.live_keys = file.live_keys.saturating_sub(keys);
+    }
+}
@@ -20,6 +20,11 @@ pub(crate) fn open_file_for_read<P>(dir: P, file_name: &str) -> anyhow::Result<f
         .open(dir.as_ref().join(file_name))?)
 }
 
+pub(crate) fn sync_dir(dir: &Path) -> anyhow::Result<()> {
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
 pub(crate) fn build_data_file_name(file_id: u64) -> String {
     format!("{file_id}.bitcask.data")
 }
@@ -29,6 +34,11 @@ pub(crate) fn timestamp() -> u32 {
     SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32
 }
 
+#[inline]
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
 pub(crate) fn expiry_time(expire_secs: u32) -> u32 {
     if expire_secs > 0 {
         timestamp() - expire_secs
@@ -0,0 +1,121 @@
+use std::sync::{mpsc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Put,
+    Delete,
+    /// Key is removed because its time to live is passed, on read or by merge.
+    Expire,
+}
+
+/// A change of a key delivered to [Watcher]. `ts_tamp` is the time of the change in seconds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent {
+    pub kind: ChangeKind,
+    pub key: Vec<u8>,
+    pub ts_tamp: u32,
+}
+
+/// Receives changes of the keys which start with the watched prefix, returned by
+/// [crate::storage::Handle::watch]. Iteration ends when the handle is dropped.
+pub struct Watcher {
+    receiver: mpsc::Receiver<ChangeEvent>,
+}
+
+impl Watcher {
+    /// Returns a change if one is already delivered.
+    pub fn try_recv(&self) -> Option<ChangeEvent> {
+        self.receiver.try_recv().ok()
+    }
+
+    /// Waits for a change until the timeout.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<ChangeEvent> {
+        self.receiver.recv_timeout(timeout).ok()
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = ChangeEvent;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.recv().ok()
+    }
+}
+
+/// A change which waits until the record is durable.
+pub(crate) struct Change {
+    pub bucket: Option<Vec<u8>>,
+    pub event: ChangeEvent,
+}
+
+struct Subscriber {
+    bucket: Option<Vec<u8>>,
+    prefix: Vec<u8>,
+    sender: mpsc::Sender<ChangeEvent>,
+}
+
+/// Subscribers of the writer and the merger.
+#[derive(Default)]
+pub(crate) struct Watchers {
+    subscribers: Mutex<Vec<Subscriber>>,
+    // checked on every write, so changes are not collected when nobody watches
+    count: AtomicUsize,
+}
+
+impl Watchers {
+    pub fn subscribe(&self, bucket: Option<&[u8]>, prefix: &[u8]) -> Watcher {
+        let (sender, receiver) = mpsc::channel();
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.push(Subscriber { bucket: bucket.map(<[u8]>::to_vec), prefix: prefix.to_vec(), sender });
+        self.count.store(subscribers.len(), Ordering::Release);
+        Watcher { receiver }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count.load(Ordering::Acquire) == 0
+    }
+
+    /// Sends the changes to the matching subscribers. Subscribers whose watcher is dropped are removed.
+    pub fn publish(&self, changes: impl IntoIterator<Item=Change>) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        for Change { bucket, event } in changes {
+            subscribers.retain(|s| {
+                if s.bucket != bucket || !event.key.starts_with(&s.prefix) {
+                    return true;
+                }
+                s.sender.send(event.clone()).is_ok()
+            });
+        }
+        self.count.store(subscribers.len(), Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Change, ChangeEvent, ChangeKind, Watchers};
+
+    fn change(bucket: Option<&[u8]>, key: &[u8]) -> Change {
+        Change { bucket: bucket.map(<[u8]>::to_vec), event: ChangeEvent { kind: ChangeKind::Put, key: key.to_vec(), ts_tamp: 1 } }
+    }
+
+    #[test]
+    fn it_should_publish_matching_changes() {
+        // given
+        let watchers = Watchers::default();
+        let users = watchers.subscribe(None, b"user:");
+        let bucket = watchers.subscribe(Some(b"b1"), b"");
+        drop(watchers.subscribe(None, b""));
+
+        // when
+        watchers.publish(vec![change(None, b"user:1"), change(None, b"order:1"), change(Some(b"b1"), b"user:2")]);
+
+        // then
+        assert_eq!(b"user:1".to_vec(), users.try_recv().unwrap().key);
+        assert!(users.try_recv().is_none());
+        assert_eq!(b"user:2".to_vec(), bucket.try_recv().unwrap().key);
+        assert!(bucket.try_recv().is_none());
+        assert_eq!(2, watchers.subscribers.lock().unwrap().len());
+    }
+}